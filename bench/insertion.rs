@@ -50,5 +50,50 @@ fn bench_insertion(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, bench_insertion);
+fn bench_batch_insertion(c: &mut Criterion) {
+    let mut group = c.benchmark_group("MSSMT Batch Insertion");
+    let leaves = (0..1000)
+        .map(|_| (generate_random_key(), generate_random_leaf()))
+        .collect::<Vec<_>>();
+
+    group.bench_function("Regular Tree Sequential", |b| {
+        b.iter(|| {
+            let db = Box::new(MemoryDb::<32, Sha256>::new());
+            let mut tree = MSSMT::<32, Sha256, ()>::new(db);
+            for (key, leaf) in &leaves {
+                tree.insert(key, leaf.clone()).unwrap();
+            }
+        })
+    });
+
+    group.bench_function("Regular Tree Batch", |b| {
+        b.iter(|| {
+            let db = Box::new(MemoryDb::<32, Sha256>::new());
+            let mut tree = MSSMT::<32, Sha256, ()>::new(db);
+            tree.insert_batch(&leaves).unwrap();
+        })
+    });
+
+    group.bench_function("Compact Tree Sequential", |b| {
+        b.iter(|| {
+            let db = Box::new(MemoryDb::<32, Sha256>::new());
+            let mut tree = CompactMSSMT::<32, Sha256, ()>::new(db);
+            for (key, leaf) in &leaves {
+                tree.insert(key, leaf.clone()).unwrap();
+            }
+        })
+    });
+
+    group.bench_function("Compact Tree Batch", |b| {
+        b.iter(|| {
+            let db = Box::new(MemoryDb::<32, Sha256>::new());
+            let mut tree = CompactMSSMT::<32, Sha256, ()>::new(db);
+            tree.insert_batch(&leaves).unwrap();
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_insertion, bench_batch_insertion);
 criterion_main!(benches);
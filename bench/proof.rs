@@ -103,7 +103,7 @@ fn bench_proof_verification(c: &mut Criterion) {
             for ((key, proof), regular_leaf) in keys
                 .iter()
                 .zip(regular_proofs.iter())
-                .zip(regular_leaves.clone().into_iter())
+                .zip(regular_leaves.clone())
             {
                 black_box::<Result<(), TreeError<()>>>(proof.verify_merkle_proof::<()>(
                     key,
@@ -121,7 +121,7 @@ fn bench_proof_verification(c: &mut Criterion) {
             for ((key, proof), compact_leaf) in keys
                 .iter()
                 .zip(compact_proofs.iter())
-                .zip(compact_leaves.clone().into_iter())
+                .zip(compact_leaves.clone())
             {
                 black_box::<Result<(), TreeError<()>>>(proof.verify_merkle_proof::<()>(
                     key,
@@ -0,0 +1,257 @@
+use std::{future::Future, sync::Arc};
+
+use crate::{
+    db::{Db, NodeKind, StoredNode, WriteBatch, WriteOp},
+    node::{Branch, CompactLeaf, Hasher, Leaf, Node},
+    MaybeSend, ThreadSafe, TreeError,
+};
+
+/// Asynchronous store for the tree nodes
+///
+/// This is the counterpart of [`Db`] for storage backends reached over the network, used by
+/// [`AsyncMSSMT`](crate::AsyncMSSMT) and [`AsyncCompactMSSMT`](crate::AsyncCompactMSSMT).
+/// With the `multi-thread` feature the futures are `Send`, so the trees can be driven by a
+/// multi-threaded executor. Implementations can use `async fn` for every method.
+pub trait AsyncDb<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone + ThreadSafe>:
+    ThreadSafe
+{
+    /// The error type for database operations
+    type DbError;
+
+    /// Get the root node of the tree
+    fn get_root_node(&self) -> impl Future<Output = Option<Branch<HASH_SIZE, H>>> + MaybeSend;
+
+    #[allow(clippy::type_complexity)]
+    /// Get the children of a node at the given height and key
+    fn get_children(
+        &self,
+        height: usize,
+        key: [u8; HASH_SIZE],
+    ) -> impl Future<
+        Output = Result<(Node<HASH_SIZE, H>, Node<HASH_SIZE, H>), TreeError<Self::DbError>>,
+    > + MaybeSend;
+
+    /// Insert a leaf node
+    fn insert_leaf(
+        &mut self,
+        leaf: Leaf<HASH_SIZE, H>,
+    ) -> impl Future<Output = Result<(), TreeError<Self::DbError>>> + MaybeSend;
+
+    /// Insert a branch node
+    fn insert_branch(
+        &mut self,
+        branch: Branch<HASH_SIZE, H>,
+    ) -> impl Future<Output = Result<(), TreeError<Self::DbError>>> + MaybeSend;
+
+    /// Insert a compact leaf node
+    fn insert_compact_leaf(
+        &mut self,
+        compact_leaf: CompactLeaf<HASH_SIZE, H>,
+    ) -> impl Future<Output = Result<(), TreeError<Self::DbError>>> + MaybeSend;
+
+    /// Get the empty tree for this database. It's computed once so this method doesn't
+    /// reach the storage.
+    fn empty_tree(&self) -> Arc<Vec<Node<HASH_SIZE, H>>>;
+
+    /// Update the root node of the tree
+    fn update_root(
+        &mut self,
+        root: Branch<HASH_SIZE, H>,
+    ) -> impl Future<Output = Result<(), TreeError<Self::DbError>>> + MaybeSend;
+
+    /// Delete a branch node
+    fn delete_branch(
+        &mut self,
+        key: &[u8; HASH_SIZE],
+    ) -> impl Future<Output = Result<(), TreeError<Self::DbError>>> + MaybeSend;
+
+    /// Delete a leaf node
+    fn delete_leaf(
+        &mut self,
+        key: &[u8; HASH_SIZE],
+    ) -> impl Future<Output = Result<(), TreeError<Self::DbError>>> + MaybeSend;
+
+    /// Delete a compact leaf node
+    fn delete_compact_leaf(
+        &mut self,
+        key: &[u8; HASH_SIZE],
+    ) -> impl Future<Output = Result<(), TreeError<Self::DbError>>> + MaybeSend;
+
+    /// List every branch, leaf and compact leaf stored in the database. See
+    /// [`Db::stored_nodes`].
+    fn stored_nodes(
+        &self,
+    ) -> impl Future<Output = Result<Vec<StoredNode<HASH_SIZE>>, TreeError<Self::DbError>>> + MaybeSend
+    {
+        async { Err(TreeError::Unsupported) }
+    }
+
+    /// Get the reference count stored for a node. See [`Db::get_ref_count`].
+    fn get_ref_count(
+        &self,
+        _kind: NodeKind,
+        _key: &[u8; HASH_SIZE],
+    ) -> impl Future<Output = Result<Option<usize>, TreeError<Self::DbError>>> + MaybeSend {
+        async { Err(TreeError::Unsupported) }
+    }
+
+    /// Store the reference count of a node. See [`Db::set_ref_count`].
+    fn set_ref_count(
+        &mut self,
+        _kind: NodeKind,
+        _key: &[u8; HASH_SIZE],
+        _count: usize,
+    ) -> impl Future<Output = Result<(), TreeError<Self::DbError>>> + MaybeSend {
+        async { Err(TreeError::Unsupported) }
+    }
+
+    /// Apply all the operations of the batch, in order. See [`Db::write_batch`].
+    fn write_batch(
+        &mut self,
+        batch: WriteBatch<HASH_SIZE, H>,
+    ) -> impl Future<Output = Result<(), TreeError<Self::DbError>>> + MaybeSend {
+        async move {
+            for op in batch {
+                match op {
+                    WriteOp::InsertLeaf(leaf) => self.insert_leaf(leaf).await?,
+                    WriteOp::InsertBranch(branch) => self.insert_branch(branch).await?,
+                    WriteOp::InsertCompactLeaf(compact_leaf) => {
+                        self.insert_compact_leaf(compact_leaf).await?
+                    }
+                    WriteOp::DeleteBranch(key) => self.delete_branch(&key).await?,
+                    WriteOp::DeleteLeaf(key) => self.delete_leaf(&key).await?,
+                    WriteOp::DeleteCompactLeaf(key) => self.delete_compact_leaf(&key).await?,
+                    WriteOp::UpdateRoot(root) => self.update_root(root).await?,
+                    WriteOp::SetRefCount(kind, key, count) => {
+                        self.set_ref_count(kind, &key, count).await?
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Exposes a blocking [`Db`] as an [`AsyncDb`], every call completes right away.
+///
+/// The calls run on the task polling the future, so this is meant for in-memory databases and
+/// tests rather than for backends that block on I/O.
+#[derive(Debug, Clone, Default)]
+pub struct BlockingDb<D> {
+    inner: D,
+}
+
+impl<D> BlockingDb<D> {
+    /// Wraps `inner`.
+    pub fn new(inner: D) -> Self {
+        Self { inner }
+    }
+
+    /// Returns the inner database.
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Unwraps the inner database.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone + ThreadSafe, D: Db<HASH_SIZE, H>>
+    AsyncDb<HASH_SIZE, H> for BlockingDb<D>
+{
+    type DbError = D::DbError;
+
+    async fn get_root_node(&self) -> Option<Branch<HASH_SIZE, H>> {
+        self.inner.get_root_node()
+    }
+
+    async fn get_children(
+        &self,
+        height: usize,
+        key: [u8; HASH_SIZE],
+    ) -> Result<(Node<HASH_SIZE, H>, Node<HASH_SIZE, H>), TreeError<Self::DbError>> {
+        self.inner.get_children(height, key)
+    }
+
+    async fn insert_leaf(
+        &mut self,
+        leaf: Leaf<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.insert_leaf(leaf)
+    }
+
+    async fn insert_branch(
+        &mut self,
+        branch: Branch<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.insert_branch(branch)
+    }
+
+    async fn insert_compact_leaf(
+        &mut self,
+        compact_leaf: CompactLeaf<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.insert_compact_leaf(compact_leaf)
+    }
+
+    fn empty_tree(&self) -> Arc<Vec<Node<HASH_SIZE, H>>> {
+        self.inner.empty_tree()
+    }
+
+    async fn update_root(
+        &mut self,
+        root: Branch<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.update_root(root)
+    }
+
+    async fn delete_branch(
+        &mut self,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.delete_branch(key)
+    }
+
+    async fn delete_leaf(&mut self, key: &[u8; HASH_SIZE]) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.delete_leaf(key)
+    }
+
+    async fn delete_compact_leaf(
+        &mut self,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.delete_compact_leaf(key)
+    }
+
+    async fn stored_nodes(&self) -> Result<Vec<StoredNode<HASH_SIZE>>, TreeError<Self::DbError>> {
+        self.inner.stored_nodes()
+    }
+
+    async fn get_ref_count(
+        &self,
+        kind: NodeKind,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<Option<usize>, TreeError<Self::DbError>> {
+        self.inner.get_ref_count(kind, key)
+    }
+
+    async fn set_ref_count(
+        &mut self,
+        kind: NodeKind,
+        key: &[u8; HASH_SIZE],
+        count: usize,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.set_ref_count(kind, key, count)
+    }
+
+    /// Applies the batch with [`Db::write_batch`] so it keeps the atomicity of the inner
+    /// database.
+    async fn write_batch(
+        &mut self,
+        batch: WriteBatch<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.write_batch(batch)
+    }
+}
@@ -0,0 +1,169 @@
+//! Write batches used to apply several database writes as a single unit.
+
+use crate::{
+    db::NodeKind,
+    node::{Branch, CompactLeaf, Hasher, Leaf},
+};
+
+/// A single write operation of a [`WriteBatch`].
+#[derive(Debug, Clone)]
+pub enum WriteOp<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
+    /// Insert a leaf node
+    InsertLeaf(Leaf<HASH_SIZE, H>),
+    /// Insert a branch node
+    InsertBranch(Branch<HASH_SIZE, H>),
+    /// Insert a compact leaf node
+    InsertCompactLeaf(CompactLeaf<HASH_SIZE, H>),
+    /// Delete the branch node with the given hash
+    DeleteBranch([u8; HASH_SIZE]),
+    /// Delete the leaf node with the given hash
+    DeleteLeaf([u8; HASH_SIZE]),
+    /// Delete the compact leaf node with the given hash
+    DeleteCompactLeaf([u8; HASH_SIZE]),
+    /// Update the root node of the tree
+    UpdateRoot(Branch<HASH_SIZE, H>),
+    /// Store the reference count of the node of the given kind and hash, `0` removing it
+    SetRefCount(NodeKind, [u8; HASH_SIZE], usize),
+}
+
+/// An ordered list of writes that a [`Db`](crate::Db) applies atomically with
+/// [`Db::write_batch`](crate::Db::write_batch).
+///
+/// Operations are applied in the order they were pushed, so deleting and then re-inserting
+/// the same node leaves it in the database.
+#[derive(Debug, Clone)]
+pub struct WriteBatch<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
+    ops: Vec<WriteOp<HASH_SIZE, H>>,
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> WriteBatch<HASH_SIZE, H> {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self { ops: Vec::new() }
+    }
+
+    /// Queues the insertion of a leaf node.
+    pub fn insert_leaf(&mut self, leaf: Leaf<HASH_SIZE, H>) {
+        self.ops.push(WriteOp::InsertLeaf(leaf));
+    }
+
+    /// Queues the insertion of a branch node.
+    pub fn insert_branch(&mut self, branch: Branch<HASH_SIZE, H>) {
+        self.ops.push(WriteOp::InsertBranch(branch));
+    }
+
+    /// Queues the insertion of a compact leaf node.
+    pub fn insert_compact_leaf(&mut self, compact_leaf: CompactLeaf<HASH_SIZE, H>) {
+        self.ops.push(WriteOp::InsertCompactLeaf(compact_leaf));
+    }
+
+    /// Queues the deletion of a branch node.
+    pub fn delete_branch(&mut self, key: &[u8; HASH_SIZE]) {
+        self.ops.push(WriteOp::DeleteBranch(*key));
+    }
+
+    /// Queues the deletion of a leaf node.
+    pub fn delete_leaf(&mut self, key: &[u8; HASH_SIZE]) {
+        self.ops.push(WriteOp::DeleteLeaf(*key));
+    }
+
+    /// Queues the deletion of a compact leaf node.
+    pub fn delete_compact_leaf(&mut self, key: &[u8; HASH_SIZE]) {
+        self.ops.push(WriteOp::DeleteCompactLeaf(*key));
+    }
+
+    /// Queues the update of the root node.
+    pub fn update_root(&mut self, root: Branch<HASH_SIZE, H>) {
+        self.ops.push(WriteOp::UpdateRoot(root));
+    }
+
+    /// Queues the update of the reference count of a node.
+    pub fn set_ref_count(&mut self, kind: NodeKind, key: &[u8; HASH_SIZE], count: usize) {
+        self.ops.push(WriteOp::SetRefCount(kind, *key, count));
+    }
+
+    /// Drops the queued deletions, keeping the nodes they would replace in the database.
+    pub(crate) fn retain_insertions(&mut self) {
+        self.ops.retain(|op| {
+            !matches!(
+                op,
+                WriteOp::DeleteBranch(_) | WriteOp::DeleteLeaf(_) | WriteOp::DeleteCompactLeaf(_)
+            )
+        });
+    }
+
+    /// Appends all the operations of `other` to this batch.
+    pub fn extend(&mut self, other: WriteBatch<HASH_SIZE, H>) {
+        self.ops.extend(other.ops);
+    }
+
+    /// Returns the queued operations.
+    pub fn ops(&self) -> &[WriteOp<HASH_SIZE, H>] {
+        &self.ops
+    }
+
+    /// Returns the number of queued operations.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if no operation is queued.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Default for WriteBatch<HASH_SIZE, H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> IntoIterator
+    for WriteBatch<HASH_SIZE, H>
+{
+    type Item = WriteOp<HASH_SIZE, H>;
+    type IntoIter = std::vec::IntoIter<WriteOp<HASH_SIZE, H>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{WriteBatch, WriteOp};
+    use crate::{Branch, Db, Leaf, MemoryDb, Node};
+    use sha2::Sha256;
+
+    #[test]
+    fn test_write_batch_order() {
+        let leaf = Leaf::<32, Sha256>::new(vec![1, 2, 3], 1);
+        let mut batch = WriteBatch::<32, Sha256>::new();
+        assert!(batch.is_empty());
+        batch.insert_leaf(leaf.clone());
+        batch.delete_leaf(&leaf.hash());
+        batch.insert_leaf(leaf.clone());
+        assert_eq!(batch.len(), 3);
+        assert!(matches!(batch.ops()[1], WriteOp::DeleteLeaf(_)));
+
+        let mut db = MemoryDb::<32, Sha256>::new();
+        db.write_batch(batch).unwrap();
+        assert!(db.get_leaves().contains_key(&leaf.hash()));
+    }
+
+    #[test]
+    fn test_write_batch_root() {
+        let leaf = Leaf::<32, Sha256>::new(vec![1, 2, 3], 1);
+        let branch = Branch::new(Node::Leaf(leaf.clone()), Node::new_empty_leaf());
+        let mut batch = WriteBatch::<32, Sha256>::new();
+        batch.insert_leaf(leaf);
+        batch.insert_branch(branch.clone());
+        batch.update_root(branch.clone());
+
+        let mut db = MemoryDb::<32, Sha256>::new();
+        db.write_batch(batch).unwrap();
+        assert_eq!(db.get_root_node().unwrap().hash(), branch.hash());
+        assert!(db.get_branches().contains_key(&branch.hash()));
+    }
+}
@@ -0,0 +1,420 @@
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use crate::{
+    db::{Db, NodeKind, StoredNode, WriteBatch, WriteOp},
+    node::{Branch, CompactLeaf, Hasher, Leaf, Node},
+    ThreadSafe, TreeError,
+};
+
+/// Children of a cached branch.
+type Children<const HASH_SIZE: usize, H> = (Node<HASH_SIZE, H>, Node<HASH_SIZE, H>);
+
+/// A database layer caching the children returned by [`Db::get_children`].
+///
+/// Each step of a walk down the tree reads the children of a branch, which on a persistent
+/// backend means reading nodes from storage at every level. This layer keeps the children of
+/// the branches of the top `pinned_levels` levels, and the ones of up to `capacity` other
+/// branches in a least recently used cache.
+///
+/// The pinned branches are evicted on their own, least recently used first, once there are
+/// more than the `2^pinned_levels - 1` branches of the top levels of a single tree. Replaced
+/// roots that are kept in the database, like the versions of a versioned tree, don't grow the
+/// cache: it holds at most `2^pinned_levels - 1 + capacity` entries.
+///
+/// Writes go straight to the inner database. Entries are keyed by the hash of their branch so
+/// they never get stale, deleting a branch evicts its entry.
+pub struct CachedDb<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, D> {
+    inner: D,
+    pinned_levels: usize,
+    cache: Mutex<NodeCache<HASH_SIZE, H>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Children of the pinned branches and of the other ones, in separate caches.
+struct NodeCache<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
+    pinned: Lru<HASH_SIZE, H>,
+    other: Lru<HASH_SIZE, H>,
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> NodeCache<HASH_SIZE, H> {
+    fn get(&mut self, key: &[u8; HASH_SIZE]) -> Option<Children<HASH_SIZE, H>> {
+        self.pinned.get(key).or_else(|| self.other.get(key))
+    }
+
+    fn insert(&mut self, key: [u8; HASH_SIZE], children: Children<HASH_SIZE, H>, pinned: bool) {
+        if pinned {
+            self.pinned.insert(key, children);
+        } else {
+            self.other.insert(key, children);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8; HASH_SIZE]) {
+        self.pinned.remove(key);
+        self.other.remove(key);
+    }
+}
+
+/// Least recently used cache of the children of up to `capacity` branches.
+struct Lru<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
+    capacity: usize,
+    /// Cached children with the tick of their last use.
+    entries: HashMap<[u8; HASH_SIZE], (Children<HASH_SIZE, H>, u64)>,
+    /// Keys of `entries` by tick of their last use, the first one is the next to be evicted.
+    order: BTreeMap<u64, [u8; HASH_SIZE]>,
+    tick: u64,
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Lru<HASH_SIZE, H> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, key: &[u8; HASH_SIZE]) -> Option<Children<HASH_SIZE, H>> {
+        let (children, last_use) = self.entries.get_mut(key)?;
+        self.order.remove(last_use);
+        self.tick += 1;
+        *last_use = self.tick;
+        self.order.insert(self.tick, *key);
+        Some(children.clone())
+    }
+
+    fn insert(&mut self, key: [u8; HASH_SIZE], children: Children<HASH_SIZE, H>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        if self.entries.len() == self.capacity {
+            if let Some((_, evicted)) = self.order.pop_first() {
+                self.entries.remove(&evicted);
+            }
+        }
+        self.tick += 1;
+        self.entries.insert(key, (children, self.tick));
+        self.order.insert(self.tick, key);
+    }
+
+    fn remove(&mut self, key: &[u8; HASH_SIZE]) {
+        if let Some((_, last_use)) = self.entries.remove(key) {
+            self.order.remove(&last_use);
+        }
+    }
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, D> CachedDb<HASH_SIZE, H, D> {
+    /// Wraps `inner`, pinning the branches of the top `pinned_levels` levels and caching up to
+    /// `capacity` other branches.
+    pub fn new(inner: D, capacity: usize, pinned_levels: usize) -> Self {
+        // Number of branches above `pinned_levels` in a tree.
+        let pinned_capacity = u32::try_from(pinned_levels)
+            .ok()
+            .and_then(|levels| 1usize.checked_shl(levels))
+            .map_or(usize::MAX, |branches| branches - 1);
+        Self {
+            inner,
+            pinned_levels,
+            cache: Mutex::new(NodeCache {
+                pinned: Lru::new(pinned_capacity),
+                other: Lru::new(capacity),
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the inner database.
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Unwraps the inner database, dropping the cache.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Number of [`Db::get_children`] calls answered by the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of [`Db::get_children`] calls that read the inner database.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn cache(&self) -> MutexGuard<'_, NodeCache<HASH_SIZE, H>> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<
+        const HASH_SIZE: usize,
+        H: Hasher<HASH_SIZE> + Clone + ThreadSafe,
+        D: Db<HASH_SIZE, H> + 'static,
+    > Db<HASH_SIZE, H> for CachedDb<HASH_SIZE, H, D>
+{
+    type DbError = D::DbError;
+
+    fn get_root_node(&self) -> Option<Branch<HASH_SIZE, H>> {
+        self.inner.get_root_node()
+    }
+
+    fn get_children(
+        &self,
+        height: usize,
+        key: [u8; HASH_SIZE],
+    ) -> Result<(Node<HASH_SIZE, H>, Node<HASH_SIZE, H>), TreeError<Self::DbError>> {
+        if let Some(children) = self.cache().get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(children);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let children = self.inner.get_children(height, key)?;
+        self.cache()
+            .insert(key, children.clone(), height < self.pinned_levels);
+        Ok(children)
+    }
+
+    fn insert_leaf(&mut self, leaf: Leaf<HASH_SIZE, H>) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.insert_leaf(leaf)
+    }
+
+    fn insert_branch(
+        &mut self,
+        branch: Branch<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.insert_branch(branch)
+    }
+
+    fn insert_compact_leaf(
+        &mut self,
+        compact_leaf: CompactLeaf<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.insert_compact_leaf(compact_leaf)
+    }
+
+    fn empty_tree(&self) -> Arc<Vec<Node<HASH_SIZE, H>>> {
+        self.inner.empty_tree()
+    }
+
+    fn update_root(&mut self, root: Branch<HASH_SIZE, H>) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.update_root(root)
+    }
+
+    fn delete_branch(&mut self, key: &[u8; HASH_SIZE]) -> Result<(), TreeError<Self::DbError>> {
+        self.cache().remove(key);
+        self.inner.delete_branch(key)
+    }
+
+    fn delete_leaf(&mut self, key: &[u8; HASH_SIZE]) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.delete_leaf(key)
+    }
+
+    fn delete_compact_leaf(
+        &mut self,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.delete_compact_leaf(key)
+    }
+
+    fn stored_nodes(&self) -> Result<Vec<StoredNode<HASH_SIZE>>, TreeError<Self::DbError>> {
+        self.inner.stored_nodes()
+    }
+
+    fn get_ref_count(
+        &self,
+        kind: NodeKind,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<Option<usize>, TreeError<Self::DbError>> {
+        self.inner.get_ref_count(kind, key)
+    }
+
+    fn set_ref_count(
+        &mut self,
+        kind: NodeKind,
+        key: &[u8; HASH_SIZE],
+        count: usize,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.set_ref_count(kind, key, count)
+    }
+
+    /// Evicts the deleted branches and applies the batch to the inner database.
+    fn write_batch(
+        &mut self,
+        batch: WriteBatch<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        {
+            let mut cache = self.cache();
+            for op in batch.ops() {
+                if let WriteOp::DeleteBranch(key) = op {
+                    cache.remove(key);
+                }
+            }
+        }
+        self.inner.write_batch(batch)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::Sha256;
+
+    use super::CachedDb;
+    use crate::{CompactMSSMT, Db, FileDb, Leaf, MemoryDb, MSSMT};
+
+    fn leaves() -> Vec<([u8; 32], Leaf<32, Sha256>)> {
+        (0..10u8)
+            .map(|i| ([i.wrapping_mul(37); 32], Leaf::new(vec![i + 1], i as u64)))
+            .collect()
+    }
+
+    fn cached_db(tree: &MSSMT<32, Sha256, ()>) -> &CachedDb<32, Sha256, MemoryDb<32, Sha256>> {
+        tree.db()
+            .as_any()
+            .downcast_ref::<CachedDb<32, Sha256, MemoryDb<32, Sha256>>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_cached_db_hits() {
+        let mut tree =
+            MSSMT::<32, Sha256, ()>::new(Box::new(CachedDb::new(MemoryDb::default(), 1024, 0)));
+        tree.insert_batch(&leaves()).unwrap();
+        let (key, leaf) = leaves()[3].clone();
+        let root = tree.root().unwrap();
+        tree.merkle_proof(&key).unwrap();
+        let (hits, misses) = (cached_db(&tree).hits(), cached_db(&tree).misses());
+        let proof = tree.merkle_proof(&key).unwrap();
+        proof
+            .verify_merkle_proof::<()>(&key, leaf, root.hash())
+            .unwrap();
+        // Every level of the second walk down is answered by the cache.
+        assert_eq!(cached_db(&tree).hits(), hits + 256);
+        assert_eq!(cached_db(&tree).misses(), misses);
+    }
+
+    #[test]
+    fn test_cached_db_pinned_levels() {
+        let mut tree =
+            MSSMT::<32, Sha256, ()>::new(Box::new(CachedDb::new(MemoryDb::default(), 16, 8)));
+        tree.insert_batch(&leaves()).unwrap();
+        for (key, _) in leaves() {
+            tree.merkle_proof(&key).unwrap();
+        }
+        // The least recently used branches were evicted, the top levels stay pinned.
+        let (hits, misses) = (cached_db(&tree).hits(), cached_db(&tree).misses());
+        tree.merkle_proof(&leaves()[0].0).unwrap();
+        assert_eq!(cached_db(&tree).hits(), hits + 8);
+        assert_eq!(cached_db(&tree).misses(), misses + 248);
+    }
+
+    #[test]
+    fn test_cached_db_pinned_bound() {
+        let mut tree = MSSMT::<32, Sha256, ()>::new_versioned(Box::new(CachedDb::new(
+            MemoryDb::default(),
+            0,
+            3,
+        )));
+        for (i, (key, leaf)) in leaves().into_iter().enumerate() {
+            tree.insert(&key, leaf).unwrap();
+            tree.merkle_proof(&key).unwrap();
+            // Every version keeps its branches, only the 7 branches of the top 3 levels of one
+            // tree are pinned.
+            assert!(cached_db(&tree).cache().pinned.entries.len() <= 7);
+            for version in 0..=i as u64 {
+                tree.at_version(version)
+                    .unwrap()
+                    .merkle_proof(&key)
+                    .unwrap();
+            }
+        }
+        assert_eq!(cached_db(&tree).cache().pinned.entries.len(), 7);
+    }
+
+    #[test]
+    fn test_cached_db_write_through() {
+        let leaves = leaves();
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut cached_tree =
+            MSSMT::<32, Sha256, ()>::new(Box::new(CachedDb::new(MemoryDb::default(), 64, 4)));
+        let mut compact_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(CachedDb::new(
+            MemoryDb::<32, Sha256>::default(),
+            64,
+            4,
+        )));
+        for (i, (key, leaf)) in leaves.iter().enumerate() {
+            tree.insert(key, leaf.clone()).unwrap();
+            cached_tree.insert(key, leaf.clone()).unwrap();
+            compact_tree.insert(key, leaf.clone()).unwrap();
+            if i % 3 == 0 {
+                tree.delete(&leaves[i / 2].0).unwrap();
+                cached_tree.delete(&leaves[i / 2].0).unwrap();
+                compact_tree.delete(&leaves[i / 2].0).unwrap();
+            }
+            let root = tree.root().unwrap();
+            assert_eq!(cached_tree.root().unwrap().hash(), root.hash());
+            assert_eq!(compact_tree.root().unwrap().hash(), root.hash());
+            for (key, _) in &leaves {
+                let leaf = tree.get(key).unwrap();
+                assert_eq!(cached_tree.get(key).unwrap().hash(), leaf.hash());
+                assert_eq!(compact_tree.get(key).unwrap().hash(), leaf.hash());
+                cached_tree
+                    .merkle_proof(key)
+                    .unwrap()
+                    .verify_merkle_proof::<()>(key, leaf, root.hash())
+                    .unwrap();
+            }
+        }
+        // The deleted branches were evicted along the way, only the stored ones and the
+        // empty subtrees are cached.
+        let db = cached_db(&cached_tree);
+        assert!(db.hits() > 0 && db.misses() > 0);
+        let empty_tree = db.inner().empty_tree();
+        let cache = db.cache();
+        let mut keys = cache
+            .pinned
+            .entries
+            .keys()
+            .chain(cache.other.entries.keys());
+        assert!(keys.all(|key| {
+            db.inner().get_branches().contains_key(key)
+                || empty_tree.iter().any(|node| node.hash() == *key)
+        }));
+    }
+
+    #[test]
+    fn test_cached_file_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tree = CompactMSSMT::<32, Sha256, _>::new(Box::new(CachedDb::new(
+            FileDb::<32, Sha256>::open(dir.path().join("db")).unwrap(),
+            256,
+            8,
+        )));
+        tree.insert_batch(&leaves()).unwrap();
+        let root = tree.root().unwrap();
+        for (key, leaf) in leaves() {
+            let (stored, proof) = tree.get_with_proof(&key).unwrap();
+            assert_eq!(stored.hash(), leaf.hash());
+            proof
+                .verify_merkle_proof::<()>(&key, leaf, root.hash())
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,922 @@
+//! File backed database implementation.
+//!
+//! Every write is appended to a single log file. An in-memory index maps each node hash
+//! to the location of its latest record so reads only touch the bytes they need.
+//!
+//! # Log format
+//!
+//! ```text
+//! record   := body_len (u32 BE) | body | checksum (H::hash(body))
+//! body     := tag (u8) | flags (u8) | payload
+//! ```
+//!
+//! The payload of a reference count record is the tag of the node kind (u8), the node hash
+//! and the count (u64 BE), a count of `0` removing it.
+//!
+//! A record is only applied once a record with the `COMMIT` flag has been read after it.
+//! When the log is reopened, a trailing uncommitted or torn record is discarded and the
+//! file is truncated back to the last committed record.
+
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    db::{Db, NodeKind, StoredNode, WriteBatch, WriteOp},
+    node::{Branch, CompactLeaf, ComputedNode, Hasher, Leaf, Node},
+    tree::EmptyTree,
+    ThreadSafe, TreeError,
+};
+
+const TAG_BRANCH: u8 = 1;
+const TAG_LEAF: u8 = 2;
+const TAG_COMPACT_LEAF: u8 = 3;
+const TAG_DELETE_BRANCH: u8 = 4;
+const TAG_DELETE_LEAF: u8 = 5;
+const TAG_DELETE_COMPACT_LEAF: u8 = 6;
+const TAG_ROOT: u8 = 7;
+const TAG_REF_COUNT: u8 = 8;
+
+const FLAG_COMMIT: u8 = 1;
+
+/// Size of the `body_len` prefix of a record.
+const LEN_SIZE: usize = 4;
+
+/// Error type for [`FileDb`] operations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileDbError {
+    /// An I/O operation on the log file failed.
+    Io(io::ErrorKind),
+    /// A committed record of the log doesn't match its checksum or can't be decoded.
+    Corrupted {
+        /// Offset of the record in the log file.
+        offset: u64,
+    },
+    /// A failed write couldn't be rolled back, the database must be reopened.
+    Poisoned,
+}
+
+impl Display for FileDbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileDbError::Io(kind) => write!(f, "I/O error: {}", kind),
+            FileDbError::Corrupted { offset } => {
+                write!(f, "Corrupted record at offset {}", offset)
+            }
+            FileDbError::Poisoned => write!(f, "Failed write not rolled back"),
+        }
+    }
+}
+
+impl From<io::Error> for FileDbError {
+    fn from(error: io::Error) -> Self {
+        FileDbError::Io(error.kind())
+    }
+}
+
+impl From<FileDbError> for TreeError<FileDbError> {
+    fn from(error: FileDbError) -> Self {
+        TreeError::DbError(error)
+    }
+}
+
+/// Location of a record body in the log file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    offset: u64,
+    len: u32,
+}
+
+/// A single entry of the log.
+enum Record<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
+    Branch(Branch<HASH_SIZE, H>),
+    Leaf(Leaf<HASH_SIZE, H>),
+    CompactLeaf(CompactLeaf<HASH_SIZE, H>),
+    DeleteBranch([u8; HASH_SIZE]),
+    DeleteLeaf([u8; HASH_SIZE]),
+    DeleteCompactLeaf([u8; HASH_SIZE]),
+    Root(Branch<HASH_SIZE, H>),
+    RefCount(NodeKind, [u8; HASH_SIZE], u64),
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Record<HASH_SIZE, H> {
+    /// Encodes the record body (tag, flags and payload).
+    fn encode_body(&self, commit: bool) -> Vec<u8> {
+        let flags = if commit { FLAG_COMMIT } else { 0 };
+        let mut body = Vec::new();
+        match self {
+            Record::Branch(branch) => {
+                body.extend_from_slice(&[TAG_BRANCH, flags]);
+                encode_branch(&mut body, branch);
+            }
+            Record::Root(branch) => {
+                body.extend_from_slice(&[TAG_ROOT, flags]);
+                encode_branch(&mut body, branch);
+            }
+            Record::Leaf(leaf) => {
+                body.extend_from_slice(&[TAG_LEAF, flags]);
+                body.extend_from_slice(&leaf.hash());
+                encode_leaf(&mut body, leaf);
+            }
+            Record::CompactLeaf(compact_leaf) => {
+                body.extend_from_slice(&[TAG_COMPACT_LEAF, flags]);
+                body.extend_from_slice(&compact_leaf.hash());
+                body.extend_from_slice(compact_leaf.key());
+                encode_leaf(&mut body, compact_leaf.leaf());
+            }
+            Record::DeleteBranch(key) => {
+                body.extend_from_slice(&[TAG_DELETE_BRANCH, flags]);
+                body.extend_from_slice(key);
+            }
+            Record::DeleteLeaf(key) => {
+                body.extend_from_slice(&[TAG_DELETE_LEAF, flags]);
+                body.extend_from_slice(key);
+            }
+            Record::DeleteCompactLeaf(key) => {
+                body.extend_from_slice(&[TAG_DELETE_COMPACT_LEAF, flags]);
+                body.extend_from_slice(key);
+            }
+            Record::RefCount(kind, key, count) => {
+                let kind = match kind {
+                    NodeKind::Branch => TAG_BRANCH,
+                    NodeKind::Leaf => TAG_LEAF,
+                    NodeKind::CompactLeaf => TAG_COMPACT_LEAF,
+                };
+                body.extend_from_slice(&[TAG_REF_COUNT, flags, kind]);
+                body.extend_from_slice(key);
+                body.extend_from_slice(&count.to_be_bytes());
+            }
+        }
+        body
+    }
+
+    /// Encodes the full record: length prefix, body and checksum.
+    fn encode(&self, commit: bool, out: &mut Vec<u8>) {
+        let body = self.encode_body(commit);
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        out.extend_from_slice(&body);
+        out.extend_from_slice(&H::hash(&body));
+    }
+
+    /// Decodes a record body. Returns `None` if the body is malformed.
+    fn decode_body(body: &[u8]) -> Option<Self> {
+        let (&tag, rest) = body.split_first()?;
+        let payload = rest.get(1..)?;
+        Some(match tag {
+            TAG_BRANCH => Record::Branch(decode_branch(payload)?),
+            TAG_ROOT => Record::Root(decode_branch(payload)?),
+            TAG_LEAF => {
+                let (_, leaf) = payload.split_at_checked(HASH_SIZE)?;
+                Record::Leaf(decode_leaf(leaf)?)
+            }
+            TAG_COMPACT_LEAF => {
+                let (node_hash, rest) = payload.split_at_checked(HASH_SIZE)?;
+                let (key, leaf) = rest.split_at_checked(HASH_SIZE)?;
+                // SAFETY: record bodies are only decoded once their checksum is verified.
+                Record::CompactLeaf(unsafe {
+                    CompactLeaf::new_with_hash(
+                        node_hash.try_into().ok()?,
+                        decode_leaf(leaf)?,
+                        key.try_into().ok()?,
+                    )
+                })
+            }
+            TAG_DELETE_BRANCH => Record::DeleteBranch(payload.try_into().ok()?),
+            TAG_DELETE_LEAF => Record::DeleteLeaf(payload.try_into().ok()?),
+            TAG_DELETE_COMPACT_LEAF => Record::DeleteCompactLeaf(payload.try_into().ok()?),
+            TAG_REF_COUNT => {
+                let (&kind, rest) = payload.split_first()?;
+                let kind = match kind {
+                    TAG_BRANCH => NodeKind::Branch,
+                    TAG_LEAF => NodeKind::Leaf,
+                    TAG_COMPACT_LEAF => NodeKind::CompactLeaf,
+                    _ => return None,
+                };
+                let (key, count) = rest.split_at_checked(HASH_SIZE)?;
+                Record::RefCount(
+                    kind,
+                    key.try_into().ok()?,
+                    u64::from_be_bytes(count.try_into().ok()?),
+                )
+            }
+            _ => return None,
+        })
+    }
+
+    /// Returns the hash of the node this record is about.
+    fn key(&self) -> [u8; HASH_SIZE] {
+        match self {
+            Record::Branch(branch) | Record::Root(branch) => branch.hash(),
+            Record::Leaf(leaf) => leaf.hash(),
+            Record::CompactLeaf(compact_leaf) => compact_leaf.hash(),
+            Record::DeleteBranch(key)
+            | Record::DeleteLeaf(key)
+            | Record::DeleteCompactLeaf(key)
+            | Record::RefCount(_, key, _) => *key,
+        }
+    }
+}
+
+fn encode_branch<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone>(
+    out: &mut Vec<u8>,
+    branch: &Branch<HASH_SIZE, H>,
+) {
+    out.extend_from_slice(&branch.hash());
+    out.extend_from_slice(&branch.sum().to_be_bytes());
+    for child in [branch.left(), branch.right()] {
+        out.extend_from_slice(&child.hash());
+        out.extend_from_slice(&child.sum().to_be_bytes());
+    }
+}
+
+fn decode_branch<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone>(
+    payload: &[u8],
+) -> Option<Branch<HASH_SIZE, H>> {
+    let (node_hash, rest) = payload.split_at_checked(HASH_SIZE)?;
+    let (sum, rest) = rest.split_at_checked(8)?;
+    let (left, right) = rest.split_at_checked(HASH_SIZE + 8)?;
+    let decode_child = |data: &[u8]| -> Option<Node<HASH_SIZE, H>> {
+        if data.len() != HASH_SIZE + 8 {
+            return None;
+        }
+        let (hash, sum) = data.split_at(HASH_SIZE);
+        Some(Node::Computed(ComputedNode::new(
+            hash.try_into().ok()?,
+            u64::from_be_bytes(sum.try_into().ok()?),
+        )))
+    };
+    // SAFETY: record bodies are only decoded once their checksum is verified.
+    Some(unsafe {
+        Branch::new_with_hash(
+            decode_child(left)?,
+            decode_child(right)?,
+            node_hash.try_into().ok()?,
+            u64::from_be_bytes(sum.try_into().ok()?),
+        )
+    })
+}
+
+fn encode_leaf<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone>(
+    out: &mut Vec<u8>,
+    leaf: &Leaf<HASH_SIZE, H>,
+) {
+    out.extend_from_slice(&leaf.sum().to_be_bytes());
+    out.extend_from_slice(leaf.value());
+}
+
+fn decode_leaf<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone>(
+    payload: &[u8],
+) -> Option<Leaf<HASH_SIZE, H>> {
+    let (sum, value) = payload.split_at_checked(8)?;
+    Some(Leaf::new(
+        value.to_vec(),
+        u64::from_be_bytes(sum.try_into().ok()?),
+    ))
+}
+
+/// A persistent database storing the tree nodes in an append-only log file.
+///
+/// The root is synced to disk on every [`Db::update_root`], so reopening the same file
+/// yields the last root that was written.
+#[derive(Debug)]
+pub struct FileDb<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
+    path: PathBuf,
+    writer: File,
+    reader: Mutex<File>,
+    /// Length of the committed part of the log.
+    len: u64,
+    /// Set when a failed write left bytes after `len` in the file, appending again would
+    /// misplace the records.
+    poisoned: bool,
+    branches: HashMap<[u8; HASH_SIZE], Location>,
+    leaves: HashMap<[u8; HASH_SIZE], Location>,
+    compact_leaves: HashMap<[u8; HASH_SIZE], Location>,
+    ref_counts: HashMap<(NodeKind, [u8; HASH_SIZE]), usize>,
+    empty_tree: Arc<Vec<Node<HASH_SIZE, H>>>,
+    root: Option<Branch<HASH_SIZE, H>>,
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> FileDb<HASH_SIZE, H> {
+    /// Opens the log file at `path`, creating it if it doesn't exist, and rebuilds the index
+    /// by replaying it.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, FileDbError> {
+        let path = path.as_ref().to_path_buf();
+        let writer = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        let reader = File::open(&path)?;
+        let mut db = Self {
+            path,
+            writer,
+            reader: Mutex::new(reader),
+            len: 0,
+            poisoned: false,
+            branches: HashMap::new(),
+            leaves: HashMap::new(),
+            compact_leaves: HashMap::new(),
+            ref_counts: HashMap::new(),
+            empty_tree: EmptyTree::<HASH_SIZE, H>::empty_tree(),
+            root: None,
+        };
+        db.replay()?;
+        Ok(db)
+    }
+
+    /// Returns the path of the log file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the number of branches currently stored.
+    pub fn branch_count(&self) -> usize {
+        self.branches.len()
+    }
+
+    /// Returns the number of leaves currently stored.
+    pub fn leaf_count(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// Returns the number of compact leaves currently stored.
+    pub fn compact_leaf_count(&self) -> usize {
+        self.compact_leaves.len()
+    }
+
+    /// Replays the whole log to rebuild the index and the root.
+    /// Truncates the file after the last committed record.
+    ///
+    /// Everything after the last commit is uncommitted, so damaged records there are the
+    /// leftovers of a crash and are discarded. A damaged record followed by a commit is
+    /// reported as [`FileDbError::Corrupted`].
+    fn replay(&mut self) -> Result<(), FileDbError> {
+        let file_len = self.writer.metadata()?.len();
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut offset = 0u64;
+        let mut pending = Vec::new();
+        // Offset of the first damaged record since the last commit.
+        let mut damaged = None;
+        loop {
+            let mut len_bytes = [0u8; LEN_SIZE];
+            if read_full(&mut reader, &mut len_bytes)? < LEN_SIZE {
+                break;
+            }
+            let body_len = u32::from_be_bytes(len_bytes);
+            let record_len = (LEN_SIZE + HASH_SIZE) as u64 + body_len as u64;
+            if offset + record_len > file_len {
+                // Torn write at the end of the log.
+                break;
+            }
+            let mut body = vec![0u8; body_len as usize];
+            reader.read_exact(&mut body)?;
+            let mut checksum = [0u8; HASH_SIZE];
+            reader.read_exact(&mut checksum)?;
+            let record = (checksum == H::hash(&body))
+                .then(|| Record::<HASH_SIZE, H>::decode_body(&body))
+                .flatten();
+            let Some(record) = record else {
+                // Skipped to find out whether a commit follows.
+                damaged.get_or_insert(offset);
+                offset += record_len;
+                continue;
+            };
+            let location = Location {
+                offset: offset + LEN_SIZE as u64,
+                len: body_len,
+            };
+            pending.push((record, location));
+            offset += record_len;
+            if body[1] & FLAG_COMMIT != 0 {
+                if let Some(offset) = damaged {
+                    return Err(FileDbError::Corrupted { offset });
+                }
+                for (record, location) in pending.drain(..) {
+                    self.apply(record, location);
+                }
+                self.len = offset;
+            }
+        }
+        if self.len != file_len {
+            self.writer.set_len(self.len)?;
+            self.writer.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Updates the index with a record that has been durably written at `location`.
+    fn apply(&mut self, record: Record<HASH_SIZE, H>, location: Location) {
+        let key = record.key();
+        match record {
+            Record::Branch(_) => {
+                self.branches.insert(key, location);
+            }
+            Record::Leaf(_) => {
+                self.leaves.insert(key, location);
+            }
+            Record::CompactLeaf(_) => {
+                self.compact_leaves.insert(key, location);
+            }
+            Record::DeleteBranch(_) => {
+                self.branches.remove(&key);
+            }
+            Record::DeleteLeaf(_) => {
+                self.leaves.remove(&key);
+            }
+            Record::DeleteCompactLeaf(_) => {
+                self.compact_leaves.remove(&key);
+            }
+            Record::Root(root) => {
+                self.root = Some(root);
+            }
+            Record::RefCount(kind, _, 0) => {
+                self.ref_counts.remove(&(kind, key));
+            }
+            Record::RefCount(kind, _, count) => {
+                self.ref_counts.insert((kind, key), count as usize);
+            }
+        }
+    }
+
+    /// Appends records to the log as a single committed unit and updates the index.
+    /// If the write fails the log is truncated back to its previous length, and the database
+    /// is poisoned if that fails too.
+    fn append(&mut self, records: Vec<Record<HASH_SIZE, H>>) -> Result<(), FileDbError> {
+        if self.poisoned {
+            return Err(FileDbError::Poisoned);
+        }
+        let mut buffer = Vec::new();
+        let mut locations = Vec::with_capacity(records.len());
+        let count = records.len();
+        for (i, record) in records.iter().enumerate() {
+            let start = buffer.len();
+            record.encode(i + 1 == count, &mut buffer);
+            locations.push(Location {
+                offset: self.len + (start + LEN_SIZE) as u64,
+                len: (buffer.len() - start - LEN_SIZE - HASH_SIZE) as u32,
+            });
+        }
+        let sync = records
+            .iter()
+            .any(|record| matches!(record, Record::Root(_)));
+        let written = self.writer.write_all(&buffer).and_then(|_| {
+            if sync {
+                self.writer.sync_data()
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(error) = written {
+            // The next appends land after the dangling bytes, they would be misplaced. The
+            // bytes are discarded on the next open anyway.
+            if self.writer.set_len(self.len).is_err() {
+                self.poisoned = true;
+            }
+            return Err(error.into());
+        }
+        self.len += buffer.len() as u64;
+        for (record, location) in records.into_iter().zip(locations) {
+            self.apply(record, location);
+        }
+        Ok(())
+    }
+
+    /// Reads the record body stored at `location`, checks it against its checksum and
+    /// decodes it. The file may have changed on disk since it was replayed.
+    fn read(&self, location: Location) -> Result<Record<HASH_SIZE, H>, FileDbError> {
+        let mut record = vec![0u8; location.len as usize + HASH_SIZE];
+        {
+            let mut reader = self.reader.lock().unwrap_or_else(|e| e.into_inner());
+            reader.seek(SeekFrom::Start(location.offset))?;
+            reader.read_exact(&mut record)?;
+        }
+        let (body, checksum) = record.split_at(location.len as usize);
+        (checksum == H::hash(body))
+            .then(|| Record::decode_body(body))
+            .flatten()
+            .ok_or(FileDbError::Corrupted {
+                offset: location.offset - LEN_SIZE as u64,
+            })
+    }
+
+    /// Gets a node by its hash, falling back on the empty node at `height`.
+    fn get_node(
+        &self,
+        height: usize,
+        key: [u8; HASH_SIZE],
+    ) -> Result<Node<HASH_SIZE, H>, FileDbError> {
+        if key == self.empty_tree[height].hash() {
+            return Ok(self.empty_tree[height].clone());
+        }
+        let location = if let Some(location) = self.branches.get(&key) {
+            location
+        } else if let Some(location) = self.leaves.get(&key) {
+            location
+        } else if let Some(location) = self.compact_leaves.get(&key) {
+            location
+        } else {
+            return Ok(self.empty_tree[height].clone());
+        };
+        Ok(match self.read(*location)? {
+            Record::Branch(branch) => Node::Branch(branch),
+            Record::Leaf(leaf) => Node::Leaf(leaf),
+            Record::CompactLeaf(compact_leaf) => Node::Compact(compact_leaf),
+            _ => {
+                return Err(FileDbError::Corrupted {
+                    offset: location.offset - LEN_SIZE as u64,
+                })
+            }
+        })
+    }
+}
+
+/// Reads as many bytes as possible into `buf`, returning how many were read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone + ThreadSafe> Db<HASH_SIZE, H>
+    for FileDb<HASH_SIZE, H>
+{
+    type DbError = FileDbError;
+
+    fn get_root_node(&self) -> Option<Branch<HASH_SIZE, H>> {
+        self.root.clone()
+    }
+
+    fn get_children(
+        &self,
+        height: usize,
+        key: [u8; HASH_SIZE],
+    ) -> Result<(Node<HASH_SIZE, H>, Node<HASH_SIZE, H>), TreeError<Self::DbError>> {
+        let node = self.get_node(height, key)?;
+        if key != self.empty_tree[height].hash() && node.hash() == self.empty_tree[height].hash() {
+            return Err(TreeError::node_not_found(height, &key));
+        }
+        if let Node::Branch(branch) = node {
+            Ok((
+                self.get_node(height + 1, branch.left().hash())?,
+                self.get_node(height + 1, branch.right().hash())?,
+            ))
+        } else {
+            Err(TreeError::expected_branch(height, &key))
+        }
+    }
+
+    fn insert_leaf(&mut self, leaf: Leaf<HASH_SIZE, H>) -> Result<(), TreeError<Self::DbError>> {
+        Ok(self.append(vec![Record::Leaf(leaf)])?)
+    }
+
+    fn insert_branch(
+        &mut self,
+        branch: Branch<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        Ok(self.append(vec![Record::Branch(branch)])?)
+    }
+
+    fn insert_compact_leaf(
+        &mut self,
+        compact_leaf: CompactLeaf<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        Ok(self.append(vec![Record::CompactLeaf(compact_leaf)])?)
+    }
+
+    fn empty_tree(&self) -> Arc<Vec<Node<HASH_SIZE, H>>> {
+        self.empty_tree.clone()
+    }
+
+    fn update_root(&mut self, root: Branch<HASH_SIZE, H>) -> Result<(), TreeError<Self::DbError>> {
+        Ok(self.append(vec![Record::Root(root)])?)
+    }
+
+    fn delete_branch(&mut self, key: &[u8; HASH_SIZE]) -> Result<(), TreeError<Self::DbError>> {
+        if !self.branches.contains_key(key) {
+            return Ok(());
+        }
+        Ok(self.append(vec![Record::DeleteBranch(*key)])?)
+    }
+
+    fn delete_leaf(&mut self, key: &[u8; HASH_SIZE]) -> Result<(), TreeError<Self::DbError>> {
+        if !self.leaves.contains_key(key) {
+            return Ok(());
+        }
+        Ok(self.append(vec![Record::DeleteLeaf(*key)])?)
+    }
+
+    fn delete_compact_leaf(
+        &mut self,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<(), TreeError<Self::DbError>> {
+        if !self.compact_leaves.contains_key(key) {
+            return Ok(());
+        }
+        Ok(self.append(vec![Record::DeleteCompactLeaf(*key)])?)
+    }
+
+    /// Writes the whole batch as a single committed unit of the log. If the process crashes
+    /// before the last record is durable, none of the batch is visible on reopen.
+    fn write_batch(
+        &mut self,
+        batch: WriteBatch<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        let records = batch
+            .into_iter()
+            .map(|op| match op {
+                WriteOp::InsertLeaf(leaf) => Record::Leaf(leaf),
+                WriteOp::InsertBranch(branch) => Record::Branch(branch),
+                WriteOp::InsertCompactLeaf(compact_leaf) => Record::CompactLeaf(compact_leaf),
+                WriteOp::DeleteBranch(key) => Record::DeleteBranch(key),
+                WriteOp::DeleteLeaf(key) => Record::DeleteLeaf(key),
+                WriteOp::DeleteCompactLeaf(key) => Record::DeleteCompactLeaf(key),
+                WriteOp::UpdateRoot(root) => Record::Root(root),
+                WriteOp::SetRefCount(kind, key, count) => Record::RefCount(kind, key, count as u64),
+            })
+            .collect::<Vec<_>>();
+        if records.is_empty() {
+            return Ok(());
+        }
+        Ok(self.append(records)?)
+    }
+
+    /// Sizes are `0`: the log is append-only, deleting a node only removes it from the index
+    /// and gives no disk space back.
+    fn stored_nodes(&self) -> Result<Vec<StoredNode<HASH_SIZE>>, TreeError<Self::DbError>> {
+        let nodes = |kind, index: &HashMap<[u8; HASH_SIZE], Location>| {
+            index
+                .keys()
+                .map(move |hash| StoredNode {
+                    kind,
+                    hash: *hash,
+                    size: 0,
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut stored = nodes(NodeKind::Branch, &self.branches);
+        stored.extend(nodes(NodeKind::Leaf, &self.leaves));
+        stored.extend(nodes(NodeKind::CompactLeaf, &self.compact_leaves));
+        Ok(stored)
+    }
+
+    fn get_ref_count(
+        &self,
+        kind: NodeKind,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<Option<usize>, TreeError<Self::DbError>> {
+        Ok(self.ref_counts.get(&(kind, *key)).copied())
+    }
+
+    fn set_ref_count(
+        &mut self,
+        kind: NodeKind,
+        key: &[u8; HASH_SIZE],
+        count: usize,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        Ok(self.append(vec![Record::RefCount(kind, *key, count as u64)])?)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use super::{FileDb, FileDbError};
+    use crate::{Branch, CompactMSSMT, Db, EmptyTree, Leaf, Node, TreeError, MSSMT};
+    use hex_literal::hex;
+    use sha2::Sha256;
+
+    #[test]
+    fn test_file_db_new() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = FileDb::<32, Sha256>::open(dir.path().join("db")).unwrap();
+        assert_eq!(db.empty_tree().len(), 257);
+        assert!(db.get_root_node().is_none());
+    }
+
+    #[test]
+    fn test_file_db_get_children() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = FileDb::<32, Sha256>::open(dir.path().join("db")).unwrap();
+        assert_eq!(
+            db.get_children(
+                0,
+                hex!("0000000000000000000000000000000000000000000000000000000000000000")
+            )
+            .unwrap_err(),
+            TreeError::node_not_found(0, &[0; 32])
+        );
+        let leaf = Leaf::<32, Sha256>::new(vec![1, 2, 3], 1);
+        let branch = Branch::new(Node::Leaf(leaf.clone()), Node::new_empty_leaf());
+        db.insert_leaf(leaf.clone()).unwrap();
+        db.insert_branch(branch.clone()).unwrap();
+        let (left, right) = db.get_children(255, branch.hash()).unwrap();
+        assert_eq!(left.hash(), leaf.hash());
+        assert_eq!(
+            right.hash(),
+            EmptyTree::<32, Sha256>::empty_tree()[256].hash()
+        );
+        assert_eq!(
+            db.get_children(0, leaf.hash()).unwrap_err(),
+            TreeError::expected_branch(0, &leaf.hash())
+        );
+    }
+
+    #[test]
+    fn test_file_db_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let mut db = FileDb::<32, Sha256>::open(&path).unwrap();
+        let leaf = Leaf::<32, Sha256>::new(vec![1, 2, 3], 1);
+        db.insert_leaf(leaf.clone()).unwrap();
+        assert_eq!(db.leaf_count(), 1);
+        db.delete_leaf(&leaf.hash()).unwrap();
+        assert_eq!(db.leaf_count(), 0);
+        drop(db);
+        let db = FileDb::<32, Sha256>::open(&path).unwrap();
+        assert_eq!(db.leaf_count(), 0);
+    }
+
+    #[test]
+    fn test_file_db_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let leaf = Leaf::new(vec![1, 2, 3], 100);
+
+        let mut tree = MSSMT::<32, Sha256, FileDbError>::new(Box::new(
+            FileDb::<32, Sha256>::open(&path).unwrap(),
+        ));
+        tree.insert(&[1; 32], leaf.clone()).unwrap();
+        tree.insert(&[2; 32], Leaf::new(vec![4, 5, 6], 200))
+            .unwrap();
+        let root = tree.root().unwrap();
+        drop(tree);
+
+        let tree = MSSMT::<32, Sha256, FileDbError>::new(Box::new(
+            FileDb::<32, Sha256>::open(&path).unwrap(),
+        ));
+        assert_eq!(tree.db().get_root_node().unwrap().hash(), root.hash());
+        assert_eq!(tree.root().unwrap().sum(), 300);
+        assert_eq!(tree.get(&[1; 32]).unwrap().hash(), leaf.hash());
+        tree.merkle_proof(&[1; 32])
+            .unwrap()
+            .verify_merkle_proof::<()>(&[1; 32], leaf, root.hash())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_file_db_reopen_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let leaf = Leaf::new(vec![1, 2, 3], 100);
+
+        let mut tree = CompactMSSMT::<32, Sha256, FileDbError>::new(Box::new(
+            FileDb::<32, Sha256>::open(&path).unwrap(),
+        ));
+        tree.insert(&[1; 32], leaf.clone()).unwrap();
+        tree.insert(&[2; 32], Leaf::new(vec![4, 5, 6], 200))
+            .unwrap();
+        let root = tree.root().unwrap();
+        drop(tree);
+
+        let tree = CompactMSSMT::<32, Sha256, FileDbError>::new(Box::new(
+            FileDb::<32, Sha256>::open(&path).unwrap(),
+        ));
+        assert_eq!(tree.root().unwrap().hash(), root.hash());
+        assert_eq!(
+            tree.walk_down(&[1; 32], |_, _, _, _| {}).unwrap().hash(),
+            leaf.hash()
+        );
+    }
+
+    #[test]
+    fn test_file_db_write_batch_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let mut tree = MSSMT::<32, Sha256, FileDbError>::new(Box::new(
+            FileDb::<32, Sha256>::open(&path).unwrap(),
+        ));
+        tree.insert(&[1; 32], Leaf::new(vec![1, 2, 3], 100))
+            .unwrap();
+        let root = tree.root().unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        tree.insert(&[2; 32], Leaf::new(vec![4, 5, 6], 200))
+            .unwrap();
+        drop(tree);
+
+        // Lose the end of the second insertion, every record of it must be discarded.
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(std::fs::metadata(&path).unwrap().len() - 1)
+            .unwrap();
+        drop(file);
+
+        let db = FileDb::<32, Sha256>::open(&path).unwrap();
+        assert_eq!(db.get_root_node().unwrap().hash(), root.hash());
+        assert_eq!(db.leaf_count(), 1);
+        assert_eq!(db.branch_count(), 256);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn test_file_db_torn_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let mut tree = MSSMT::<32, Sha256, FileDbError>::new(Box::new(
+            FileDb::<32, Sha256>::open(&path).unwrap(),
+        ));
+        tree.insert(&[1; 32], Leaf::new(vec![1, 2, 3], 100))
+            .unwrap();
+        let root = tree.root().unwrap();
+        drop(tree);
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        // Simulate a crash in the middle of a write.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 200, 1, 1, 42]).unwrap();
+        drop(file);
+
+        let db = FileDb::<32, Sha256>::open(&path).unwrap();
+        assert_eq!(db.get_root_node().unwrap().hash(), root.hash());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn test_file_db_garbage_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let mut tree = MSSMT::<32, Sha256, FileDbError>::new(Box::new(
+            FileDb::<32, Sha256>::open(&path).unwrap(),
+        ));
+        tree.insert(&[1; 32], Leaf::new(vec![1, 2, 3], 100))
+            .unwrap();
+        let root = tree.root().unwrap();
+        drop(tree);
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        // A crash can leave zeroes after the last commit, read as several damaged records.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0; 100]).unwrap();
+        drop(file);
+
+        let db = FileDb::<32, Sha256>::open(&path).unwrap();
+        assert_eq!(db.get_root_node().unwrap().hash(), root.hash());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn test_file_db_corrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let mut db = FileDb::<32, Sha256>::open(&path).unwrap();
+        db.insert_leaf(Leaf::new(vec![1, 2, 3], 1)).unwrap();
+        db.insert_leaf(Leaf::new(vec![4, 5, 6], 2)).unwrap();
+        drop(db);
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[10] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+        assert_eq!(
+            FileDb::<32, Sha256>::open(&path).unwrap_err(),
+            FileDbError::Corrupted { offset: 0 }
+        );
+    }
+
+    #[test]
+    fn test_file_db_corrupted_after_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let mut db = FileDb::<32, Sha256>::open(&path).unwrap();
+        let leaf = Leaf::<32, Sha256>::new(vec![1, 2, 3], 1);
+        let branch = Branch::new(Node::Leaf(leaf.clone()), Node::new_empty_leaf());
+        db.insert_leaf(leaf).unwrap();
+        let offset = std::fs::metadata(&path).unwrap().len();
+        db.insert_branch(branch.clone()).unwrap();
+
+        // Flip a byte of the stored branch hash behind the back of the open database.
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[offset as usize + 10] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+        assert_eq!(
+            db.get_children(255, branch.hash()).unwrap_err(),
+            TreeError::DbError(FileDbError::Corrupted { offset })
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use std::{any::Any, collections::HashMap, sync::Arc};
 
 use crate::{
-    db::Db,
+    db::{Db, NodeKind, StoredNode},
     node::{Branch, CompactLeaf, Hasher, Leaf, Node},
     tree::EmptyTree,
     ThreadSafe, TreeError,
@@ -15,6 +15,8 @@ pub struct MemoryDb<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
     compact_leaves: HashMap<[u8; HASH_SIZE], CompactLeaf<HASH_SIZE, H>>,
     empty_tree: Arc<Vec<Node<HASH_SIZE, H>>>,
     root: Option<Branch<HASH_SIZE, H>>,
+    ref_counts: HashMap<(NodeKind, [u8; HASH_SIZE]), usize>,
+    strict: bool,
 }
 
 impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> MemoryDb<HASH_SIZE, H> {
@@ -25,8 +27,27 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> MemoryDb<HASH_SIZE, H
             compact_leaves: HashMap::new(),
             empty_tree: EmptyTree::<HASH_SIZE, H>::empty_tree(),
             root: None,
+            ref_counts: HashMap::new(),
+            strict: false,
         }
     }
+
+    /// Creates a database that errors with [`TreeError::NodeNotFound`] when a child of a
+    /// branch isn't stored, instead of reading it as an empty subtree.
+    ///
+    /// A missing child means the database is corrupted: reading it as empty would silently
+    /// produce wrong roots and proofs.
+    pub fn new_strict() -> Self {
+        Self {
+            strict: true,
+            ..Self::new()
+        }
+    }
+
+    /// Whether missing children are reported instead of read as empty subtrees.
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
     pub fn get_branches(&self) -> &HashMap<[u8; HASH_SIZE], Branch<HASH_SIZE, H>> {
         &self.branches
     }
@@ -60,30 +81,29 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone + ThreadSafe> Db<HASH_
     ) -> Result<(Node<HASH_SIZE, H>, Node<HASH_SIZE, H>), TreeError<Self::DbError>> {
         let get_node = |height: usize, key: [u8; HASH_SIZE]| {
             if key == self.empty_tree()[height].hash() {
-                self.empty_tree()[height].clone()
+                Some(self.empty_tree()[height].clone())
             } else if let Some(node) = self.branches.get(&key) {
-                Node::Branch(node.clone())
+                Some(Node::Branch(node.clone()))
             } else if let Some(leaf) = self.leaves.get(&key) {
-                Node::Leaf(leaf.clone())
-            } else if let Some(compact) = self.compact_leaves.get(&key) {
-                Node::Compact(compact.clone())
+                Some(Node::Leaf(leaf.clone()))
             } else {
-                self.empty_tree()[height].clone()
+                self.compact_leaves
+                    .get(&key)
+                    .map(|compact| Node::Compact(compact.clone()))
             }
         };
-        let node = get_node(height, key);
-        if key != self.empty_tree()[height].hash()
-            && node.hash() == self.empty_tree()[height].hash()
-        {
-            return Err(TreeError::NodeNotFound);
-        }
-        if let Node::Branch(branch) = node {
-            Ok((
-                get_node(height + 1, branch.left().hash()),
-                get_node(height + 1, branch.right().hash()),
-            ))
-        } else {
-            Err(TreeError::ExpectedBranch)
+        let get_child = |height: usize, key: [u8; HASH_SIZE]| match get_node(height, key) {
+            Some(node) => Ok(node),
+            None if self.strict => Err(TreeError::node_not_found(height, &key)),
+            None => Ok(self.empty_tree()[height].clone()),
+        };
+        match get_node(height, key) {
+            Some(Node::Branch(branch)) => Ok((
+                get_child(height + 1, branch.left().hash())?,
+                get_child(height + 1, branch.right().hash())?,
+            )),
+            Some(_) => Err(TreeError::expected_branch(height, &key)),
+            None => Err(TreeError::node_not_found(height, &key)),
         }
     }
 
@@ -136,6 +156,51 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone + ThreadSafe> Db<HASH_
         Ok(())
     }
 
+    /// Sizes are the ones of the hashes, sums, keys and values held by the nodes.
+    fn stored_nodes(&self) -> Result<Vec<StoredNode<HASH_SIZE>>, TreeError<Self::DbError>> {
+        let branches = self.branches.keys().map(|hash| StoredNode {
+            kind: NodeKind::Branch,
+            hash: *hash,
+            size: 3 * HASH_SIZE + 8,
+        });
+        let leaves = self.leaves.iter().map(|(hash, leaf)| StoredNode {
+            kind: NodeKind::Leaf,
+            hash: *hash,
+            size: HASH_SIZE + leaf.value().len() + 8,
+        });
+        let compact_leaves = self
+            .compact_leaves
+            .iter()
+            .map(|(hash, compact)| StoredNode {
+                kind: NodeKind::CompactLeaf,
+                hash: *hash,
+                size: 2 * HASH_SIZE + compact.leaf().value().len() + 8,
+            });
+        Ok(branches.chain(leaves).chain(compact_leaves).collect())
+    }
+
+    fn get_ref_count(
+        &self,
+        kind: NodeKind,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<Option<usize>, TreeError<Self::DbError>> {
+        Ok(self.ref_counts.get(&(kind, *key)).copied())
+    }
+
+    fn set_ref_count(
+        &mut self,
+        kind: NodeKind,
+        key: &[u8; HASH_SIZE],
+        count: usize,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        if count == 0 {
+            self.ref_counts.remove(&(kind, *key));
+        } else {
+            self.ref_counts.insert((kind, *key), count);
+        }
+        Ok(())
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
@@ -169,7 +234,7 @@ mod test {
                 hex!("0000000000000000000000000000000000000000000000000000000000000000")
             )
             .unwrap_err(),
-            TreeError::NodeNotFound
+            TreeError::node_not_found(0, &[0; 32])
         );
         let Node::Branch(empty_root) = db.empty_tree()[0].clone() else {
             panic!("Empty root is not a branch");
@@ -212,7 +277,28 @@ mod test {
         db.insert_leaf(leaf.clone()).unwrap();
         assert_eq!(
             db.get_children(0, leaf.hash()).unwrap_err(),
-            TreeError::ExpectedBranch
+            TreeError::expected_branch(0, &leaf.hash())
+        );
+    }
+
+    #[test]
+    fn test_memory_db_strict_missing_child() {
+        let leaf = Leaf::<32, Sha256>::new(vec![1, 2, 3], 1);
+        let branch = Branch::new(Node::Leaf(leaf.clone()), Node::new_empty_leaf());
+        let mut db = MemoryDb::<32, Sha256>::new();
+        db.insert_branch(branch.clone()).unwrap();
+        let (left, _) = db.get_children(255, branch.hash()).unwrap();
+        assert_eq!(left.hash(), db.empty_tree()[256].hash());
+
+        let mut db = MemoryDb::<32, Sha256>::new_strict();
+        db.insert_branch(branch.clone()).unwrap();
+        assert_eq!(
+            db.get_children(255, branch.hash()).unwrap_err(),
+            TreeError::node_not_found(256, &leaf.hash())
         );
+        db.insert_leaf(leaf.clone()).unwrap();
+        let (left, right) = db.get_children(255, branch.hash()).unwrap();
+        assert_eq!(left.hash(), leaf.hash());
+        assert_eq!(right.hash(), db.empty_tree()[256].hash());
     }
 }
@@ -1,8 +1,18 @@
 //! Database trait and implementations for the Merkle Sum Sparse Merkle Tree
 
+mod async_db;
+mod batch;
+mod cache;
+mod file;
 mod memory;
+mod refcount;
 
+pub use async_db::*;
+pub use batch::*;
+pub use cache::*;
+pub use file::*;
 pub use memory::*;
+pub use refcount::*;
 
 use std::{any::Any, sync::Arc};
 
@@ -22,6 +32,39 @@ pub trait ThreadSafe {}
 #[cfg(not(feature = "multi-thread"))]
 impl<T> ThreadSafe for T {}
 
+/// Marker of the futures returned by an [`AsyncDb`], `Send` with the `multi-thread` feature
+#[cfg(feature = "multi-thread")]
+pub trait MaybeSend: Send {}
+#[cfg(feature = "multi-thread")]
+impl<T: Send> MaybeSend for T {}
+
+#[cfg(not(feature = "multi-thread"))]
+pub trait MaybeSend {}
+#[cfg(not(feature = "multi-thread"))]
+impl<T> MaybeSend for T {}
+
+/// Kind of a node stored in a [`Db`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NodeKind {
+    /// A branch node, stored with its two children
+    Branch,
+    /// A leaf node of a regular tree
+    Leaf,
+    /// A compact leaf node, stored with its key above its empty subtree
+    CompactLeaf,
+}
+
+/// A node stored in a [`Db`], listed by [`Db::stored_nodes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoredNode<const HASH_SIZE: usize> {
+    /// Kind of the node
+    pub kind: NodeKind,
+    /// Hash of the node, the key it's stored under
+    pub hash: [u8; HASH_SIZE],
+    /// Bytes given back to the storage when the node is deleted
+    pub size: usize,
+}
+
 /// Store for the tree nodes
 ///
 /// This trait must be implemented by any storage backend used with the tree.
@@ -74,5 +117,62 @@ pub trait Db<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone>: ThreadSafe {
         key: &[u8; HASH_SIZE],
     ) -> Result<(), TreeError<Self::DbError>>;
 
+    /// List every branch, leaf and compact leaf stored in the database.
+    ///
+    /// Pruning and integrity checks need it, databases that can't list their nodes keep the
+    /// default implementation returning [`TreeError::Unsupported`].
+    fn stored_nodes(&self) -> Result<Vec<StoredNode<HASH_SIZE>>, TreeError<Self::DbError>> {
+        Err(TreeError::Unsupported)
+    }
+
+    /// Get the reference count stored for a node by a [`RefCountedDb`], `None` if it has none.
+    ///
+    /// Databases that can't store counts keep the default implementation returning
+    /// [`TreeError::Unsupported`], and can't be wrapped in a [`RefCountedDb`].
+    fn get_ref_count(
+        &self,
+        _kind: NodeKind,
+        _key: &[u8; HASH_SIZE],
+    ) -> Result<Option<usize>, TreeError<Self::DbError>> {
+        Err(TreeError::Unsupported)
+    }
+
+    /// Store the reference count of a node, `0` removing it. See [`Db::get_ref_count`].
+    fn set_ref_count(
+        &mut self,
+        _kind: NodeKind,
+        _key: &[u8; HASH_SIZE],
+        _count: usize,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        Err(TreeError::Unsupported)
+    }
+
+    /// Apply all the operations of the batch, in order.
+    ///
+    /// Trees only write through this method so a failing operation never leaves a
+    /// half-applied update behind. The default implementation applies the operations one by
+    /// one, which is only atomic if they can't fail: fallible backends should override it so
+    /// that either every operation lands or none does.
+    fn write_batch(
+        &mut self,
+        batch: WriteBatch<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        for op in batch {
+            match op {
+                WriteOp::InsertLeaf(leaf) => self.insert_leaf(leaf)?,
+                WriteOp::InsertBranch(branch) => self.insert_branch(branch)?,
+                WriteOp::InsertCompactLeaf(compact_leaf) => {
+                    self.insert_compact_leaf(compact_leaf)?
+                }
+                WriteOp::DeleteBranch(key) => self.delete_branch(&key)?,
+                WriteOp::DeleteLeaf(key) => self.delete_leaf(&key)?,
+                WriteOp::DeleteCompactLeaf(key) => self.delete_compact_leaf(&key)?,
+                WriteOp::UpdateRoot(root) => self.update_root(root)?,
+                WriteOp::SetRefCount(kind, key, count) => self.set_ref_count(kind, &key, count)?,
+            }
+        }
+        Ok(())
+    }
+
     fn as_any(&self) -> &dyn Any;
 }
@@ -0,0 +1,397 @@
+use std::{any::Any, collections::HashMap, marker::PhantomData, sync::Arc};
+
+use crate::{
+    db::{Db, NodeKind, StoredNode, WriteBatch, WriteOp},
+    node::{Branch, CompactLeaf, Hasher, Leaf, Node},
+    ThreadSafe, TreeError,
+};
+
+/// A database layer counting the references to each node it stores.
+///
+/// Nodes are stored by hash so identical subtrees, in the same tree or across versions, are
+/// stored once. With a plain database the first deletion of such a node breaks every other
+/// subtree holding it. This layer counts the insertions of each node and only deletes it
+/// from the inner database once it was deleted as many times as it was inserted.
+///
+/// The counts that change are stored in the inner database with [`Db::set_ref_count`], in the
+/// same [`Db::write_batch`] as the nodes, so they survive reopening a persistent database and
+/// the in-memory copy is only updated if the batch succeeds. Pruning releases a single
+/// reference of each node it sweeps.
+pub struct RefCountedDb<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, D> {
+    inner: D,
+    counts: HashMap<(NodeKind, [u8; HASH_SIZE]), usize>,
+    _phantom: PhantomData<H>,
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, D: Db<HASH_SIZE, H>>
+    RefCountedDb<HASH_SIZE, H, D>
+{
+    /// Wraps `inner`, reading back the counts it stores. Nodes stored without a count, written
+    /// without this layer, start with a single reference.
+    ///
+    /// Returns [`TreeError::Unsupported`] if `inner` can't list its nodes or store counts.
+    pub fn new(inner: D) -> Result<Self, TreeError<D::DbError>> {
+        // Fails early on databases that can't store the counts, even empty ones.
+        inner.get_ref_count(NodeKind::Branch, &[0; HASH_SIZE])?;
+        let mut counts = HashMap::new();
+        for node in inner.stored_nodes()? {
+            let count = inner.get_ref_count(node.kind, &node.hash)?.unwrap_or(1);
+            counts.insert((node.kind, node.hash), count);
+        }
+        Ok(Self {
+            inner,
+            counts,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Returns the inner database.
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Unwraps the inner database, which keeps the counts.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Number of references to the node of `kind` whose hash is `hash`, `0` if it isn't
+    /// stored.
+    pub fn ref_count(&self, kind: NodeKind, hash: &[u8; HASH_SIZE]) -> usize {
+        self.counts.get(&(kind, *hash)).copied().unwrap_or(0)
+    }
+
+    /// Adds a reference to `node` in `staged` and returns whether it must be inserted in the
+    /// inner database.
+    fn acquire(
+        &self,
+        staged: &mut HashMap<(NodeKind, [u8; HASH_SIZE]), usize>,
+        node: (NodeKind, [u8; HASH_SIZE]),
+    ) -> bool {
+        let count = staged
+            .entry(node)
+            .or_insert_with(|| self.ref_count(node.0, &node.1));
+        *count += 1;
+        *count == 1
+    }
+
+    /// Removes a reference to `node` in `staged` and returns whether it must be deleted from
+    /// the inner database. Releasing a node without references does nothing.
+    fn release(
+        &self,
+        staged: &mut HashMap<(NodeKind, [u8; HASH_SIZE]), usize>,
+        node: (NodeKind, [u8; HASH_SIZE]),
+    ) -> bool {
+        let count = staged
+            .entry(node)
+            .or_insert_with(|| self.ref_count(node.0, &node.1));
+        if *count == 0 {
+            return false;
+        }
+        *count -= 1;
+        *count == 0
+    }
+}
+
+impl<
+        const HASH_SIZE: usize,
+        H: Hasher<HASH_SIZE> + Clone + ThreadSafe,
+        D: Db<HASH_SIZE, H> + 'static,
+    > Db<HASH_SIZE, H> for RefCountedDb<HASH_SIZE, H, D>
+{
+    type DbError = D::DbError;
+
+    fn get_root_node(&self) -> Option<Branch<HASH_SIZE, H>> {
+        self.inner.get_root_node()
+    }
+
+    fn get_children(
+        &self,
+        height: usize,
+        key: [u8; HASH_SIZE],
+    ) -> Result<(Node<HASH_SIZE, H>, Node<HASH_SIZE, H>), TreeError<Self::DbError>> {
+        self.inner.get_children(height, key)
+    }
+
+    fn insert_leaf(&mut self, leaf: Leaf<HASH_SIZE, H>) -> Result<(), TreeError<Self::DbError>> {
+        let mut batch = WriteBatch::new();
+        batch.insert_leaf(leaf);
+        self.write_batch(batch)
+    }
+
+    fn insert_branch(
+        &mut self,
+        branch: Branch<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        let mut batch = WriteBatch::new();
+        batch.insert_branch(branch);
+        self.write_batch(batch)
+    }
+
+    fn insert_compact_leaf(
+        &mut self,
+        compact_leaf: CompactLeaf<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        let mut batch = WriteBatch::new();
+        batch.insert_compact_leaf(compact_leaf);
+        self.write_batch(batch)
+    }
+
+    fn empty_tree(&self) -> Arc<Vec<Node<HASH_SIZE, H>>> {
+        self.inner.empty_tree()
+    }
+
+    fn update_root(&mut self, root: Branch<HASH_SIZE, H>) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.update_root(root)
+    }
+
+    fn delete_branch(&mut self, key: &[u8; HASH_SIZE]) -> Result<(), TreeError<Self::DbError>> {
+        let mut batch = WriteBatch::new();
+        batch.delete_branch(key);
+        self.write_batch(batch)
+    }
+
+    fn delete_leaf(&mut self, key: &[u8; HASH_SIZE]) -> Result<(), TreeError<Self::DbError>> {
+        let mut batch = WriteBatch::new();
+        batch.delete_leaf(key);
+        self.write_batch(batch)
+    }
+
+    fn delete_compact_leaf(
+        &mut self,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<(), TreeError<Self::DbError>> {
+        let mut batch = WriteBatch::new();
+        batch.delete_compact_leaf(key);
+        self.write_batch(batch)
+    }
+
+    fn stored_nodes(&self) -> Result<Vec<StoredNode<HASH_SIZE>>, TreeError<Self::DbError>> {
+        self.inner.stored_nodes()
+    }
+
+    fn get_ref_count(
+        &self,
+        kind: NodeKind,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<Option<usize>, TreeError<Self::DbError>> {
+        self.inner.get_ref_count(kind, key)
+    }
+
+    fn set_ref_count(
+        &mut self,
+        kind: NodeKind,
+        key: &[u8; HASH_SIZE],
+        count: usize,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        self.inner.set_ref_count(kind, key, count)
+    }
+
+    /// Only the first insertion and the last deletion of a node reach the inner database.
+    fn write_batch(
+        &mut self,
+        batch: WriteBatch<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<Self::DbError>> {
+        let mut staged = HashMap::new();
+        let mut inner_batch = WriteBatch::new();
+        for op in batch {
+            match op {
+                WriteOp::InsertLeaf(leaf) => {
+                    if self.acquire(&mut staged, (NodeKind::Leaf, leaf.hash())) {
+                        inner_batch.insert_leaf(leaf);
+                    }
+                }
+                WriteOp::InsertBranch(branch) => {
+                    if self.acquire(&mut staged, (NodeKind::Branch, branch.hash())) {
+                        inner_batch.insert_branch(branch);
+                    }
+                }
+                WriteOp::InsertCompactLeaf(compact_leaf) => {
+                    if self.acquire(&mut staged, (NodeKind::CompactLeaf, compact_leaf.hash())) {
+                        inner_batch.insert_compact_leaf(compact_leaf);
+                    }
+                }
+                WriteOp::DeleteBranch(key) => {
+                    if self.release(&mut staged, (NodeKind::Branch, key)) {
+                        inner_batch.delete_branch(&key);
+                    }
+                }
+                WriteOp::DeleteLeaf(key) => {
+                    if self.release(&mut staged, (NodeKind::Leaf, key)) {
+                        inner_batch.delete_leaf(&key);
+                    }
+                }
+                WriteOp::DeleteCompactLeaf(key) => {
+                    if self.release(&mut staged, (NodeKind::CompactLeaf, key)) {
+                        inner_batch.delete_compact_leaf(&key);
+                    }
+                }
+                WriteOp::UpdateRoot(root) => inner_batch.update_root(root),
+                WriteOp::SetRefCount(kind, key, count) => {
+                    inner_batch.set_ref_count(kind, &key, count)
+                }
+            }
+        }
+        for (&(kind, key), &count) in &staged {
+            if count != self.ref_count(kind, &key) {
+                inner_batch.set_ref_count(kind, &key, count);
+            }
+        }
+        self.inner.write_batch(inner_batch)?;
+        for (node, count) in staged {
+            if count == 0 {
+                self.counts.remove(&node);
+            } else {
+                self.counts.insert(node, count);
+            }
+        }
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::Sha256;
+
+    use super::RefCountedDb;
+    use crate::{CompactMSSMT, Db, FileDb, Leaf, MemoryDb, NodeKind, WriteBatch, MSSMT};
+
+    fn leaves() -> Vec<([u8; 32], Leaf<32, Sha256>)> {
+        (0..10u8)
+            .map(|i| ([i.wrapping_mul(37); 32], Leaf::new(vec![i + 1], i as u64)))
+            .collect()
+    }
+
+    #[test]
+    fn test_ref_count() {
+        let mut db = RefCountedDb::new(MemoryDb::<32, Sha256>::new()).unwrap();
+        let leaf = Leaf::new(vec![1], 1);
+        db.insert_leaf(leaf.clone()).unwrap();
+        db.insert_leaf(leaf.clone()).unwrap();
+        assert_eq!(db.ref_count(NodeKind::Leaf, &leaf.hash()), 2);
+        db.delete_leaf(&leaf.hash()).unwrap();
+        assert_eq!(db.ref_count(NodeKind::Leaf, &leaf.hash()), 1);
+        assert!(db.inner().get_leaves().contains_key(&leaf.hash()));
+        db.delete_leaf(&leaf.hash()).unwrap();
+        assert_eq!(db.ref_count(NodeKind::Leaf, &leaf.hash()), 0);
+        assert!(db.inner().get_leaves().is_empty());
+        // Releasing an unreferenced node does nothing.
+        db.delete_leaf(&leaf.hash()).unwrap();
+        db.insert_leaf(leaf.clone()).unwrap();
+        assert_eq!(db.ref_count(NodeKind::Leaf, &leaf.hash()), 1);
+
+        // A node deleted then inserted again in a batch stays stored.
+        let mut batch = WriteBatch::new();
+        batch.delete_leaf(&leaf.hash());
+        batch.insert_leaf(leaf.clone());
+        db.write_batch(batch).unwrap();
+        assert_eq!(db.ref_count(NodeKind::Leaf, &leaf.hash()), 1);
+        assert!(db.inner().get_leaves().contains_key(&leaf.hash()));
+    }
+
+    #[test]
+    fn test_ref_count_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let open = || RefCountedDb::new(FileDb::<32, Sha256>::open(&path).unwrap()).unwrap();
+        let leaf = Leaf::new(vec![1], 1);
+        let mut db = open();
+        db.insert_leaf(leaf.clone()).unwrap();
+        db.insert_leaf(leaf.clone()).unwrap();
+        drop(db);
+
+        // The counts are read back from the log.
+        let mut db = open();
+        assert_eq!(db.ref_count(NodeKind::Leaf, &leaf.hash()), 2);
+        db.delete_leaf(&leaf.hash()).unwrap();
+        assert_eq!(db.inner().leaf_count(), 1);
+        drop(db);
+
+        let mut db = open();
+        assert_eq!(db.ref_count(NodeKind::Leaf, &leaf.hash()), 1);
+        db.delete_leaf(&leaf.hash()).unwrap();
+        assert_eq!(db.inner().leaf_count(), 0);
+        let db = db.into_inner();
+        assert_eq!(
+            db.get_ref_count(NodeKind::Leaf, &leaf.hash()).unwrap(),
+            None
+        );
+        drop(db);
+        assert_eq!(open().ref_count(NodeKind::Leaf, &leaf.hash()), 0);
+    }
+
+    #[test]
+    fn test_ref_count_existing_nodes() {
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        tree.insert_batch(&leaves()).unwrap();
+        let memory_db = tree
+            .db()
+            .as_any()
+            .downcast_ref::<MemoryDb<32, Sha256>>()
+            .unwrap()
+            .clone();
+        let db = RefCountedDb::new(memory_db).unwrap();
+        for node in db.stored_nodes().unwrap() {
+            assert_eq!(db.ref_count(node.kind, &node.hash), 1);
+        }
+        let tree = MSSMT::<32, Sha256, ()>::new(Box::new(db));
+        for (key, leaf) in leaves() {
+            assert_eq!(tree.get(&key).unwrap().hash(), leaf.hash());
+        }
+    }
+
+    #[test]
+    fn test_ref_count_identical_subtrees() {
+        // The keys only differ by their first bit so both subtrees of the root hold the same
+        // branches.
+        let leaf = Leaf::<32, Sha256>::new(vec![1], 1);
+        let mut key = [0; 32];
+        let mut tree =
+            MSSMT::<32, Sha256, ()>::new(Box::new(RefCountedDb::new(MemoryDb::default()).unwrap()));
+        tree.insert(&key, leaf.clone()).unwrap();
+        key[0] = 1;
+        tree.insert(&key, leaf.clone()).unwrap();
+        tree.delete(&[0; 32]).unwrap();
+        assert_eq!(tree.get(&key).unwrap().hash(), leaf.hash());
+        tree.merkle_proof(&key)
+            .unwrap()
+            .verify_merkle_proof::<()>(&key, leaf, tree.root().unwrap().hash())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_ref_count_release_all() {
+        let leaves = leaves();
+        let mut tree =
+            MSSMT::<32, Sha256, ()>::new(Box::new(RefCountedDb::new(MemoryDb::default()).unwrap()));
+        let mut compact_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(
+            RefCountedDb::new(MemoryDb::default()).unwrap(),
+        ));
+        tree.insert_batch(&leaves[..5]).unwrap();
+        compact_tree.insert_batch(&leaves[..5]).unwrap();
+        for (key, leaf) in &leaves {
+            tree.insert(key, leaf.clone()).unwrap();
+            compact_tree.insert(key, leaf.clone()).unwrap();
+        }
+        assert_eq!(
+            tree.root().unwrap().hash(),
+            compact_tree.root().unwrap().hash()
+        );
+        for (key, _) in &leaves {
+            tree.delete(key).unwrap();
+            compact_tree.delete(key).unwrap();
+        }
+        // Every branch was released, the regular tree never deletes its leaves.
+        assert!(tree
+            .db()
+            .stored_nodes()
+            .unwrap()
+            .iter()
+            .all(|node| node.kind == NodeKind::Leaf));
+        assert!(compact_tree.db().stored_nodes().unwrap().is_empty());
+    }
+}
@@ -7,9 +7,19 @@ use std::fmt::{Debug, Display};
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum TreeError<DbError> {
     /// Node was not found in the tree
-    NodeNotFound,
+    NodeNotFound {
+        /// Height of the missing node
+        height: usize,
+        /// Hash of the missing node
+        hash: Vec<u8>,
+    },
     /// Node is not a branch node
-    ExpectedBranch,
+    ExpectedBranch {
+        /// Height of the node
+        height: usize,
+        /// Hash of the node
+        hash: Vec<u8>,
+    },
     /// Node is not a leaf node
     ExpectedLeaf,
     /// Node is not a compact leaf node
@@ -22,19 +32,56 @@ pub enum TreeError<DbError> {
     SumOverflow,
     /// Invalid merkle proof
     InvalidMerkleProof,
+    /// Invalid proof encoding
+    InvalidProofEncoding,
+    /// Root or version not committed in the tree
+    UnknownRoot,
+    /// Operation not supported by the database
+    Unsupported,
+}
+
+impl<DbError> TreeError<DbError> {
+    /// Error of a missing node of hash `hash` at `height`.
+    pub fn node_not_found(height: usize, hash: &[u8]) -> Self {
+        TreeError::NodeNotFound {
+            height,
+            hash: hash.to_vec(),
+        }
+    }
+
+    /// Error of a node of hash `hash` at `height` that isn't a branch.
+    pub fn expected_branch(height: usize, hash: &[u8]) -> Self {
+        TreeError::ExpectedBranch {
+            height,
+            hash: hash.to_vec(),
+        }
+    }
 }
 
 impl<DbError: Display> Display for TreeError<DbError> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            TreeError::NodeNotFound => write!(f, "Node not found in tree"),
-            TreeError::ExpectedBranch => write!(f, "Node is not a branch node"),
+            TreeError::NodeNotFound { height, hash } => write!(
+                f,
+                "Node {} not found in tree at height {}",
+                hex::encode(hash),
+                height
+            ),
+            TreeError::ExpectedBranch { height, hash } => write!(
+                f,
+                "Node {} at height {} is not a branch node",
+                hex::encode(hash),
+                height
+            ),
             TreeError::ExpectedLeaf => write!(f, "Node is not a leaf node"),
             TreeError::ExpectedCompactLeaf => write!(f, "Node is not a compact leaf node"),
             TreeError::ExpectedEmptyLeaf => write!(f, "Node is not an empty tree node"),
             TreeError::DbError(e) => write!(f, "Database error: {}", e),
             TreeError::SumOverflow => write!(f, "Sum overflow"),
             TreeError::InvalidMerkleProof => write!(f, "Invalid merkle proof"),
+            TreeError::InvalidProofEncoding => write!(f, "Invalid proof encoding"),
+            TreeError::UnknownRoot => write!(f, "Unknown root"),
+            TreeError::Unsupported => write!(f, "Operation not supported by the database"),
         }
     }
 }
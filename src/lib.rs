@@ -7,18 +7,30 @@
 //! - Efficient sparse storage
 //! - Sum aggregation at each level
 //! - Cryptographic verification
-//! - Flexible storage backend through the `Db` trait
+//! - Flexible storage backend through the `Db` trait, with in-memory and file backed implementations
+//! - Serde support for the nodes and proofs behind the `serde` feature
 
 mod db;
 mod error;
 mod node;
 mod proof;
+#[cfg(feature = "serde")]
+mod serialization;
 mod tree;
 
-pub use db::{Db, MemoryDb, ThreadSafe};
+pub use db::{
+    AsyncDb, BlockingDb, CachedDb, Db, FileDb, FileDbError, MaybeSend, MemoryDb, NodeKind,
+    RefCountedDb, StoredNode, ThreadSafe, WriteBatch, WriteOp,
+};
 pub use error::TreeError;
 pub use node::{Branch, CompactLeaf, ComputedNode, EmptyLeaf, Hasher, Leaf, Node};
-pub use proof::{CompressedProof, Proof};
-pub use tree::{walk_up, CompactMSSMT, EmptyTree, MSSMT};
+pub use proof::{
+    CompactExclusionProof, CompressedProof, MultiProof, Proof, RangeSumProof, TransitionProof,
+    TransitionStep,
+};
+pub use tree::{
+    walk_up, AsyncCompactMSSMT, AsyncMSSMT, CompactMSSMT, DiffEntry, EmptyTree, IntegrityIssue,
+    IntegrityReport, LeafIter, PruneStats, TreeDiff, TreeView, MSSMT,
+};
 #[cfg(test)]
 mod tests;
@@ -38,6 +38,11 @@ pub trait Hasher<const HASH_SIZE: usize>: 'static {
 /// * `HASH_SIZE` - The size of the hash digest in bytes
 /// * `H` - The hasher implementation used for this node
 #[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound = "")
+)]
 pub enum Node<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
     /// A leaf node containing a value and sum
     Leaf(Leaf<HASH_SIZE, H>),
@@ -0,0 +1,203 @@
+//! Short exclusion proofs of compacted trees.
+//!
+//! In a [`CompactMSSMT`](crate::CompactMSSMT), the path of an absent key ends either at an empty
+//! subtree or at a compacted leaf holding another key whose path shares the same bits so far.
+//! A proof that stops there only holds the siblings above that node, and the verifier checks
+//! that the compacted leaf really diverges from the queried key.
+
+use crate::{
+    node::{Branch, CompactLeaf, Hasher, Leaf, Node},
+    tree::bit_index,
+    EmptyTree, TreeError,
+};
+
+/// A proof that no leaf is stored at a key, terminating where the path of the key leaves the
+/// tree.
+#[derive(Debug, Clone)]
+pub struct CompactExclusionProof<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
+    nodes: Vec<Node<HASH_SIZE, H>>,
+    end: Option<([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)>,
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> CompactExclusionProof<HASH_SIZE, H> {
+    /// Creates a new proof from the siblings above the terminal node, from the bottom to the
+    /// root, and the key and leaf of the compacted leaf it terminates at, or `None` if it
+    /// terminates at an empty subtree.
+    pub fn new(
+        nodes: Vec<Node<HASH_SIZE, H>>,
+        end: Option<([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)>,
+    ) -> Self {
+        Self { nodes, end }
+    }
+
+    /// Returns the siblings of the proof, from the bottom to the root.
+    pub fn nodes(&self) -> &[Node<HASH_SIZE, H>] {
+        &self.nodes
+    }
+
+    /// Returns the key and leaf of the compacted leaf the proof terminates at, or `None` if it
+    /// terminates at an empty subtree.
+    pub fn end(&self) -> Option<&([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)> {
+        self.end.as_ref()
+    }
+
+    /// Rebuilds the root of the tree along the path of `key`.
+    ///
+    /// # Returns
+    ///
+    /// Returns [`TreeError::InvalidMerkleProof`] if the proof is longer than the path or if the
+    /// compacted leaf doesn't diverge from `key` below the terminal node.
+    pub fn root<DbError>(
+        &self,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<Node<HASH_SIZE, H>, TreeError<DbError>> {
+        let height = self.nodes.len();
+        if height > HASH_SIZE * 8 {
+            return Err(TreeError::InvalidMerkleProof);
+        }
+        let empty_tree = EmptyTree::<HASH_SIZE, H>::empty_tree();
+        let mut current = match &self.end {
+            None => empty_tree[height].clone(),
+            Some((end_key, leaf)) => {
+                // The compacted leaf must share the path of `key` down to the terminal node,
+                // and hold another key.
+                if end_key == key || (0..height).any(|i| bit_index(i, end_key) != bit_index(i, key))
+                {
+                    return Err(TreeError::InvalidMerkleProof);
+                }
+                Node::Compact(CompactLeaf::new(height, *end_key, leaf.clone(), empty_tree))
+            }
+        };
+        for (i, sibling) in (0..height).rev().zip(&self.nodes) {
+            // A valid tree never overflows.
+            if current.sum().checked_add(sibling.sum()).is_none() {
+                return Err(TreeError::InvalidMerkleProof);
+            }
+            current = if bit_index(i, key) == 0 {
+                Node::Branch(Branch::new(current, sibling.clone()))
+            } else {
+                Node::Branch(Branch::new(sibling.clone(), current))
+            };
+        }
+        Ok(current)
+    }
+
+    /// Verify that no leaf is stored at `key` in the tree whose root hash is `root_hash`.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the proof is valid, otherwise returns an error.
+    pub fn verify<DbError>(
+        &self,
+        key: &[u8; HASH_SIZE],
+        root_hash: [u8; HASH_SIZE],
+    ) -> Result<(), TreeError<DbError>> {
+        if self.root(key)?.hash() == root_hash {
+            Ok(())
+        } else {
+            Err(TreeError::InvalidMerkleProof)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::Sha256;
+
+    use super::CompactExclusionProof;
+    use crate::{CompactMSSMT, Leaf, MemoryDb, TreeError, MSSMT};
+
+    type Trees = (MSSMT<32, Sha256, ()>, CompactMSSMT<32, Sha256, ()>);
+
+    fn trees() -> Trees {
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut compact_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for i in 0..10u8 {
+            let leaf = Leaf::new(vec![i + 1], i as u64);
+            tree.insert(&[i * 2; 32], leaf.clone()).unwrap();
+            compact_tree.insert(&[i * 2; 32], leaf).unwrap();
+        }
+        (tree, compact_tree)
+    }
+
+    #[test]
+    fn test_exclusion_proof() {
+        let (tree, compact_tree) = trees();
+        let root = tree.root().unwrap();
+        // Odd keys diverge from the stored even ones at the first bit, `[32; 32]` shares its
+        // first five bits with the stored `[0; 32]` only.
+        for key in [[1; 32], [3; 32], [0xff; 32], [32; 32]] {
+            let proof = tree.exclusion_proof(&key).unwrap();
+            proof.verify_exclusion::<()>(&key, root.hash()).unwrap();
+            let proof = compact_tree.exclusion_proof(&key).unwrap();
+            proof.verify_exclusion::<()>(&key, root.hash()).unwrap();
+
+            let short_proof = compact_tree.compact_exclusion_proof(&key).unwrap();
+            assert!(short_proof.nodes().len() < 256);
+            short_proof.verify::<()>(&key, root.hash()).unwrap();
+            assert_eq!(
+                short_proof.verify::<()>(&key, [0; 32]).unwrap_err(),
+                TreeError::InvalidMerkleProof
+            );
+        }
+    }
+
+    #[test]
+    fn test_exclusion_proof_present_key() {
+        let (tree, compact_tree) = trees();
+        assert_eq!(
+            tree.exclusion_proof(&[2; 32]).unwrap_err(),
+            TreeError::ExpectedEmptyLeaf
+        );
+        assert_eq!(
+            compact_tree.exclusion_proof(&[2; 32]).unwrap_err(),
+            TreeError::ExpectedEmptyLeaf
+        );
+        assert_eq!(
+            compact_tree.compact_exclusion_proof(&[2; 32]).unwrap_err(),
+            TreeError::ExpectedEmptyLeaf
+        );
+        // An inclusion proof isn't an exclusion proof.
+        let proof = tree.merkle_proof(&[2; 32]).unwrap();
+        assert_eq!(
+            proof
+                .verify_exclusion::<()>(&[2; 32], tree.root().unwrap().hash())
+                .unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+    }
+
+    #[test]
+    fn test_compact_exclusion_proof_invalid() {
+        let (_, compact_tree) = trees();
+        let root = compact_tree.root().unwrap();
+        let key = [32; 32];
+        let proof = compact_tree.compact_exclusion_proof(&key).unwrap();
+        let (end_key, leaf) = proof.end().unwrap().clone();
+        assert_ne!(end_key, key);
+
+        // The queried key can't be the one of the compacted leaf.
+        assert_eq!(
+            proof.verify::<()>(&end_key, root.hash()).unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        let same_key = CompactExclusionProof::new(proof.nodes().to_vec(), Some((key, leaf)));
+        assert_eq!(
+            same_key.verify::<()>(&key, root.hash()).unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        // The compacted leaf must share the path of the queried key.
+        let mut other_key = key;
+        other_key[0] ^= 1;
+        assert_eq!(
+            proof.verify::<()>(&other_key, root.hash()).unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        // A proof can't be longer than the path.
+        let too_long = CompactExclusionProof::new([proof.nodes(); 300].concat(), None);
+        assert_eq!(
+            too_long.verify::<()>(&key, root.hash()).unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+    }
+}
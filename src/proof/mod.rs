@@ -5,15 +5,32 @@
 //! A proof can be compressed into a bitvector.
 //!
 //! A compressed proof can be decompressed into a proof.
+//!
+//! Both can be encoded into bytes, a proof to a fixed size that doesn't need to be decompressed.
+mod exclusion;
+mod multi;
+mod range;
+mod transition;
+
+pub use exclusion::CompactExclusionProof;
+pub use multi::MultiProof;
+pub use range::RangeSumProof;
+pub use transition::{TransitionProof, TransitionStep};
+
 use std::sync::Arc;
 
 use bitvec::order::Lsb0;
 use bitvec::vec::BitVec;
 
-use crate::{walk_up, Branch, ComputedNode, EmptyTree, Hasher, Leaf, Node, TreeError};
+use crate::{walk_up, Branch, ComputedNode, EmptyLeaf, EmptyTree, Hasher, Leaf, Node, TreeError};
 
 /// A merkle proof for a given key.
 #[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound = "")
+)]
 pub struct Proof<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
     nodes: Vec<Node<HASH_SIZE, H>>,
 }
@@ -29,24 +46,28 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Proof<HASH_SIZE, H> {
         &self.nodes
     }
 
-    /// Verifies the proof against a leaf and a key.
-    pub fn root<DbError: std::fmt::Debug>(
+    /// Rebuilds the root of the tree from the leaf at `key`.
+    ///
+    /// # Returns
+    ///
+    /// Returns [`TreeError::InvalidMerkleProof`] if the proof doesn't hold a sibling per level
+    /// or if the sums on the path overflow.
+    pub fn root<DbError>(
         &self,
         key: &[u8; HASH_SIZE],
         leaf: Leaf<HASH_SIZE, H>,
-    ) -> Branch<HASH_SIZE, H> {
-        // This can't fail
-        walk_up::<HASH_SIZE, H, DbError>(
-            key,
-            leaf,
-            &self
-                .nodes
-                .iter()
-                .map(|node| Arc::new(node.clone()))
-                .collect::<Vec<_>>(),
-            |_, _, _, _| {},
-        )
-        .unwrap()
+    ) -> Result<Branch<HASH_SIZE, H>, TreeError<DbError>> {
+        if self.nodes.len() != HASH_SIZE * 8 {
+            return Err(TreeError::InvalidMerkleProof);
+        }
+        let siblings = self
+            .nodes
+            .iter()
+            .map(|node| Arc::new(node.clone()))
+            .collect::<Vec<_>>();
+        // A forged proof can overflow, a tree never does.
+        walk_up::<HASH_SIZE, H, DbError>(key, leaf, &siblings, |_, _, _, _| {})
+            .map_err(|_| TreeError::InvalidMerkleProof)
     }
 
     /// Compresses the proof into a compressed proof.
@@ -65,6 +86,60 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Proof<HASH_SIZE, H> {
         CompressedProof::new(nodes, bits)
     }
 
+    /// Version byte of the [`encode`](Self::encode) format.
+    pub const ENCODING_VERSION: u8 = 0;
+
+    /// Size in bytes of an [`encode`](Self::encode)d proof.
+    pub const ENCODED_LEN: usize = 1 + HASH_SIZE * 8 * (HASH_SIZE + 8);
+
+    /// Encodes the proof into a byte vector of [`ENCODED_LEN`](Self::ENCODED_LEN) bytes.
+    ///
+    /// Unlike a [`CompressedProof`], every sibling is stored so the proof can be verified
+    /// without being decompressed. The encoding is the [`ENCODING_VERSION`](Self::ENCODING_VERSION)
+    /// byte followed by each sibling, from the leaf to the root, as its hash and its sum as a big
+    /// endian `u64`.
+    ///
+    /// # Returns
+    ///
+    /// Returns [`TreeError::InvalidMerkleProof`] if the proof doesn't hold `HASH_SIZE * 8`
+    /// siblings.
+    pub fn encode<DbError>(&self) -> Result<Vec<u8>, TreeError<DbError>> {
+        if self.nodes.len() != HASH_SIZE * 8 {
+            return Err(TreeError::InvalidMerkleProof);
+        }
+        let mut encoded = Vec::with_capacity(Self::ENCODED_LEN);
+        encoded.push(Self::ENCODING_VERSION);
+        for node in self.nodes.iter() {
+            encoded.extend_from_slice(&node.hash());
+            encoded.extend_from_slice(&node.sum().to_be_bytes());
+        }
+        Ok(encoded)
+    }
+
+    /// Decodes a proof encoded with [`encode`](Self::encode). The siblings are
+    /// [`Node::Computed`] nodes.
+    ///
+    /// # Returns
+    ///
+    /// Returns [`TreeError::InvalidProofEncoding`] if `data` isn't
+    /// [`ENCODED_LEN`](Self::ENCODED_LEN) bytes long or if the version is unknown.
+    pub fn decode<DbError>(data: &[u8]) -> Result<Self, TreeError<DbError>> {
+        if data.len() != Self::ENCODED_LEN || data[0] != Self::ENCODING_VERSION {
+            return Err(TreeError::InvalidProofEncoding);
+        }
+        let nodes = data[1..]
+            .chunks_exact(HASH_SIZE + 8)
+            .map(|chunk| {
+                let (hash, sum) = chunk.split_at(HASH_SIZE);
+                Node::Computed(ComputedNode::new(
+                    hash.try_into().expect("chunk holds a hash"),
+                    u64::from_be_bytes(sum.try_into().expect("chunk holds a sum")),
+                ))
+            })
+            .collect();
+        Ok(Self::new(nodes))
+    }
+
     /// Verify a merkle proof for a given key.
     ///
     /// # Arguments
@@ -84,17 +159,7 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Proof<HASH_SIZE, H> {
         root_hash: [u8; HASH_SIZE],
     ) -> Result<(), TreeError<DbError>> {
         // Compute the root from the leaf and the proof
-        let got_root = walk_up(
-            key,
-            leaf,
-            &self
-                .nodes
-                .clone()
-                .into_iter()
-                .map(Arc::new)
-                .collect::<Vec<_>>(),
-            |_, _, _, _| {},
-        )?;
+        let got_root = self.root(key, leaf)?;
         // Check if the computed root matches the expected root
         if got_root.hash() == root_hash {
             Ok(())
@@ -102,10 +167,62 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Proof<HASH_SIZE, H> {
             Err(TreeError::InvalidMerkleProof)
         }
     }
+
+    /// Applies the update of the leaf at `key` from `old_leaf` to `new_leaf` to the tree whose
+    /// root hash is `old_root`, without a database. Inserting and deleting are updates from and
+    /// to an empty leaf.
+    ///
+    /// # Returns
+    ///
+    /// Returns the new root of the tree, [`TreeError::InvalidMerkleProof`] if the proof of
+    /// `old_leaf` doesn't match `old_root` or doesn't hold a sibling per level, or
+    /// [`TreeError::SumOverflow`] if the sum of the new tree overflows.
+    pub fn apply_update<DbError>(
+        &self,
+        key: &[u8; HASH_SIZE],
+        old_leaf: Leaf<HASH_SIZE, H>,
+        new_leaf: Leaf<HASH_SIZE, H>,
+        old_root: [u8; HASH_SIZE],
+    ) -> Result<Branch<HASH_SIZE, H>, TreeError<DbError>> {
+        if self.root(key, old_leaf)?.hash() != old_root {
+            return Err(TreeError::InvalidMerkleProof);
+        }
+        let siblings = self
+            .nodes
+            .iter()
+            .map(|node| Arc::new(node.clone()))
+            .collect::<Vec<_>>();
+        walk_up(key, new_leaf, &siblings, |_, _, _, _| {})
+    }
+
+    /// Verify that no leaf is stored at `key` in the tree whose root hash is `root_hash`, i.e.
+    /// that the proof is the one of an empty leaf.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the proof is valid, otherwise returns an error.
+    pub fn verify_exclusion<DbError>(
+        &self,
+        key: &[u8; HASH_SIZE],
+        root_hash: [u8; HASH_SIZE],
+    ) -> Result<(), TreeError<DbError>> {
+        self.verify_merkle_proof(key, Leaf::Empty(EmptyLeaf::new()), root_hash)
+    }
 }
 
 /// A compressed merkle proof for a given key.
 /// We don't store all the nodes if they are empty.
+///
+/// The encoding is the one of the `mssmt` package of
+/// [taproot-assets](https://github.com/lightninglabs/taproot-assets/tree/main/mssmt), so proofs
+/// can be exchanged with `tapd`:
+///
+/// * the number of non-empty nodes as a big endian `u16`,
+/// * each non-empty node as its hash followed by its sum as a big endian `u64`,
+/// * `HASH_SIZE` bytes holding one bit per level, set if the node at that level is empty.
+///   Bit `i` is bit `i % 8` of byte `i / 8`, starting from the least significant one, and level
+///   `0` is the sibling of the leaf.
+#[derive(Debug, Clone)]
 pub struct CompressedProof<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
     nodes: Vec<Node<HASH_SIZE, H>>,
     bits: BitVec<u8, Lsb0>,
@@ -117,6 +234,16 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> CompressedProof<HASH_
         Self { nodes, bits }
     }
 
+    /// Returns the non-empty nodes of the proof.
+    pub fn nodes(&self) -> &[Node<HASH_SIZE, H>] {
+        &self.nodes
+    }
+
+    /// Returns the bitvector, a set bit meaning that the node at that level is empty.
+    pub fn bits(&self) -> &BitVec<u8, Lsb0> {
+        &self.bits
+    }
+
     /// Decompresses the proof into a proof.
     pub fn decompress<DbError: std::fmt::Debug>(
         &self,
@@ -147,25 +274,48 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> CompressedProof<HASH_
             encoded.extend_from_slice(&node.hash());
             encoded.extend_from_slice(&node.sum().to_be_bytes());
         }
-        encoded.extend_from_slice(self.bits.as_raw_slice());
+        // The bitvector is always encoded on `HASH_SIZE` bytes.
+        let mut bits = [0u8; HASH_SIZE];
+        for (i, bit) in self.bits.iter().by_vals().take(HASH_SIZE * 8).enumerate() {
+            if bit {
+                bits[i / 8] |= 1 << (i % 8);
+            }
+        }
+        encoded.extend_from_slice(&bits);
         encoded
     }
 
     /// Decodes a proof from a byte vector.
-    pub fn decode(data: &[u8]) -> Self {
-        let nb_nodes = u16::from_be_bytes(data[0..2].try_into().unwrap());
-        let mut nodes = Vec::new();
-        let mut data_index = 2;
+    ///
+    /// # Returns
+    ///
+    /// Returns [`TreeError::InvalidProofEncoding`] if `data` is truncated, holds trailing bytes,
+    /// or if the bitmap doesn't hold `HASH_SIZE * 8` bits with a cleared bit for each node.
+    pub fn decode<DbError>(data: &[u8]) -> Result<Self, TreeError<DbError>> {
+        let mut data = data;
+        let mut read = |len: usize| -> Result<&[u8], TreeError<DbError>> {
+            if data.len() < len {
+                return Err(TreeError::InvalidProofEncoding);
+            }
+            let (bytes, rest) = data.split_at(len);
+            data = rest;
+            Ok(bytes)
+        };
+        let nb_nodes = u16::from_be_bytes(read(2)?.try_into().expect("2 bytes were read"));
+        let mut nodes = Vec::with_capacity(nb_nodes as usize);
         for _ in 0..nb_nodes {
-            let mut hash = [0u8; HASH_SIZE];
-            hash.copy_from_slice(&data[data_index..data_index + HASH_SIZE]);
-            data_index += HASH_SIZE;
-            let sum = u64::from_be_bytes(data[data_index..data_index + 8].try_into().unwrap());
-            data_index += 8;
+            let hash = read(HASH_SIZE)?
+                .try_into()
+                .expect("HASH_SIZE bytes were read");
+            let sum = u64::from_be_bytes(read(8)?.try_into().expect("8 bytes were read"));
             nodes.push(Node::Computed(ComputedNode::new(hash, sum)));
         }
-        let bits = BitVec::<u8, Lsb0>::from_slice(&data[data_index..]);
-        Self::new(nodes, bits)
+        // One bit per level of the tree.
+        let bits = BitVec::<u8, Lsb0>::from_slice(read(HASH_SIZE)?);
+        if !data.is_empty() || bits.count_zeros() != nodes.len() {
+            return Err(TreeError::InvalidProofEncoding);
+        }
+        Ok(Self::new(nodes, bits))
     }
 }
 
@@ -195,6 +345,41 @@ mod tests {
             });
     }
 
+    #[test]
+    fn test_proof_root() {
+        let db = Box::new(MemoryDb::<32, Sha256>::new());
+        let mut mssmt = CompactMSSMT::<32, Sha256, ()>::new(db);
+        let leaf = Leaf::new(vec![1], 1);
+        mssmt.insert(&[1; 32], leaf.clone()).unwrap();
+        let proof = mssmt.merkle_proof(&[1; 32]).unwrap();
+        let root = proof.root::<()>(&[1; 32], leaf.clone()).unwrap();
+        assert_eq!(root.hash(), mssmt.root().unwrap().hash());
+
+        // Missing sibling.
+        let mut nodes = proof.nodes().to_vec();
+        nodes.pop();
+        let short = Proof::<32, Sha256>::new(nodes);
+        assert_eq!(
+            short.root::<()>(&[1; 32], leaf.clone()).unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        assert_eq!(
+            short
+                .verify_merkle_proof::<()>(&[1; 32], leaf.clone(), root.hash())
+                .unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        // Forged sibling overflowing the sums.
+        let mut nodes = proof.nodes().to_vec();
+        nodes[0] = Node::Computed(ComputedNode::new([0; 32], u64::MAX));
+        assert_eq!(
+            Proof::<32, Sha256>::new(nodes)
+                .root::<()>(&[1; 32], leaf)
+                .unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+    }
+
     #[test]
     fn test_compressed_proof_encode_decode() {
         let db = Box::new(MemoryDb::<32, Sha256>::new());
@@ -203,7 +388,7 @@ mod tests {
         let proof = mssmt.merkle_proof(&[0; 32]).unwrap();
         let compressed = proof.compress();
         let encoded = compressed.encode();
-        let decoded = CompressedProof::<32, Sha256>::decode(&encoded);
+        let decoded = CompressedProof::<32, Sha256>::decode::<()>(&encoded).unwrap();
         compressed
             .nodes
             .iter()
@@ -214,11 +399,200 @@ mod tests {
         assert_eq!(compressed.bits, decoded.bits);
     }
 
+    #[test]
+    fn test_proof_encode_decode() {
+        let db = Box::new(MemoryDb::<32, Sha256>::new());
+        let mut mssmt = CompactMSSMT::<32, Sha256, ()>::new(db);
+        mssmt.insert(&[1; 32], Leaf::new(vec![1], 1)).unwrap();
+        mssmt.insert(&[2; 32], Leaf::new(vec![2], 2)).unwrap();
+        let root = mssmt.root().unwrap();
+        let proof = mssmt.merkle_proof(&[1; 32]).unwrap();
+        let encoded = proof.encode::<()>().unwrap();
+        assert_eq!(encoded.len(), Proof::<32, Sha256>::ENCODED_LEN);
+        assert_eq!(encoded.len(), 1 + 256 * 40);
+
+        let decoded = Proof::<32, Sha256>::decode::<()>(&encoded).unwrap();
+        assert_eq!(decoded.nodes.len(), 256);
+        for (node, expected) in decoded.nodes.iter().zip(&proof.nodes) {
+            assert!(matches!(node, Node::Computed(_)));
+            assert_eq!(node.hash(), expected.hash());
+            assert_eq!(node.sum(), expected.sum());
+        }
+        decoded
+            .verify_merkle_proof::<()>(&[1; 32], Leaf::new(vec![1], 1), root.hash())
+            .unwrap();
+        assert_eq!(decoded.encode::<()>().unwrap(), encoded);
+        // A proof that doesn't hold a sibling per level can't be encoded.
+        assert_eq!(
+            Proof::new(proof.nodes[1..].to_vec()).encode::<()>(),
+            Err(TreeError::InvalidMerkleProof)
+        );
+    }
+
+    #[test]
+    fn test_proof_decode_invalid() {
+        let db = Box::new(MemoryDb::<32, Sha256>::new());
+        let mut mssmt = CompactMSSMT::<32, Sha256, ()>::new(db);
+        mssmt.insert(&[1; 32], Leaf::new(vec![1], 1)).unwrap();
+        let encoded = mssmt
+            .merkle_proof(&[1; 32])
+            .unwrap()
+            .encode::<()>()
+            .unwrap();
+
+        assert_eq!(
+            Proof::<32, Sha256>::decode::<()>(&[]).unwrap_err(),
+            TreeError::InvalidProofEncoding
+        );
+        assert_eq!(
+            Proof::<32, Sha256>::decode::<()>(&encoded[..encoded.len() - 1]).unwrap_err(),
+            TreeError::InvalidProofEncoding
+        );
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert_eq!(
+            Proof::<32, Sha256>::decode::<()>(&trailing).unwrap_err(),
+            TreeError::InvalidProofEncoding
+        );
+        let mut version = encoded;
+        version[0] = 1;
+        assert_eq!(
+            Proof::<32, Sha256>::decode::<()>(&version).unwrap_err(),
+            TreeError::InvalidProofEncoding
+        );
+    }
+
+    #[test]
+    fn test_apply_update() {
+        let db = Box::new(MemoryDb::<32, Sha256>::new());
+        let mut mssmt = CompactMSSMT::<32, Sha256, ()>::new(db);
+        for i in 0..5u8 {
+            mssmt
+                .insert(&[i; 32], Leaf::new(vec![i], i as u64 + 1))
+                .unwrap();
+        }
+        let updates = [
+            // Insertion.
+            ([7; 32], Leaf::new(vec![7], 7)),
+            // Replacement.
+            ([2; 32], Leaf::new(vec![9], 9)),
+            // Deletion.
+            ([3; 32], Leaf::Empty(EmptyLeaf::new())),
+        ];
+        for (key, new_leaf) in updates {
+            let old_root = mssmt.root().unwrap();
+            let (old_leaf, proof) = mssmt.get_with_proof(&key).unwrap();
+            let root = proof
+                .apply_update::<()>(&key, old_leaf, new_leaf.clone(), old_root.hash())
+                .unwrap();
+            mssmt.insert(&key, new_leaf).unwrap();
+            let expected = mssmt.root().unwrap();
+            assert_eq!(root.hash(), expected.hash());
+            assert_eq!(root.sum(), expected.sum());
+        }
+
+        // The old leaf must match the old root.
+        let root = mssmt.root().unwrap();
+        let proof = mssmt.merkle_proof(&[1; 32]).unwrap();
+        assert_eq!(
+            proof
+                .apply_update::<()>(
+                    &[1; 32],
+                    Leaf::new(vec![0], 2),
+                    Leaf::new(vec![1], 3),
+                    root.hash()
+                )
+                .unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        // The new tree can't overflow.
+        assert_eq!(
+            proof
+                .apply_update::<()>(
+                    &[1; 32],
+                    Leaf::new(vec![1], 2),
+                    Leaf::new(vec![1], u64::MAX),
+                    root.hash()
+                )
+                .unwrap_err(),
+            TreeError::SumOverflow
+        );
+        // A forged sibling can't overflow the old tree.
+        let mut nodes = proof.nodes().to_vec();
+        nodes[0] = Node::Computed(ComputedNode::new([0; 32], u64::MAX));
+        let forged = Proof::new(nodes);
+        assert_eq!(
+            forged
+                .apply_update::<()>(
+                    &[1; 32],
+                    Leaf::new(vec![1], 2),
+                    Leaf::new(vec![1], 3),
+                    root.hash()
+                )
+                .unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        // Nor can a proof without a sibling per level.
+        assert_eq!(
+            Proof::new(proof.nodes()[1..].to_vec())
+                .apply_update::<()>(
+                    &[1; 32],
+                    Leaf::new(vec![1], 2),
+                    Leaf::new(vec![1], 3),
+                    root.hash()
+                )
+                .unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+    }
+
+    #[test]
+    fn test_compressed_proof_decode_invalid() {
+        let db = Box::new(MemoryDb::<32, Sha256>::new());
+        let mut mssmt = CompactMSSMT::<32, Sha256, ()>::new(db);
+        mssmt.insert(&[1; 32], Leaf::new(vec![1], 1)).unwrap();
+        let encoded = mssmt.merkle_proof(&[0; 32]).unwrap().compress().encode();
+        assert!(CompressedProof::<32, Sha256>::decode::<()>(&encoded).is_ok());
+
+        // Truncated input, down to an empty one.
+        for len in 0..encoded.len() {
+            assert_eq!(
+                CompressedProof::<32, Sha256>::decode::<()>(&encoded[..len]).unwrap_err(),
+                TreeError::InvalidProofEncoding
+            );
+        }
+        // Trailing bytes.
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert_eq!(
+            CompressedProof::<32, Sha256>::decode::<()>(&trailing).unwrap_err(),
+            TreeError::InvalidProofEncoding
+        );
+        // Bitmap with a cleared bit without a matching node.
+        let mut bits = encoded.clone();
+        *bits.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            CompressedProof::<32, Sha256>::decode::<()>(&bits).unwrap_err(),
+            TreeError::InvalidProofEncoding
+        );
+        // More nodes announced than the input holds.
+        let mut count = encoded;
+        count[1] += 1;
+        assert_eq!(
+            CompressedProof::<32, Sha256>::decode::<()>(&count).unwrap_err(),
+            TreeError::InvalidProofEncoding
+        );
+    }
+
     #[test]
     fn decode_proof() {
         let proof = "000d5e33603b6fc04e71c5bb9037922c3b82dbe97fee8bf7ad1141e63d9be1e37f070000000115ff8f1aa2fb6e0a9a429d7ad2943f8d6f5c5aac52a5ac97ba34b08467e064fc42270a9c000000001f94b81669aeea06116829ae6c1bb088352980bfe670e97d3e1881936eab07ebd444e264000000004b8d77876bfbdbb3df1d985bb274e56f5f24dc4f5a8c9cfdf66d42a167098169aeb645b70000000534ecae32445ab27a6948995c9bbb4c90ba726914712e3e5e617aa1b6155571b46eacad9900000006389b57888d1a6d1e0e49bd475c99e33d0d76e6c632da6ebb9b4cf69fafa10cd54e7444ed000000108dc8a6e26005098a57041edb8a8ab7efb312be0219e8c82222982a3ad8d1cfb99efedc210000002b03f9d45cebe1f6f2c431b8aa7ea4c3e00308f5b3e72d03ebee85dcf97f6072969e8b6e3a0000005674538dbbfa554ed4ab986d77966d9bde88df9f176cb1b50b18d333c0cdc37e97134619e60000009368c72d0e686b8b50812d592e3e7986fdeca248dc99860a13b1ee2b4b12539817323a17cf0000013f2625fb388690fd5fdde3653af7cfe50e4f7e4bad565cee682cfc42b7f28a00e5585247cd0000027b6f28ec879fd492ae93e3f9d558656b6523212974325b43555f39687f1603268aead6e549000004df05d54f97dc71216a7e5193f8ae3ee589b7f8941b91a5e6b617563e68a0835180dfff2224000009b96f048feeffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff0700";
-        let compressed = CompressedProof::<32, Sha256>::decode(&hex::decode(proof).unwrap());
+        let bytes = hex::decode(proof).unwrap();
+        let compressed = CompressedProof::<32, Sha256>::decode::<()>(&bytes).unwrap();
+        // Byte for byte round trip of a proof encoded by taproot-assets.
+        assert_eq!(compressed.encode(), bytes);
         let decompressed = compressed.decompress::<()>().unwrap();
+        assert_eq!(decompressed.compress().encode(), bytes);
         decompressed
             .nodes
             .iter()
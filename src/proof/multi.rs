@@ -0,0 +1,317 @@
+//! Merkle proofs of several keys.
+//!
+//! A multi proof holds the siblings of the union of the paths of its keys: subtrees holding
+//! several keys are rebuilt from them instead of being stored, so the siblings near the root
+//! are only stored once. Siblings are ordered by a depth-first left to right descent of the
+//! tree and, like in a [`CompressedProof`](crate::CompressedProof), empty subtrees are stored
+//! as a single bit.
+
+use bitvec::{order::Lsb0, vec::BitVec};
+
+use crate::{
+    node::{Branch, ComputedNode, Hasher, Leaf, Node},
+    tree::{bit_index, path_cmp},
+    EmptyTree, TreeError,
+};
+
+/// A merkle proof for a set of keys.
+#[derive(Debug, Clone)]
+pub struct MultiProof<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
+    nodes: Vec<Node<HASH_SIZE, H>>,
+    bits: BitVec<u8, Lsb0>,
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> MultiProof<HASH_SIZE, H> {
+    /// Creates a new multi proof from the non-empty siblings and a bitvector holding a bit per
+    /// sibling, set if it's an empty subtree.
+    pub fn new(nodes: Vec<Node<HASH_SIZE, H>>, bits: BitVec<u8, Lsb0>) -> Self {
+        Self { nodes, bits }
+    }
+
+    /// Returns the non-empty siblings of the proof.
+    pub fn nodes(&self) -> &[Node<HASH_SIZE, H>] {
+        &self.nodes
+    }
+
+    /// Returns the bitvector, a set bit meaning that the sibling is an empty subtree.
+    pub fn bits(&self) -> &BitVec<u8, Lsb0> {
+        &self.bits
+    }
+
+    /// Rebuilds the root of the tree from the proof and the leaves of its keys.
+    ///
+    /// # Returns
+    ///
+    /// Returns the root of the tree, or [`TreeError::InvalidMerkleProof`] if the proof doesn't
+    /// match the keys of `leaves` or if a key is given twice.
+    pub fn root<DbError>(
+        &self,
+        leaves: &[([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)],
+    ) -> Result<ComputedNode<HASH_SIZE>, TreeError<DbError>> {
+        let mut leaves = leaves.to_vec();
+        leaves.sort_by(|(a, _), (b, _)| path_cmp(a, b));
+        if leaves.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return Err(TreeError::InvalidMerkleProof);
+        }
+        let empty_tree = EmptyTree::<HASH_SIZE, H>::empty_tree();
+        let mut siblings = self.bits.iter().by_vals();
+        let mut nodes = self.nodes.iter();
+        let mut next_sibling = |height: usize| match siblings.next() {
+            Some(true) => Ok(empty_tree[height].clone()),
+            Some(false) => nodes.next().cloned().ok_or(TreeError::InvalidMerkleProof),
+            None => Err(TreeError::InvalidMerkleProof),
+        };
+        let root = rebuild(0, &leaves, &mut next_sibling)?;
+        // All the siblings must have been used.
+        if siblings.next().is_some() || nodes.next().is_some() {
+            return Err(TreeError::InvalidMerkleProof);
+        }
+        Ok(ComputedNode::new(root.hash(), root.sum()))
+    }
+
+    /// Verify that `leaves` are stored at their keys in the tree whose root hash is
+    /// `root_hash`.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the proof is valid, otherwise returns an error.
+    pub fn verify<DbError>(
+        &self,
+        leaves: &[([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)],
+        root_hash: [u8; HASH_SIZE],
+    ) -> Result<(), TreeError<DbError>> {
+        if self.root(leaves)?.hash() == root_hash {
+            Ok(())
+        } else {
+            Err(TreeError::InvalidMerkleProof)
+        }
+    }
+
+    /// Encodes the proof into a byte vector.
+    ///
+    /// The encoding is the number of siblings as a big endian `u32`, the bitvector padded to a
+    /// whole number of bytes, then each non-empty sibling as its hash followed by its sum as a
+    /// big endian `u64`.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        encoded.extend_from_slice(&(self.bits.len() as u32).to_be_bytes());
+        let mut bits = vec![0u8; self.bits.len().div_ceil(8)];
+        for (i, bit) in self.bits.iter().by_vals().enumerate() {
+            if bit {
+                bits[i / 8] |= 1 << (i % 8);
+            }
+        }
+        encoded.extend_from_slice(&bits);
+        for node in self.nodes.iter() {
+            encoded.extend_from_slice(&node.hash());
+            encoded.extend_from_slice(&node.sum().to_be_bytes());
+        }
+        encoded
+    }
+
+    /// Decodes a proof encoded with [`encode`](Self::encode).
+    ///
+    /// # Returns
+    ///
+    /// Returns [`TreeError::InvalidProofEncoding`] if `data` is truncated, holds trailing bytes
+    /// or padding bits, or if the number of nodes doesn't match the bitvector.
+    pub fn decode<DbError>(data: &[u8]) -> Result<Self, TreeError<DbError>> {
+        let Some((count, data)) = data.split_first_chunk::<4>() else {
+            return Err(TreeError::InvalidProofEncoding);
+        };
+        let count = u32::from_be_bytes(*count) as usize;
+        if data.len() < count.div_ceil(8) {
+            return Err(TreeError::InvalidProofEncoding);
+        }
+        let (bits, data) = data.split_at(count.div_ceil(8));
+        let mut bits = BitVec::<u8, Lsb0>::from_slice(bits);
+        // The padding bits must be cleared.
+        if bits[count..].any() {
+            return Err(TreeError::InvalidProofEncoding);
+        }
+        bits.truncate(count);
+        if data.len() != bits.count_zeros() * (HASH_SIZE + 8) {
+            return Err(TreeError::InvalidProofEncoding);
+        }
+        let nodes = data
+            .chunks_exact(HASH_SIZE + 8)
+            .map(|chunk| {
+                let (hash, sum) = chunk.split_at(HASH_SIZE);
+                Node::Computed(ComputedNode::new(
+                    hash.try_into().expect("chunk holds a hash"),
+                    u64::from_be_bytes(sum.try_into().expect("chunk holds a sum")),
+                ))
+            })
+            .collect();
+        Ok(Self::new(nodes, bits))
+    }
+}
+
+/// Rebuilds the subtree at `height` holding the path-sorted `leaves`, taking the subtrees
+/// without any leaf from `next_sibling`.
+#[allow(clippy::type_complexity)]
+fn rebuild<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
+    height: usize,
+    leaves: &[([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)],
+    next_sibling: &mut impl FnMut(usize) -> Result<Node<HASH_SIZE, H>, TreeError<DbError>>,
+) -> Result<Node<HASH_SIZE, H>, TreeError<DbError>> {
+    match leaves {
+        [] => next_sibling(height),
+        [(_, leaf)] if height == HASH_SIZE * 8 => Ok(Node::Leaf(leaf.clone())),
+        _ => {
+            let (left, right) =
+                leaves.split_at(leaves.partition_point(|(key, _)| bit_index(height, key) == 0));
+            let left = rebuild(height + 1, left, next_sibling)?;
+            let right = rebuild(height + 1, right, next_sibling)?;
+            // A valid tree never overflows.
+            if left.sum().checked_add(right.sum()).is_none() {
+                return Err(TreeError::InvalidMerkleProof);
+            }
+            Ok(Node::Branch(Branch::new(left, right)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::Sha256;
+
+    use super::MultiProof;
+    use crate::{CompactMSSMT, EmptyLeaf, Leaf, MemoryDb, TreeError, MSSMT};
+
+    #[allow(clippy::type_complexity)]
+    fn leaves() -> Vec<([u8; 32], Leaf<32, Sha256>)> {
+        (0..50u8)
+            .map(|i| ([i.wrapping_mul(37); 32], Leaf::new(vec![i + 1], i as u64)))
+            .collect()
+    }
+
+    #[test]
+    fn test_multi_proof() {
+        let leaves = leaves();
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut compact_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for (key, leaf) in &leaves {
+            tree.insert(key, leaf.clone()).unwrap();
+            compact_tree.insert(key, leaf.clone()).unwrap();
+        }
+        let root = tree.root().unwrap();
+
+        // Some of the keys, an absent one and a duplicate.
+        let mut proven = leaves[..20].to_vec();
+        proven.push(([0xaa; 32], Leaf::Empty(EmptyLeaf::new())));
+        let mut keys = proven.iter().map(|(key, _)| *key).collect::<Vec<_>>();
+        keys.push(keys[3]);
+
+        let proof = tree.multi_proof(&keys).unwrap();
+        let compact_proof = compact_tree.multi_proof(&keys).unwrap();
+        assert_eq!(proof.bits(), compact_proof.bits());
+        assert_eq!(proof.nodes().len(), compact_proof.nodes().len());
+        for (node, compact_node) in proof.nodes().iter().zip(compact_proof.nodes()) {
+            assert_eq!(node.hash(), compact_node.hash());
+        }
+        proof.verify::<()>(&proven, root.hash()).unwrap();
+        compact_proof.verify::<()>(&proven, root.hash()).unwrap();
+        let got = proof.root::<()>(&proven).unwrap();
+        assert_eq!(got.hash(), root.hash());
+        assert_eq!(got.sum(), root.sum());
+
+        // Siblings are shared: far less than one proof per key.
+        assert!(proof.bits().len() < proven.len() * 256);
+        assert!(proof.nodes().len() < proven.len() * 8);
+
+        let decoded = MultiProof::<32, Sha256>::decode::<()>(&proof.encode()).unwrap();
+        assert_eq!(decoded.bits(), proof.bits());
+        decoded.verify::<()>(&proven, root.hash()).unwrap();
+    }
+
+    #[test]
+    fn test_multi_proof_single_key() {
+        let leaves = leaves();
+        let mut tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for (key, leaf) in &leaves {
+            tree.insert(key, leaf.clone()).unwrap();
+        }
+        let root = tree.root().unwrap();
+        let (key, leaf) = leaves[7].clone();
+        let proof = tree.multi_proof(&[key]).unwrap();
+        // A single key needs the same siblings as a regular proof.
+        let regular = tree.merkle_proof(&key).unwrap().compress();
+        assert_eq!(proof.bits().len(), 256);
+        assert_eq!(proof.nodes().len(), regular.nodes().len());
+        proof.verify::<()>(&[(key, leaf)], root.hash()).unwrap();
+
+        // Without keys the proof is the root.
+        let proof = tree.multi_proof(&[]).unwrap();
+        assert_eq!(proof.root::<()>(&[]).unwrap().hash(), root.hash());
+    }
+
+    #[test]
+    fn test_multi_proof_invalid() {
+        let leaves = leaves();
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for (key, leaf) in &leaves {
+            tree.insert(key, leaf.clone()).unwrap();
+        }
+        let root = tree.root().unwrap();
+        let proven = leaves[..10].to_vec();
+        let keys = proven.iter().map(|(key, _)| *key).collect::<Vec<_>>();
+        let proof = tree.multi_proof(&keys).unwrap();
+
+        // Wrong leaf.
+        let mut wrong = proven.clone();
+        wrong[2].1 = Leaf::new(vec![42], 2);
+        assert_eq!(
+            proof.verify::<()>(&wrong, root.hash()).unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        // Missing key.
+        assert_eq!(
+            proof.verify::<()>(&proven[1..], root.hash()).unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        // Duplicated key.
+        let mut duplicated = proven.clone();
+        duplicated.push(proven[0].clone());
+        assert_eq!(
+            proof.verify::<()>(&duplicated, root.hash()).unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        // Wrong root.
+        assert_eq!(
+            proof.verify::<()>(&proven, [0; 32]).unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+    }
+
+    #[test]
+    fn test_multi_proof_decode_invalid() {
+        let leaves = leaves();
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for (key, leaf) in &leaves {
+            tree.insert(key, leaf.clone()).unwrap();
+        }
+        let proof = tree.multi_proof(&[leaves[0].0, leaves[1].0]).unwrap();
+        let encoded = proof.encode();
+
+        for len in 0..encoded.len() {
+            assert_eq!(
+                MultiProof::<32, Sha256>::decode::<()>(&encoded[..len]).unwrap_err(),
+                TreeError::InvalidProofEncoding
+            );
+        }
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert_eq!(
+            MultiProof::<32, Sha256>::decode::<()>(&trailing).unwrap_err(),
+            TreeError::InvalidProofEncoding
+        );
+        // Wrong number of siblings.
+        let mut count = encoded;
+        count[3] -= 1;
+        assert_eq!(
+            MultiProof::<32, Sha256>::decode::<()>(&count).unwrap_err(),
+            TreeError::InvalidProofEncoding
+        );
+    }
+}
@@ -0,0 +1,280 @@
+//! Range sum proofs.
+//!
+//! A range sum proof shows that the leaves whose keys are in `[start, end)` sum to a given
+//! value without revealing them. Keys are compared in lexicographic order, like the ranges of
+//! [`MSSMT::range`](crate::MSSMT::range).
+//!
+//! The proof holds the boundary nodes of the descent from the root: every subtree that is
+//! fully inside or fully outside of the range is given by its hash and sum, and subtrees that
+//! partially overlap the range are split in two, unless they are empty. The path of a key
+//! starts with the least significant bit of its first byte, so at the `j`-th level of a byte
+//! up to `2^j` subtrees sharing the prefix of each bound are split, but only the ones holding
+//! a leaf. Each split adds one node, so for a tree of `n` leaves a proof holds at most
+//! `1 + HASH_SIZE * min(8 * n, 510)` nodes, and a single node for an empty tree.
+
+use std::{iter::Peekable, slice::Iter};
+
+use crate::{
+    node::{Branch, ComputedNode, Hasher, Node, Sum},
+    tree::range::{overlap, Overlap},
+    EmptyTree, TreeError,
+};
+
+/// A proof of the sum of the leaves in a key range.
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound = "")
+)]
+pub struct RangeSumProof<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
+    nodes: Vec<Node<HASH_SIZE, H>>,
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> RangeSumProof<HASH_SIZE, H> {
+    /// Creates a new proof from the boundary nodes, in the order of a depth-first left to right
+    /// descent of the tree.
+    pub fn new(nodes: Vec<Node<HASH_SIZE, H>>) -> Self {
+        Self { nodes }
+    }
+
+    /// Returns the boundary nodes of the proof.
+    pub fn nodes(&self) -> &[Node<HASH_SIZE, H>] {
+        &self.nodes
+    }
+
+    /// Rebuilds the root of the tree from the proof.
+    ///
+    /// # Returns
+    ///
+    /// Returns the root of the tree and the sum of the leaves in `[start, end)`, or
+    /// [`TreeError::InvalidMerkleProof`] if the proof doesn't match the range.
+    pub fn root<DbError>(
+        &self,
+        start: &[u8; HASH_SIZE],
+        end: &[u8; HASH_SIZE],
+    ) -> Result<(ComputedNode<HASH_SIZE>, Sum), TreeError<DbError>> {
+        let empty_tree = EmptyTree::<HASH_SIZE, H>::empty_tree();
+        let mut nodes = self.nodes.iter().peekable();
+        let (root, sum) = self.rebuild(&empty_tree, 0, [0; HASH_SIZE], start, end, &mut nodes)?;
+        // All the nodes must have been used.
+        if nodes.next().is_some() {
+            return Err(TreeError::InvalidMerkleProof);
+        }
+        Ok((ComputedNode::new(root.hash(), root.sum()), sum))
+    }
+
+    /// Verify that the leaves in `[start, end)` sum to `sum` in the tree whose root hash is
+    /// `root_hash`.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the proof is valid, otherwise returns an error.
+    pub fn verify<DbError>(
+        &self,
+        start: &[u8; HASH_SIZE],
+        end: &[u8; HASH_SIZE],
+        sum: Sum,
+        root_hash: [u8; HASH_SIZE],
+    ) -> Result<(), TreeError<DbError>> {
+        let (root, got_sum) = self.root(start, end)?;
+        if root.hash() == root_hash && got_sum == sum {
+            Ok(())
+        } else {
+            Err(TreeError::InvalidMerkleProof)
+        }
+    }
+
+    /// Replays the descent of the prover from the subtree at `height` whose path is `key`.
+    /// Returns the subtree root and the sum of its leaves in the range.
+    fn rebuild<DbError>(
+        &self,
+        empty_tree: &[Node<HASH_SIZE, H>],
+        height: usize,
+        key: [u8; HASH_SIZE],
+        start: &[u8; HASH_SIZE],
+        end: &[u8; HASH_SIZE],
+        nodes: &mut Peekable<Iter<'_, Node<HASH_SIZE, H>>>,
+    ) -> Result<(Node<HASH_SIZE, H>, Sum), TreeError<DbError>> {
+        match overlap(height, &key, start, end) {
+            Overlap::Outside => {
+                let node = nodes.next().ok_or(TreeError::InvalidMerkleProof)?;
+                Ok((node.clone(), 0))
+            }
+            Overlap::Inside => {
+                let node = nodes.next().ok_or(TreeError::InvalidMerkleProof)?;
+                Ok((node.clone(), node.sum()))
+            }
+            // An empty subtree isn't split and holds no leaf in the range.
+            Overlap::Partial
+                if nodes
+                    .peek()
+                    .is_some_and(|node| node.hash() == empty_tree[height].hash()) =>
+            {
+                let node = nodes.next().expect("peeked");
+                Ok((node.clone(), 0))
+            }
+            Overlap::Partial => {
+                let mut right_key = key;
+                right_key[height / 8] |= 1 << (height % 8);
+                let (left, left_sum) =
+                    self.rebuild(empty_tree, height + 1, key, start, end, nodes)?;
+                let (right, right_sum) =
+                    self.rebuild(empty_tree, height + 1, right_key, start, end, nodes)?;
+                // A valid tree never overflows.
+                if left.sum().checked_add(right.sum()).is_none() {
+                    return Err(TreeError::InvalidMerkleProof);
+                }
+                let sum = left_sum
+                    .checked_add(right_sum)
+                    .ok_or(TreeError::InvalidMerkleProof)?;
+                Ok((Node::Branch(Branch::new(left, right)), sum))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::Sha256;
+
+    use super::RangeSumProof;
+    use crate::{CompactMSSMT, Leaf, MemoryDb, TreeError, MSSMT};
+
+    type Trees = (
+        Vec<([u8; 32], Leaf<32, Sha256>)>,
+        MSSMT<32, Sha256, ()>,
+        CompactMSSMT<32, Sha256, ()>,
+    );
+
+    /// Returns the leaves sorted by key and the trees holding them.
+    fn trees() -> Trees {
+        let mut leaves = (0..20u8)
+            .map(|i| ([i.wrapping_mul(37); 32], Leaf::new(vec![i + 1], i as u64)))
+            .collect::<Vec<_>>();
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut compact_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for (key, leaf) in &leaves {
+            tree.insert(key, leaf.clone()).unwrap();
+            compact_tree.insert(key, leaf.clone()).unwrap();
+        }
+        leaves.sort_by_key(|(key, _)| *key);
+        (leaves, tree, compact_tree)
+    }
+
+    #[test]
+    fn test_range_sum_proof() {
+        let (leaves, tree, compact_tree) = trees();
+        let root = tree.root().unwrap();
+        for (start, end) in [(0, 20), (3, 11), (5, 6), (7, 7), (12, 20)] {
+            let start_key = leaves[start].0;
+            let end_key = leaves.get(end).map(|(key, _)| *key).unwrap_or([0xff; 32]);
+            let sum = leaves[start..end]
+                .iter()
+                .map(|(_, leaf)| leaf.sum())
+                .sum::<u64>();
+
+            let proof = tree.range_sum_proof(&start_key, &end_key).unwrap();
+            let compact_proof = compact_tree.range_sum_proof(&start_key, &end_key).unwrap();
+            assert!(proof.nodes().len() <= 1 + 32 * (8 * leaves.len()).min(510));
+            assert_eq!(proof.nodes().len(), compact_proof.nodes().len());
+            for (node, compact_node) in proof.nodes().iter().zip(compact_proof.nodes()) {
+                assert_eq!(node.hash(), compact_node.hash());
+            }
+            proof
+                .verify::<()>(&start_key, &end_key, sum, root.hash())
+                .unwrap();
+            compact_proof
+                .verify::<()>(&start_key, &end_key, sum, root.hash())
+                .unwrap();
+            let (got_root, got_sum) = proof.root::<()>(&start_key, &end_key).unwrap();
+            assert_eq!(got_root.hash(), root.hash());
+            assert_eq!(got_root.sum(), root.sum());
+            assert_eq!(got_sum, sum);
+        }
+    }
+
+    #[test]
+    fn test_range_sum_proof_empty_range() {
+        let (_, tree, _) = trees();
+        let proof = tree.range_sum_proof(&[0xff; 32], &[0; 32]).unwrap();
+        assert_eq!(proof.nodes().len(), 1);
+        proof
+            .verify::<()>(&[0xff; 32], &[0; 32], 0, tree.root().unwrap().hash())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_range_sum_proof_empty_tree() {
+        let (start, end) = ([0x40; 32], [0xc0; 32]);
+        let tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let compact_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let root = tree.root().unwrap();
+        for proof in [
+            tree.range_sum_proof(&start, &end).unwrap(),
+            compact_tree.range_sum_proof(&start, &end).unwrap(),
+        ] {
+            assert_eq!(proof.nodes().len(), 1);
+            proof.verify::<()>(&start, &end, 0, root.hash()).unwrap();
+        }
+
+        // A single leaf splits one subtree per level.
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        tree.insert(&[0x80; 32], Leaf::new(vec![1], 5)).unwrap();
+        let proof = tree.range_sum_proof(&start, &end).unwrap();
+        assert!(proof.nodes().len() <= 1 + 256);
+        proof
+            .verify::<()>(&start, &end, 5, tree.root().unwrap().hash())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_range_sum_proof_invalid() {
+        let (leaves, tree, _) = trees();
+        let root = tree.root().unwrap();
+        let (start, end) = (leaves[3].0, leaves[11].0);
+        let sum = leaves[3..11]
+            .iter()
+            .map(|(_, leaf)| leaf.sum())
+            .sum::<u64>();
+        let proof = tree.range_sum_proof(&start, &end).unwrap();
+
+        // Wrong sum.
+        assert_eq!(
+            proof
+                .verify::<()>(&start, &end, sum + 1, root.hash())
+                .unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        // Wrong range.
+        assert_eq!(
+            proof
+                .verify::<()>(&start, &leaves[12].0, sum, root.hash())
+                .unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        // Wrong root.
+        assert_eq!(
+            proof.verify::<()>(&start, &end, sum, [0; 32]).unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        // Missing node.
+        let mut nodes = proof.nodes().to_vec();
+        let last = nodes.pop().unwrap();
+        assert_eq!(
+            RangeSumProof::new(nodes.clone())
+                .verify::<()>(&start, &end, sum, root.hash())
+                .unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        // Extra node.
+        nodes.push(last.clone());
+        nodes.push(last);
+        assert_eq!(
+            RangeSumProof::new(nodes)
+                .verify::<()>(&start, &end, sum, root.hash())
+                .unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+    }
+}
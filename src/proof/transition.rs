@@ -0,0 +1,242 @@
+//! Transition proofs between two roots of a tree.
+//!
+//! A transition proof lists the updates applied to a tree, in order, each with the merkle proof
+//! of its key in the tree it was applied to. Replaying the updates from the old root checks
+//! every proof and yields the new root, so an auditor knows exactly which keys changed between
+//! the two roots.
+
+use crate::{
+    node::{Hasher, Leaf},
+    Proof, TreeError,
+};
+
+/// An update of a [`TransitionProof`]: the leaf at `key` went from `old_leaf` to `new_leaf`.
+#[derive(Debug, Clone)]
+pub struct TransitionStep<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
+    key: [u8; HASH_SIZE],
+    old_leaf: Leaf<HASH_SIZE, H>,
+    new_leaf: Leaf<HASH_SIZE, H>,
+    proof: Proof<HASH_SIZE, H>,
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> TransitionStep<HASH_SIZE, H> {
+    /// Creates a new step from the update of `key` and its proof in the tree before the
+    /// update.
+    pub fn new(
+        key: [u8; HASH_SIZE],
+        old_leaf: Leaf<HASH_SIZE, H>,
+        new_leaf: Leaf<HASH_SIZE, H>,
+        proof: Proof<HASH_SIZE, H>,
+    ) -> Self {
+        Self {
+            key,
+            old_leaf,
+            new_leaf,
+            proof,
+        }
+    }
+
+    /// Returns the updated key.
+    pub fn key(&self) -> &[u8; HASH_SIZE] {
+        &self.key
+    }
+
+    /// Returns the leaf before the update, empty if the key was inserted.
+    pub fn old_leaf(&self) -> &Leaf<HASH_SIZE, H> {
+        &self.old_leaf
+    }
+
+    /// Returns the leaf after the update, empty if the key was deleted.
+    pub fn new_leaf(&self) -> &Leaf<HASH_SIZE, H> {
+        &self.new_leaf
+    }
+
+    /// Returns the proof of the key in the tree before the update.
+    pub fn proof(&self) -> &Proof<HASH_SIZE, H> {
+        &self.proof
+    }
+}
+
+/// A proof of the updates between two roots of a tree.
+#[derive(Debug, Clone)]
+pub struct TransitionProof<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
+    steps: Vec<TransitionStep<HASH_SIZE, H>>,
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> TransitionProof<HASH_SIZE, H> {
+    /// Creates a new proof from the steps, in the order they were applied.
+    pub fn new(steps: Vec<TransitionStep<HASH_SIZE, H>>) -> Self {
+        Self { steps }
+    }
+
+    /// Returns the steps of the proof, in the order they were applied.
+    pub fn steps(&self) -> &[TransitionStep<HASH_SIZE, H>] {
+        &self.steps
+    }
+
+    /// Appends a step to the proof.
+    pub fn push(&mut self, step: TransitionStep<HASH_SIZE, H>) {
+        self.steps.push(step);
+    }
+
+    /// Replays the updates from the tree whose root hash is `old_root`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the root hash of the tree after the updates and the net change of its sum, or
+    /// [`TreeError::InvalidMerkleProof`] if a step doesn't match the tree it's applied to.
+    pub fn root<DbError>(
+        &self,
+        old_root: [u8; HASH_SIZE],
+    ) -> Result<([u8; HASH_SIZE], i128), TreeError<DbError>> {
+        let mut root = old_root;
+        let mut sum_change = 0i128;
+        for step in &self.steps {
+            root = step
+                .proof
+                .apply_update(
+                    &step.key,
+                    step.old_leaf.clone(),
+                    step.new_leaf.clone(),
+                    root,
+                )?
+                .hash();
+            sum_change += step.new_leaf.sum() as i128 - step.old_leaf.sum() as i128;
+        }
+        Ok((root, sum_change))
+    }
+
+    /// Verify that the updates lead from the tree whose root hash is `old_root` to the one
+    /// whose root hash is `new_root`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the net change of the sum of the tree if the proof is valid, otherwise returns
+    /// an error.
+    pub fn verify<DbError>(
+        &self,
+        old_root: [u8; HASH_SIZE],
+        new_root: [u8; HASH_SIZE],
+    ) -> Result<i128, TreeError<DbError>> {
+        let (root, sum_change) = self.root(old_root)?;
+        if root == new_root {
+            Ok(sum_change)
+        } else {
+            Err(TreeError::InvalidMerkleProof)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::Sha256;
+
+    use super::{TransitionProof, TransitionStep};
+    use crate::{CompactMSSMT, EmptyLeaf, Leaf, MemoryDb, TreeError, MSSMT};
+
+    /// Updates inserting, replacing and deleting leaves, some keys being updated twice.
+    fn updates() -> Vec<([u8; 32], Leaf<32, Sha256>)> {
+        vec![
+            ([7; 32], Leaf::new(vec![7], 7)),
+            ([2; 32], Leaf::new(vec![9], 9)),
+            ([3; 32], Leaf::Empty(EmptyLeaf::new())),
+            ([7; 32], Leaf::new(vec![8], 1)),
+            ([9; 32], Leaf::Empty(EmptyLeaf::new())),
+        ]
+    }
+
+    #[test]
+    fn test_transition_proof() {
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut compact_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for i in 0..5u8 {
+            let leaf = Leaf::new(vec![i], i as u64 + 1);
+            tree.insert(&[i; 32], leaf.clone()).unwrap();
+            compact_tree.insert(&[i; 32], leaf).unwrap();
+        }
+        let old_root = tree.root().unwrap();
+
+        let proof = tree.apply_transition(&updates()).unwrap();
+        let compact_proof = compact_tree.apply_transition(&updates()).unwrap();
+        let new_root = tree.root().unwrap();
+        assert_eq!(compact_tree.root().unwrap().hash(), new_root.hash());
+        assert_eq!(proof.steps().len(), 5);
+
+        let expected_change = new_root.sum() as i128 - old_root.sum() as i128;
+        assert_eq!(expected_change, 7 + (9 - 3) - 4 + (1 - 7));
+        for proof in [proof, compact_proof] {
+            assert_eq!(
+                proof.root::<()>(old_root.hash()).unwrap(),
+                (new_root.hash(), expected_change)
+            );
+            assert_eq!(
+                proof
+                    .verify::<()>(old_root.hash(), new_root.hash())
+                    .unwrap(),
+                expected_change
+            );
+        }
+    }
+
+    #[test]
+    fn test_transition_proof_empty() {
+        let proof = TransitionProof::<32, Sha256>::new(Vec::new());
+        assert_eq!(proof.verify::<()>([1; 32], [1; 32]).unwrap(), 0);
+        assert_eq!(
+            proof.verify::<()>([1; 32], [2; 32]).unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+    }
+
+    #[test]
+    fn test_transition_proof_invalid() {
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        tree.insert(&[1; 32], Leaf::new(vec![1], 1)).unwrap();
+        let old_root = tree.root().unwrap();
+        let proof = tree.apply_transition(&updates()).unwrap();
+        let new_root = tree.root().unwrap();
+
+        // Wrong roots.
+        assert_eq!(
+            proof.verify::<()>([0; 32], new_root.hash()).unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        assert_eq!(
+            proof.verify::<()>(old_root.hash(), [0; 32]).unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        // Missing step.
+        let mut steps = proof.steps().to_vec();
+        steps.remove(1);
+        assert_eq!(
+            TransitionProof::new(steps)
+                .verify::<()>(old_root.hash(), new_root.hash())
+                .unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        // Steps out of order.
+        let mut steps = proof.steps().to_vec();
+        steps.swap(0, 3);
+        assert_eq!(
+            TransitionProof::new(steps)
+                .verify::<()>(old_root.hash(), new_root.hash())
+                .unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+        // Hidden change of the new leaf.
+        let mut steps = proof.steps().to_vec();
+        let step = &steps[1];
+        steps[1] = TransitionStep::new(
+            *step.key(),
+            step.old_leaf().clone(),
+            Leaf::new(vec![9], 10),
+            step.proof().clone(),
+        );
+        assert_eq!(
+            TransitionProof::new(steps)
+                .verify::<()>(old_root.hash(), new_root.hash())
+                .unwrap_err(),
+            TreeError::InvalidMerkleProof
+        );
+    }
+}
@@ -0,0 +1,476 @@
+//! Serde support for the nodes and proofs, enabled by the `serde` feature.
+//!
+//! Hashes, keys and values are hex strings in human-readable formats and raw bytes in binary
+//! ones. A [`CompressedProof`] or a [`MultiProof`] is serialized as its encoded bytes.
+//!
+//! Leaves, branches and compacted leaves carry their hash, which is recomputed and checked when
+//! they are deserialized. Branches only hold the hash and sum of their children.
+
+use std::convert::Infallible;
+
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::SerializeStruct,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::{
+    node::Sum, Branch, CompactExclusionProof, CompactLeaf, CompressedProof, ComputedNode,
+    EmptyLeaf, EmptyTree, Hasher, Leaf, MultiProof, Node,
+};
+
+/// Bytes serialized as hex in human-readable formats.
+struct Hex<'a>(&'a [u8]);
+
+impl Serialize for Hex<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&hex::encode(self.0))
+        } else {
+            serializer.serialize_bytes(self.0)
+        }
+    }
+}
+
+/// Deserializes bytes serialized with [`Hex`].
+fn bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a hex string or bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            hex::decode(v).map_err(E::custom)
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(v)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_str(BytesVisitor)
+    } else {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+/// Deserializes a hash serialized with [`Hex`].
+fn hash<'de, const HASH_SIZE: usize, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<[u8; HASH_SIZE], D::Error> {
+    let bytes = bytes(deserializer)?;
+    let len = bytes.len();
+    bytes
+        .try_into()
+        .map_err(|_| de::Error::invalid_length(len, &"a hash"))
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Leaf")]
+struct LeafRepr<const HASH_SIZE: usize> {
+    #[serde(deserialize_with = "bytes")]
+    value: Vec<u8>,
+    sum: Sum,
+    #[serde(deserialize_with = "hash")]
+    hash: [u8; HASH_SIZE],
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Serialize for Leaf<HASH_SIZE, H> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Leaf", 3)?;
+        state.serialize_field("value", &Hex(self.value()))?;
+        state.serialize_field("sum", &self.sum())?;
+        state.serialize_field("hash", &Hex(&self.hash()))?;
+        state.end()
+    }
+}
+
+impl<'de, const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Deserialize<'de>
+    for Leaf<HASH_SIZE, H>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = LeafRepr::<HASH_SIZE>::deserialize(deserializer)?;
+        let leaf = Leaf::new(repr.value, repr.sum);
+        if leaf.hash() != repr.hash || leaf.sum() != repr.sum {
+            return Err(de::Error::custom("leaf hash mismatch"));
+        }
+        Ok(leaf)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "EmptyLeaf")]
+struct EmptyLeafRepr;
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Serialize for EmptyLeaf<HASH_SIZE, H> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_unit_struct("EmptyLeaf")
+    }
+}
+
+impl<'de, const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Deserialize<'de>
+    for EmptyLeaf<HASH_SIZE, H>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        EmptyLeafRepr::deserialize(deserializer)?;
+        Ok(EmptyLeaf::new())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Branch")]
+struct BranchRepr<const HASH_SIZE: usize> {
+    left: ComputedNode<HASH_SIZE>,
+    right: ComputedNode<HASH_SIZE>,
+    sum: Sum,
+    #[serde(deserialize_with = "hash")]
+    hash: [u8; HASH_SIZE],
+}
+
+/// The children of a branch are serialized as [`ComputedNode`]s: serializing them fully would
+/// walk the whole subtree, and the empty subtrees it shares.
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Serialize for Branch<HASH_SIZE, H> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (left, right) = self.children();
+        let mut state = serializer.serialize_struct("Branch", 4)?;
+        state.serialize_field("left", &ComputedNode::new(left.hash(), left.sum()))?;
+        state.serialize_field("right", &ComputedNode::new(right.hash(), right.sum()))?;
+        state.serialize_field("sum", &self.sum())?;
+        state.serialize_field("hash", &Hex(&self.hash()))?;
+        state.end()
+    }
+}
+
+/// The deserialized branch holds [`ComputedNode`] children.
+impl<'de, const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Deserialize<'de>
+    for Branch<HASH_SIZE, H>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = BranchRepr::<HASH_SIZE>::deserialize(deserializer)?;
+        if repr.left.sum().checked_add(repr.right.sum()) != Some(repr.sum) {
+            return Err(de::Error::custom("branch sum mismatch"));
+        }
+        let branch = Branch::new(Node::Computed(repr.left), Node::Computed(repr.right));
+        if branch.hash() != repr.hash {
+            return Err(de::Error::custom("branch hash mismatch"));
+        }
+        Ok(branch)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "CompactLeaf", bound = "")]
+struct CompactLeafRepr<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
+    #[serde(deserialize_with = "hash")]
+    key: [u8; HASH_SIZE],
+    leaf: Leaf<HASH_SIZE, H>,
+    #[serde(deserialize_with = "hash")]
+    hash: [u8; HASH_SIZE],
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Serialize for CompactLeaf<HASH_SIZE, H> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("CompactLeaf", 3)?;
+        state.serialize_field("key", &Hex(self.key()))?;
+        state.serialize_field("leaf", self.leaf())?;
+        state.serialize_field("hash", &Hex(&self.hash()))?;
+        state.end()
+    }
+}
+
+impl<'de, const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Deserialize<'de>
+    for CompactLeaf<HASH_SIZE, H>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = CompactLeafRepr::<HASH_SIZE, H>::deserialize(deserializer)?;
+        // The height of the compacted leaf isn't serialized, the hash must match the path of
+        // the key at one of the heights.
+        let empty_tree = EmptyTree::<HASH_SIZE, H>::empty_tree();
+        let mut current = Node::Leaf(repr.leaf.clone());
+        let mut height = HASH_SIZE * 8;
+        while current.hash() != repr.hash {
+            if height == 0 {
+                return Err(de::Error::custom("compact leaf hash mismatch"));
+            }
+            height -= 1;
+            current = if crate::tree::bit_index(height, &repr.key) == 0 {
+                Node::new_branch(current, empty_tree[height + 1].clone())
+            } else {
+                Node::new_branch(empty_tree[height + 1].clone(), current)
+            };
+        }
+        // SAFETY: the hash was just recomputed.
+        Ok(unsafe { CompactLeaf::new_with_hash(repr.hash, repr.leaf, repr.key) })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "ComputedNode")]
+struct ComputedNodeRepr<const HASH_SIZE: usize> {
+    #[serde(deserialize_with = "hash")]
+    hash: [u8; HASH_SIZE],
+    sum: Sum,
+}
+
+impl<const HASH_SIZE: usize> Serialize for ComputedNode<HASH_SIZE> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("ComputedNode", 2)?;
+        state.serialize_field("hash", &Hex(&self.hash()))?;
+        state.serialize_field("sum", &self.sum())?;
+        state.end()
+    }
+}
+
+impl<'de, const HASH_SIZE: usize> Deserialize<'de> for ComputedNode<HASH_SIZE> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = ComputedNodeRepr::<HASH_SIZE>::deserialize(deserializer)?;
+        Ok(ComputedNode::new(repr.hash, repr.sum))
+    }
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Serialize
+    for CompressedProof<HASH_SIZE, H>
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Hex(&self.encode()).serialize(serializer)
+    }
+}
+
+impl<'de, const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Deserialize<'de>
+    for CompressedProof<HASH_SIZE, H>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        CompressedProof::decode::<Infallible>(&bytes(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Serialize for MultiProof<HASH_SIZE, H> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Hex(&self.encode()).serialize(serializer)
+    }
+}
+
+impl<'de, const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Deserialize<'de>
+    for MultiProof<HASH_SIZE, H>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        MultiProof::decode::<Infallible>(&bytes(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+/// The compacted leaf a [`CompactExclusionProof`] terminates at.
+struct ExclusionEnd<'a, const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone>(
+    &'a [u8; HASH_SIZE],
+    &'a Leaf<HASH_SIZE, H>,
+);
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Serialize
+    for ExclusionEnd<'_, HASH_SIZE, H>
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("ExclusionEnd", 2)?;
+        state.serialize_field("key", &Hex(self.0))?;
+        state.serialize_field("leaf", self.1)?;
+        state.end()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "ExclusionEnd", bound = "")]
+struct ExclusionEndRepr<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
+    #[serde(deserialize_with = "hash")]
+    key: [u8; HASH_SIZE],
+    leaf: Leaf<HASH_SIZE, H>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "CompactExclusionProof", bound = "")]
+struct CompactExclusionProofRepr<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
+    nodes: Vec<Node<HASH_SIZE, H>>,
+    end: Option<ExclusionEndRepr<HASH_SIZE, H>>,
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Serialize
+    for CompactExclusionProof<HASH_SIZE, H>
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("CompactExclusionProof", 2)?;
+        state.serialize_field("nodes", self.nodes())?;
+        state.serialize_field(
+            "end",
+            &self.end().map(|(key, leaf)| ExclusionEnd(key, leaf)),
+        )?;
+        state.end()
+    }
+}
+
+impl<'de, const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> Deserialize<'de>
+    for CompactExclusionProof<HASH_SIZE, H>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = CompactExclusionProofRepr::<HASH_SIZE, H>::deserialize(deserializer)?;
+        Ok(CompactExclusionProof::new(
+            repr.nodes,
+            repr.end.map(|end| (end.key, end.leaf)),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde::{de::DeserializeOwned, Serialize};
+    use sha2::Sha256;
+
+    use crate::{
+        Branch, CompactExclusionProof, CompactLeaf, CompactMSSMT, CompressedProof, ComputedNode,
+        EmptyLeaf, EmptyTree, Leaf, MemoryDb, MultiProof, Node, Proof, RangeSumProof,
+    };
+
+    /// Round trips `value` through a human-readable and a binary format.
+    fn round_trip<T: Serialize + DeserializeOwned>(value: &T) -> (T, T) {
+        let json = serde_json::to_string(value).unwrap();
+        let binary = bincode::serialize(value).unwrap();
+        (
+            serde_json::from_str(&json).unwrap(),
+            bincode::deserialize(&binary).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_serde_nodes() {
+        let leaf = Leaf::<32, Sha256>::new(vec![1, 2, 3], 10);
+        for got in <[_; 2]>::from(round_trip(&leaf)) {
+            assert_eq!(got.hash(), leaf.hash());
+            assert_eq!(got.value(), leaf.value());
+        }
+        let empty = Leaf::<32, Sha256>::Empty(EmptyLeaf::new());
+        for got in <[_; 2]>::from(round_trip(&empty)) {
+            assert!(matches!(got, Leaf::Empty(_)));
+        }
+        let empty_leaf = EmptyLeaf::<32, Sha256>::new();
+        for got in <[_; 2]>::from(round_trip(&empty_leaf)) {
+            assert_eq!(got.hash(), empty_leaf.hash());
+        }
+        let computed = ComputedNode::new([7; 32], 3);
+        for got in <[_; 2]>::from(round_trip(&computed)) {
+            assert_eq!(got, computed);
+        }
+        let empty_tree = EmptyTree::<32, Sha256>::empty_tree();
+        let compact = CompactLeaf::new(3, [5; 32], leaf.clone(), empty_tree.clone());
+        for got in <[_; 2]>::from(round_trip(&compact)) {
+            assert_eq!(got.hash(), compact.hash());
+            assert_eq!(got.key(), compact.key());
+        }
+        let branch = Branch::new(
+            Node::Branch(Branch::new(
+                Node::Leaf(leaf.clone()),
+                Node::Computed(computed.clone()),
+            )),
+            Node::Compact(compact.clone()),
+        );
+        for got in <[_; 2]>::from(round_trip(&branch)) {
+            assert_eq!(got.hash(), branch.hash());
+            assert_eq!(got.sum(), branch.sum());
+        }
+    }
+
+    #[test]
+    fn test_serde_hex() {
+        let leaf = Leaf::<32, Sha256>::new(vec![0xab, 0xcd], 10);
+        let json = serde_json::to_value(&leaf).unwrap();
+        assert_eq!(json["value"], "abcd");
+        assert_eq!(json["sum"], 10);
+        assert_eq!(json["hash"], hex::encode(leaf.hash()));
+        // Binary formats hold the raw bytes.
+        let binary = bincode::serialize(&leaf).unwrap();
+        assert!(binary.windows(32).any(|window| window == leaf.hash()));
+    }
+
+    #[test]
+    fn test_serde_hash_mismatch() {
+        let leaf = Leaf::<32, Sha256>::new(vec![1], 10);
+        let mut json = serde_json::to_value(&leaf).unwrap();
+        json["sum"] = 11.into();
+        assert!(serde_json::from_value::<Leaf<32, Sha256>>(json).is_err());
+
+        let branch = Branch::<32, Sha256>::new(Node::Leaf(leaf.clone()), Node::new_empty_leaf());
+        let mut json = serde_json::to_value(&branch).unwrap();
+        json["hash"] = hex::encode([0; 32]).into();
+        assert!(serde_json::from_value::<Branch<32, Sha256>>(json).is_err());
+        let mut json = serde_json::to_value(&branch).unwrap();
+        json["sum"] = 11.into();
+        assert!(serde_json::from_value::<Branch<32, Sha256>>(json).is_err());
+
+        let empty_tree = EmptyTree::<32, Sha256>::empty_tree();
+        let compact = CompactLeaf::new(3, [5; 32], leaf, empty_tree);
+        let mut json = serde_json::to_value(&compact).unwrap();
+        json["key"] = hex::encode([6; 32]).into();
+        assert!(serde_json::from_value::<CompactLeaf<32, Sha256>>(json).is_err());
+
+        // Hashes must have the right size.
+        let mut json = serde_json::to_value(ComputedNode::new([7; 32], 3)).unwrap();
+        json["hash"] = "0707".into();
+        assert!(serde_json::from_value::<ComputedNode<32>>(json).is_err());
+    }
+
+    #[test]
+    fn test_serde_proofs() {
+        let mut tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for i in 0..10u8 {
+            tree.insert(&[i; 32], Leaf::new(vec![i + 1], i as u64))
+                .unwrap();
+        }
+        let root = tree.root().unwrap();
+        let leaf = Leaf::new(vec![4], 3);
+
+        let proof = tree.merkle_proof(&[3; 32]).unwrap();
+        for got in <[Proof<32, Sha256>; 2]>::from(round_trip(&proof)) {
+            got.verify_merkle_proof::<()>(&[3; 32], leaf.clone(), root.hash())
+                .unwrap();
+        }
+
+        // Compressed proofs use the same hex encoding as the taproot-assets test vectors.
+        let compressed = proof.compress();
+        let json = serde_json::to_value(&compressed).unwrap();
+        assert_eq!(json, hex::encode(compressed.encode()));
+        for got in <[CompressedProof<32, Sha256>; 2]>::from(round_trip(&compressed)) {
+            assert_eq!(got.encode(), compressed.encode());
+        }
+        assert!(serde_json::from_value::<CompressedProof<32, Sha256>>("0000".into()).is_err());
+
+        let multi_proof = tree.multi_proof(&[[3; 32], [4; 32]]).unwrap();
+        let leaves = [([3; 32], leaf), ([4; 32], Leaf::new(vec![5], 4))];
+        for got in <[MultiProof<32, Sha256>; 2]>::from(round_trip(&multi_proof)) {
+            got.verify::<()>(&leaves, root.hash()).unwrap();
+        }
+
+        let (start, end) = ([1; 32], [6; 32]);
+        let sum = tree.range_sum(&start, &end).unwrap();
+        let range_proof = tree.range_sum_proof(&start, &end).unwrap();
+        for got in <[RangeSumProof<32, Sha256>; 2]>::from(round_trip(&range_proof)) {
+            got.verify::<()>(&start, &end, sum, root.hash()).unwrap();
+        }
+
+        let exclusion_proof = tree.compact_exclusion_proof(&[0xff; 32]).unwrap();
+        for got in <[CompactExclusionProof<32, Sha256>; 2]>::from(round_trip(&exclusion_proof)) {
+            got.verify::<()>(&[0xff; 32], root.hash()).unwrap();
+        }
+    }
+}
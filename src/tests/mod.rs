@@ -1,3 +1,22 @@
 mod sha512;
 mod taproot;
 mod tree;
+
+use std::{
+    future::Future,
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+
+/// Polls `future` on the current thread until it completes. The futures of
+/// [`BlockingDb`](crate::BlockingDb) are always ready so no executor is needed to drive them.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        std::thread::yield_now();
+    }
+}
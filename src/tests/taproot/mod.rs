@@ -1,10 +1,39 @@
+use std::cell::Cell;
+use std::fmt::Debug;
 use std::path::PathBuf;
 mod types;
-use crate::{tests::taproot::types::TestVectors, CompactMSSMT, EmptyLeaf, Leaf, MemoryDb, MSSMT};
+use crate::{
+    tests::{block_on, taproot::types::TestVectors},
+    AsyncCompactMSSMT, AsyncMSSMT, BlockingDb, CompactMSSMT, CompressedProof, Db, EmptyLeaf,
+    FileDb, FileDbError, Leaf, MemoryDb, ThreadSafe, MSSMT,
+};
 use sha2::Sha256;
 use std::fs;
+use tempfile::TempDir;
 
-fn run_bip_test_vector(test_vectors: &TestVectors) {
+fn memory_db() -> Box<dyn Db<32, Sha256, DbError = ()>> {
+    Box::new(MemoryDb::<32, Sha256>::new())
+}
+
+/// Returns a function that opens a new [`FileDb`] in `dir` every time it's called.
+fn file_db(dir: &TempDir) -> impl Fn() -> Box<dyn Db<32, Sha256, DbError = FileDbError>> + '_ {
+    let count = Cell::new(0);
+    move || {
+        count.set(count.get() + 1);
+        Box::new(FileDb::<32, Sha256>::open(dir.path().join(count.get().to_string())).unwrap())
+    }
+}
+
+fn load_test_vectors(file: &str) -> TestVectors {
+    let path = PathBuf::from("src/tests/taproot/testdata").join(file);
+    let json = fs::read_to_string(&path).unwrap();
+    serde_json::from_str::<TestVectors>(&json).unwrap()
+}
+
+fn run_bip_test_vector<DbError: Debug + ThreadSafe>(
+    test_vectors: &TestVectors,
+    new_db: impl Fn() -> Box<dyn Db<32, Sha256, DbError = DbError>>,
+) {
     // For each valid test case
     if let Some(valid_cases) = &test_vectors.valid_test_cases {
         for valid_case in valid_cases {
@@ -12,14 +41,15 @@ fn run_bip_test_vector(test_vectors: &TestVectors) {
             println!("Running test case: {}", comment);
 
             // Create both a full tree and a compact tree
-            let full_db = Box::new(MemoryDb::<32, Sha256>::new());
-            let mut full_tree = MSSMT::<32, Sha256, ()>::new(full_db);
-
-            let compact_db = Box::new(MemoryDb::<32, Sha256>::new());
-            let mut compact_tree = CompactMSSMT::<32, Sha256, ()>::new(compact_db);
+            let mut full_tree = MSSMT::<32, Sha256, DbError>::new(new_db());
+            let mut compact_tree = CompactMSSMT::<32, Sha256, DbError>::new(new_db());
+            // And the same trees built with batches
+            let mut full_batch_tree = MSSMT::<32, Sha256, DbError>::new(new_db());
+            let mut compact_batch_tree = CompactMSSMT::<32, Sha256, DbError>::new(new_db());
 
             // Insert all leaves declared in the test vector
             println!("insertion");
+            let mut batch = Vec::new();
             for leaf in &test_vectors.all_tree_leaves {
                 if !valid_case.should_insert(&leaf.key) {
                     continue;
@@ -29,12 +59,16 @@ fn run_bip_test_vector(test_vectors: &TestVectors) {
                 let leaf_node = leaf.to_leaf_node().unwrap();
 
                 full_tree.insert(&key, leaf_node.clone()).unwrap();
-                compact_tree.insert(&key, leaf_node).unwrap();
+                compact_tree.insert(&key, leaf_node.clone()).unwrap();
+                batch.push((key, leaf_node));
             }
+            full_batch_tree.insert_batch(&batch).unwrap();
+            compact_batch_tree.insert_batch(&batch).unwrap();
 
             // Delete all leaves declared in the test vector
             println!("deletion");
             if let Some(deleted_leaves) = &valid_case.deleted_leaves {
+                let mut batch = Vec::new();
                 for key_hex in deleted_leaves {
                     if !valid_case.should_delete(key_hex) {
                         continue;
@@ -43,20 +77,42 @@ fn run_bip_test_vector(test_vectors: &TestVectors) {
                     let key = hex::decode(key_hex).unwrap().try_into().unwrap();
                     full_tree.delete(&key).unwrap();
                     compact_tree.delete(&key).unwrap();
+                    batch.push((key, Leaf::Empty(EmptyLeaf::new())));
                 }
+                full_batch_tree.insert_batch(&batch).unwrap();
+                compact_batch_tree.insert_batch(&batch).unwrap();
             }
 
             // Replace all leaves declared in the test vector
             if let Some(replaced_leaves) = &valid_case.replaced_leaves {
+                let mut batch = Vec::new();
                 for leaf in replaced_leaves {
                     let key = leaf.key_as_bytes().unwrap();
                     let leaf_node = leaf.to_leaf_node().unwrap();
 
                     full_tree.insert(&key, leaf_node.clone()).unwrap();
-                    compact_tree.insert(&key, leaf_node).unwrap();
+                    compact_tree.insert(&key, leaf_node.clone()).unwrap();
+                    batch.push((key, leaf_node));
                 }
+                full_batch_tree.insert_batch(&batch).unwrap();
+                compact_batch_tree.insert_batch(&batch).unwrap();
+            }
+
+            // Both layouts must hold the same leaves
+            let leaves = full_tree.iter().collect::<Result<Vec<_>, _>>().unwrap();
+            let compact_leaves = compact_tree.iter().collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(leaves.len(), compact_leaves.len());
+            for ((key, leaf), (compact_key, compact_leaf)) in leaves.iter().zip(&compact_leaves) {
+                assert_eq!(key, compact_key);
+                assert_eq!(leaf.hash(), compact_leaf.hash());
+                assert_eq!(full_tree.get(key).unwrap().hash(), leaf.hash());
             }
 
+            // Batches must end up with the same roots as sequential updates
+            let full_root = full_tree.root().unwrap();
+            assert_eq!(full_root.hash(), full_batch_tree.root().unwrap().hash());
+            assert_eq!(full_root.hash(), compact_batch_tree.root().unwrap().hash());
+
             // Verify the expected root hash and sum
             if let (Some(root_hash), Some(root_sum)) = (&valid_case.root_hash, &valid_case.root_sum)
             {
@@ -78,7 +134,9 @@ fn run_bip_test_vector(test_vectors: &TestVectors) {
                 for proof_case in inclusion_proofs {
                     let key = proof_case.proof_key_as_bytes().unwrap();
                     let proof = proof_case.to_proof();
-                    let leaf = test_vectors.find_leaf(&proof_case.proof_key).unwrap();
+                    let leaf = valid_case
+                        .find_leaf(test_vectors, &proof_case.proof_key)
+                        .unwrap();
                     let leaf_node = leaf.to_leaf_node().unwrap();
 
                     let full_root = full_tree.root().unwrap();
@@ -90,6 +148,13 @@ fn run_bip_test_vector(test_vectors: &TestVectors) {
                     proof
                         .verify_merkle_proof::<()>(&key, leaf_node, compact_root.hash())
                         .unwrap();
+
+                    // Our proofs must be encoded exactly like the Go ones
+                    let expected = proof_case.compressed_proof_as_bytes().unwrap();
+                    let full_proof = full_tree.merkle_proof(&key).unwrap();
+                    let compact_proof = compact_tree.merkle_proof(&key).unwrap();
+                    assert_eq!(full_proof.compress().encode(), expected);
+                    assert_eq!(compact_proof.compress().encode(), expected);
                 }
             }
 
@@ -110,6 +175,12 @@ fn run_bip_test_vector(test_vectors: &TestVectors) {
                     assert!(proof
                         .verify_merkle_proof::<()>(&key, empty_leaf, compact_root.hash())
                         .is_ok());
+
+                    let expected = proof_case.compressed_proof_as_bytes().unwrap();
+                    let full_proof = full_tree.merkle_proof(&key).unwrap();
+                    let compact_proof = compact_tree.merkle_proof(&key).unwrap();
+                    assert_eq!(full_proof.compress().encode(), expected);
+                    assert_eq!(compact_proof.compress().encode(), expected);
                 }
             }
         }
@@ -124,11 +195,8 @@ fn run_bip_test_vector(test_vectors: &TestVectors) {
                 .unwrap_or("unnamed error test");
             println!("Running error test case: {}", comment);
 
-            let full_db = Box::new(MemoryDb::<32, Sha256>::new());
-            let mut full_tree = MSSMT::<32, Sha256, ()>::new(full_db);
-
-            let compact_db = Box::new(MemoryDb::<32, Sha256>::new());
-            let mut compact_tree = CompactMSSMT::<32, Sha256, ()>::new(compact_db);
+            let mut full_tree = MSSMT::<32, Sha256, DbError>::new(new_db());
+            let mut compact_tree = CompactMSSMT::<32, Sha256, DbError>::new(new_db());
 
             let last_idx = test_vectors.all_tree_leaves.len() - 1;
             for (idx, leaf) in test_vectors.all_tree_leaves.iter().enumerate() {
@@ -152,34 +220,172 @@ fn run_bip_test_vector(test_vectors: &TestVectors) {
     }
 }
 
+/// Runs the valid cases of the test vectors against the async trees, stored in a
+/// [`MemoryDb`] behind a [`BlockingDb`].
+fn run_async_bip_test_vector(test_vectors: &TestVectors) {
+    let new_db = || BlockingDb::new(MemoryDb::<32, Sha256>::new());
+    for valid_case in test_vectors.valid_test_cases.iter().flatten() {
+        let mut full_tree = AsyncMSSMT::new(new_db());
+        let mut compact_tree = AsyncCompactMSSMT::new(new_db());
+        block_on(async {
+            for leaf in &test_vectors.all_tree_leaves {
+                if !valid_case.should_insert(&leaf.key) {
+                    continue;
+                }
+                let key = leaf.key_as_bytes().unwrap();
+                let leaf_node = leaf.to_leaf_node().unwrap();
+                full_tree.insert(&key, leaf_node.clone()).await.unwrap();
+                compact_tree.insert(&key, leaf_node).await.unwrap();
+            }
+            for key_hex in valid_case.deleted_leaves.iter().flatten() {
+                if !valid_case.should_delete(key_hex) {
+                    continue;
+                }
+                let key = hex::decode(key_hex).unwrap().try_into().unwrap();
+                full_tree.delete(&key).await.unwrap();
+                compact_tree.delete(&key).await.unwrap();
+            }
+            for leaf in valid_case.replaced_leaves.iter().flatten() {
+                let key = leaf.key_as_bytes().unwrap();
+                let leaf_node = leaf.to_leaf_node().unwrap();
+                full_tree.insert(&key, leaf_node.clone()).await.unwrap();
+                compact_tree.insert(&key, leaf_node).await.unwrap();
+            }
+
+            let full_root = full_tree.root().await.unwrap();
+            let compact_root = compact_tree.root().await.unwrap();
+            assert_eq!(full_root.hash(), compact_root.hash());
+            if let (Some(root_hash), Some(root_sum)) = (&valid_case.root_hash, &valid_case.root_sum)
+            {
+                let expected_hash: [u8; 32] = hex::decode(root_hash).unwrap().try_into().unwrap();
+                assert_eq!(expected_hash, full_root.hash());
+                assert_eq!(root_sum.parse::<u64>().unwrap(), full_root.sum());
+            }
+
+            for proof_case in valid_case.inclusion_proofs.iter().flatten() {
+                let key = proof_case.proof_key_as_bytes().unwrap();
+                let leaf = valid_case
+                    .find_leaf(test_vectors, &proof_case.proof_key)
+                    .unwrap();
+                let leaf_node = leaf.to_leaf_node().unwrap();
+                assert_eq!(full_tree.get(&key).await.unwrap().hash(), leaf_node.hash());
+                assert_eq!(
+                    compact_tree.get(&key).await.unwrap().hash(),
+                    leaf_node.hash()
+                );
+                let expected = proof_case.compressed_proof_as_bytes().unwrap();
+                let full_proof = full_tree.merkle_proof(&key).await.unwrap();
+                let compact_proof = compact_tree.merkle_proof(&key).await.unwrap();
+                assert_eq!(full_proof.compress().encode(), expected);
+                assert_eq!(compact_proof.compress().encode(), expected);
+            }
+            for proof_case in valid_case.exclusion_proofs.iter().flatten() {
+                let key = proof_case.proof_key_as_bytes().unwrap();
+                assert!(matches!(
+                    compact_tree.get(&key).await.unwrap(),
+                    Leaf::Empty(_)
+                ));
+                let expected = proof_case.compressed_proof_as_bytes().unwrap();
+                let full_proof = full_tree.merkle_proof(&key).await.unwrap();
+                let compact_proof = compact_tree.merkle_proof(&key).await.unwrap();
+                assert_eq!(full_proof.compress().encode(), expected);
+                assert_eq!(compact_proof.compress().encode(), expected);
+            }
+        });
+    }
+}
+
+/// Decodes and re-encodes every proof of the test vectors, returns the number of proofs.
+fn run_proof_encoding(test_vectors: &TestVectors) -> usize {
+    let proofs = test_vectors
+        .valid_test_cases
+        .iter()
+        .flatten()
+        .flat_map(|case| {
+            let inclusion = case.inclusion_proofs.iter().flatten();
+            inclusion.chain(case.exclusion_proofs.iter().flatten())
+        });
+    let mut count = 0;
+    for proof_case in proofs {
+        let bytes = proof_case.compressed_proof_as_bytes().unwrap();
+        let compressed = CompressedProof::<32, Sha256>::decode::<()>(&bytes).unwrap();
+        assert_eq!(compressed.encode(), bytes);
+        let proof = compressed.decompress::<()>().unwrap();
+        assert_eq!(proof.compress().encode(), bytes);
+        count += 1;
+    }
+    count
+}
+
 #[test]
 fn test_bip_tree_deletion() {
-    let path = PathBuf::from("src/tests/taproot/testdata/mssmt_tree_deletion.json");
-    let json = fs::read_to_string(&path).unwrap();
-    let test_vectors = serde_json::from_str::<TestVectors>(&json).unwrap();
-    run_bip_test_vector(&test_vectors);
+    run_bip_test_vector(&load_test_vectors("mssmt_tree_deletion.json"), memory_db);
 }
 
 #[test]
 fn test_bip_tree_error_cases() {
-    let path = PathBuf::from("src/tests/taproot/testdata/mssmt_tree_error_cases.json");
-    let json = fs::read_to_string(&path).unwrap();
-    let test_vectors = serde_json::from_str::<TestVectors>(&json).unwrap();
-    run_bip_test_vector(&test_vectors);
+    run_bip_test_vector(&load_test_vectors("mssmt_tree_error_cases.json"), memory_db);
 }
 
+/// The leaves and the root hash come from the taproot-assets replacement vectors, the proofs
+/// were encoded by this crate and must verify against that root. The byte format itself is
+/// pinned on a taproot-assets proof by `proof::tests::decode_proof`.
 #[test]
 fn test_bip_tree_proofs() {
-    let path = PathBuf::from("src/tests/taproot/testdata/mssmt_tree_proofs.json");
-    let json = fs::read_to_string(&path).unwrap();
-    let test_vectors = serde_json::from_str::<TestVectors>(&json).unwrap();
-    run_bip_test_vector(&test_vectors);
+    let test_vectors = load_test_vectors("mssmt_tree_proofs.json");
+    run_bip_test_vector(&test_vectors, memory_db);
+    assert_ne!(run_proof_encoding(&test_vectors), 0);
 }
 
 #[test]
 fn test_bip_tree_replacement() {
-    let path = PathBuf::from("src/tests/taproot/testdata/mssmt_tree_replacement.json");
-    let json = fs::read_to_string(&path).unwrap();
-    let test_vectors = serde_json::from_str::<TestVectors>(&json).unwrap();
-    run_bip_test_vector(&test_vectors);
+    run_bip_test_vector(&load_test_vectors("mssmt_tree_replacement.json"), memory_db);
+}
+
+#[test]
+fn test_bip_tree_deletion_file_db() {
+    let dir = tempfile::tempdir().unwrap();
+    run_bip_test_vector(
+        &load_test_vectors("mssmt_tree_deletion.json"),
+        file_db(&dir),
+    );
+}
+
+#[test]
+fn test_bip_tree_error_cases_file_db() {
+    let dir = tempfile::tempdir().unwrap();
+    run_bip_test_vector(
+        &load_test_vectors("mssmt_tree_error_cases.json"),
+        file_db(&dir),
+    );
+}
+
+#[test]
+fn test_bip_tree_proofs_file_db() {
+    let dir = tempfile::tempdir().unwrap();
+    run_bip_test_vector(&load_test_vectors("mssmt_tree_proofs.json"), file_db(&dir));
+}
+
+#[test]
+fn test_bip_tree_replacement_file_db() {
+    let dir = tempfile::tempdir().unwrap();
+    run_bip_test_vector(
+        &load_test_vectors("mssmt_tree_replacement.json"),
+        file_db(&dir),
+    );
+}
+
+#[test]
+fn test_bip_tree_deletion_async() {
+    run_async_bip_test_vector(&load_test_vectors("mssmt_tree_deletion.json"));
+}
+
+#[test]
+fn test_bip_tree_proofs_async() {
+    run_async_bip_test_vector(&load_test_vectors("mssmt_tree_proofs.json"));
+}
+
+#[test]
+fn test_bip_tree_replacement_async() {
+    run_async_bip_test_vector(&load_test_vectors("mssmt_tree_replacement.json"));
 }
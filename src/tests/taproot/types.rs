@@ -8,7 +8,7 @@ use std::str::FromStr;
 /// Parse a hex string into a proof
 pub fn parse_proof(proof_hex: &str) -> Result<Proof<32, Sha256>, TreeError<()>> {
     let proof_bytes = hex::decode(proof_hex).unwrap();
-    let compressed = CompressedProof::<32, Sha256>::decode(&proof_bytes[..]);
+    let compressed = CompressedProof::<32, Sha256>::decode(&proof_bytes[..])?;
     compressed.decompress()
 }
 
@@ -154,14 +154,27 @@ impl ValidTestCase {
     pub fn should_insert(&self, key: &str) -> bool {
         self.inserted_leaves
             .as_ref()
-            .map_or(false, |leaves| leaves.iter().any(|k| k == key))
+            .is_some_and(|leaves| leaves.iter().any(|k| k == key))
     }
 
     /// Check if a key should be deleted
     pub fn should_delete(&self, key: &str) -> bool {
         self.deleted_leaves
             .as_ref()
-            .map_or(false, |leaves| leaves.iter().any(|k| k == key))
+            .is_some_and(|leaves| leaves.iter().any(|k| k == key))
+    }
+
+    /// Find the leaf of a key once the test case is applied, among the replaced leaves first
+    pub fn find_leaf<'a>(
+        &'a self,
+        test_vectors: &'a TestVectors,
+        key: &str,
+    ) -> Option<&'a TestLeaf> {
+        self.replaced_leaves
+            .iter()
+            .flatten()
+            .find(|leaf| leaf.key == key)
+            .or_else(|| test_vectors.find_leaf(key))
     }
 }
 
@@ -1,18 +1,89 @@
 //! Tests for the Merkle Sum Sparse Merkle Tree implementation
 
+use std::cell::Cell;
+use std::fmt::Debug;
+
 use hex_literal::hex;
 use sha2::Sha256;
+use tempfile::TempDir;
 
 use crate::{
     node::{Branch, CompactLeaf, Hasher, Leaf, Node},
     tree::{CompactMSSMT, MSSMT},
-    Db, EmptyTree, MemoryDb, ThreadSafe,
+    Db, EmptyTree, FileDb, FileDbError, MemoryDb, ThreadSafe,
 };
 
+fn memory_db() -> Box<dyn Db<32, Sha256, DbError = ()>> {
+    Box::new(MemoryDb::<32, Sha256>::default())
+}
+
+/// Returns a function that opens a new [`FileDb`] in `dir` every time it's called.
+fn file_db(dir: &TempDir) -> impl Fn() -> Box<dyn Db<32, Sha256, DbError = FileDbError>> + '_ {
+    let count = Cell::new(0);
+    move || {
+        count.set(count.get() + 1);
+        Box::new(FileDb::<32, Sha256>::open(dir.path().join(count.get().to_string())).unwrap())
+    }
+}
+
 #[test]
 fn test_empty_tree() {
-    let tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
-    let compact_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+    empty_tree(memory_db);
+}
+
+#[test]
+fn test_empty_tree_file_db() {
+    let dir = tempfile::tempdir().unwrap();
+    empty_tree(file_db(&dir));
+}
+
+#[test]
+fn test_leaves_insertion() {
+    leaves_insertion(memory_db);
+}
+
+#[test]
+fn test_leaves_insertion_file_db() {
+    let dir = tempfile::tempdir().unwrap();
+    leaves_insertion(file_db(&dir));
+}
+
+#[test]
+fn test_history_independant() {
+    history_independant(memory_db);
+}
+
+#[test]
+fn test_history_independant_file_db() {
+    let dir = tempfile::tempdir().unwrap();
+    history_independant(file_db(&dir));
+}
+
+#[test]
+fn test_insert_batch() {
+    insert_batch(memory_db);
+}
+
+#[test]
+fn test_insert_batch_file_db() {
+    let dir = tempfile::tempdir().unwrap();
+    insert_batch(file_db(&dir));
+}
+
+#[test]
+fn test_insertion() {
+    insertion(memory_db);
+}
+
+#[test]
+fn test_insertion_file_db() {
+    let dir = tempfile::tempdir().unwrap();
+    insertion(file_db(&dir));
+}
+
+fn empty_tree<DbError: Debug>(new_db: impl Fn() -> Box<dyn Db<32, Sha256, DbError = DbError>>) {
+    let tree = MSSMT::<32, Sha256, DbError>::new(new_db());
+    let compact_tree = CompactMSSMT::<32, Sha256, DbError>::new(new_db());
     assert_eq!(
         tree.root().unwrap().hash(),
         hex!("b1e8e8f2dc3b266452988cfe169aa73be25405eeead02ab5dd6b3c6fd0ca8d67")
@@ -31,8 +102,9 @@ fn test_empty_tree() {
     );
 }
 
-#[test]
-fn test_leaves_insertion() {
+fn leaves_insertion<DbError: Debug>(
+    new_db: impl Fn() -> Box<dyn Db<32, Sha256, DbError = DbError>>,
+) {
     let leaf1 = Leaf::new([1; 32].to_vec(), 1);
     let leaf2 = Leaf::new([2; 32].to_vec(), 2);
     let leaf3 = Leaf::new([3; 32].to_vec(), 3);
@@ -56,8 +128,8 @@ fn test_leaves_insertion() {
         ]
     );
 
-    let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
-    let mut compact_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+    let mut tree = MSSMT::<32, Sha256, DbError>::new(new_db());
+    let mut compact_tree = CompactMSSMT::<32, Sha256, DbError>::new(new_db());
 
     tree.insert(&[1; 32], leaf1.clone()).unwrap();
     compact_tree.insert(&[1; 32], leaf1.clone()).unwrap();
@@ -100,14 +172,15 @@ fn test_leaves_insertion() {
     );
 }
 
-#[test]
-fn test_history_independant() {
+fn history_independant<DbError: Debug>(
+    new_db: impl Fn() -> Box<dyn Db<32, Sha256, DbError = DbError>>,
+) {
     let leaf1 = Leaf::new([1; 32].to_vec(), 1);
     let leaf2 = Leaf::new([2; 32].to_vec(), 2);
     let leaf3 = Leaf::new([3; 32].to_vec(), 3);
 
-    let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
-    let mut compact_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+    let mut tree = MSSMT::<32, Sha256, DbError>::new(new_db());
+    let mut compact_tree = CompactMSSMT::<32, Sha256, DbError>::new(new_db());
     tree.insert(&[1; 32], leaf1.clone()).unwrap();
     tree.insert(&[3; 32], leaf3.clone()).unwrap();
     tree.insert(&[2; 32], leaf2.clone()).unwrap();
@@ -124,19 +197,54 @@ fn test_history_independant() {
     );
 }
 
-#[test]
-fn test_insertion() {
+// Large enough for the top subtrees to be built concurrently with the `multi-thread` feature.
+fn insert_batch<DbError: Debug + ThreadSafe>(
+    new_db: impl Fn() -> Box<dyn Db<32, Sha256, DbError = DbError>>,
+) {
+    let leaves = (0..300u32)
+        .map(|i| {
+            let key = Sha256::hash(&i.to_be_bytes());
+            (key, Leaf::new(i.to_be_bytes().to_vec(), i as u64))
+        })
+        .collect::<Vec<_>>();
+
+    let mut tree = MSSMT::<32, Sha256, DbError>::new(new_db());
+    let mut batch_tree = MSSMT::<32, Sha256, DbError>::new(new_db());
+    let mut compact_tree = CompactMSSMT::<32, Sha256, DbError>::new(new_db());
+    let mut compact_batch_tree = CompactMSSMT::<32, Sha256, DbError>::new(new_db());
+    for (key, leaf) in &leaves {
+        tree.insert(key, leaf.clone()).unwrap();
+        compact_tree.insert(key, leaf.clone()).unwrap();
+    }
+    batch_tree.insert_batch(&leaves).unwrap();
+    compact_batch_tree.insert_batch(&leaves).unwrap();
+
+    let root = tree.root().unwrap();
+    assert_eq!(root.sum(), (0..300).sum::<u64>());
+    assert_eq!(root.hash(), batch_tree.root().unwrap().hash());
+    assert_eq!(root.hash(), compact_tree.root().unwrap().hash());
+    assert_eq!(root.hash(), compact_batch_tree.root().unwrap().hash());
+    for (key, leaf) in leaves.iter().step_by(10) {
+        batch_tree
+            .merkle_proof(key)
+            .unwrap()
+            .verify_merkle_proof::<DbError>(key, leaf.clone(), root.hash())
+            .unwrap();
+        compact_batch_tree
+            .merkle_proof(key)
+            .unwrap()
+            .verify_merkle_proof::<DbError>(key, leaf.clone(), root.hash())
+            .unwrap();
+    }
+}
+
+fn insertion<DbError: Debug>(new_db: impl Fn() -> Box<dyn Db<32, Sha256, DbError = DbError>>) {
     // tests that inserting leaves, branches and compacted leaves
     // in an orderly manner results in the expected tree structure in the database.
-    fn test_children<
-        const HASH_SIZE: usize,
-        H: Hasher<HASH_SIZE> + Clone + Default + ThreadSafe,
-    >(
-        leaves: Vec<Leaf<HASH_SIZE, H>>,
-        check_branches: Vec<Vec<Branch<HASH_SIZE, H>>>,
-        leaf_level: usize,
-    ) {
-        let mut db = MemoryDb::default();
+    let test_children = |leaves: Vec<Leaf<32, Sha256>>,
+                         check_branches: Vec<Vec<Branch<32, Sha256>>>,
+                         leaf_level: usize| {
+        let mut db = new_db();
         for leaf in leaves {
             db.insert_leaf(leaf).unwrap();
         }
@@ -152,7 +260,7 @@ fn test_insertion() {
                 assert_eq!(branch.right().hash(), right.hash());
             }
         }
-    }
+    };
 
     let l1 = Leaf::new([1; 32].to_vec(), 1);
     let l2 = Leaf::new([2; 32].to_vec(), 2);
@@ -301,7 +409,7 @@ fn test_insertion() {
         Node::Branch(b2.clone()),
         Node::Branch(branch_cl4_eb.clone()),
     );
-    let mut db = MemoryDb::default();
+    let mut db = new_db();
     for cl in [cl1, cl2, cl3, cl4] {
         db.insert_compact_leaf(cl).unwrap();
     }
@@ -0,0 +1,258 @@
+//! Compact Merkle Sum Sparse Merkle Tree over an asynchronous database
+
+use std::marker::PhantomData;
+
+use crate::{
+    db::{AsyncDb, WriteBatch},
+    node::{Branch, CompactLeaf, Hasher, Leaf, Node},
+    EmptyLeaf, Proof, ThreadSafe, TreeError,
+};
+
+use super::{
+    async_regular::root_node,
+    compact::{merge_leaves, step_order},
+    view::resolve,
+};
+
+/// Compact merkle sum sparse merkle tree stored in an [`AsyncDb`].
+///
+/// It has the same layout and the same semantics as [`CompactMSSMT`](crate::CompactMSSMT),
+/// the nodes being read and written asynchronously.
+pub struct AsyncCompactMSSMT<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, D> {
+    db: D,
+    _phantom: PhantomData<H>,
+}
+
+impl<
+        const HASH_SIZE: usize,
+        H: Hasher<HASH_SIZE> + Clone + ThreadSafe,
+        D: AsyncDb<HASH_SIZE, H>,
+    > AsyncCompactMSSMT<HASH_SIZE, H, D>
+{
+    /// Creates a new compact mssmt stored in `db`.
+    pub fn new(db: D) -> Self {
+        Self {
+            db,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn db(&self) -> &D {
+        &self.db
+    }
+
+    /// Max height of the tree
+    pub const fn max_levels() -> usize {
+        HASH_SIZE * 8
+    }
+
+    /// Root node of the tree.
+    pub async fn root(&self) -> Result<Branch<HASH_SIZE, H>, TreeError<D::DbError>> {
+        Ok(root_node(&self.db).await)
+    }
+
+    /// Walks down the path of `key` from the root until a compacted leaf, an empty subtree or
+    /// the leaf level, and returns the height of the node it stopped at with the node. The
+    /// siblings on the path are pushed from the top to `siblings`, and the hashes of their
+    /// parents to `parents`.
+    async fn descend(
+        &self,
+        key: &[u8; HASH_SIZE],
+        siblings: &mut Vec<Node<HASH_SIZE, H>>,
+        parents: &mut Vec<[u8; HASH_SIZE]>,
+    ) -> Result<(usize, Node<HASH_SIZE, H>), TreeError<D::DbError>> {
+        let empty_tree = self.db.empty_tree();
+        let mut current = Node::Branch(self.root().await?);
+        for i in 0..Self::max_levels() {
+            let (left, right) = self.db.get_children(i, current.hash()).await?;
+            let (next, sibling) = step_order(i, key, left, right);
+            siblings.push(sibling);
+            parents.push(current.hash());
+            if matches!(next, Node::Compact(_)) || next.hash() == empty_tree[i + 1].hash() {
+                return Ok((i + 1, next));
+            }
+            current = next;
+        }
+        match current {
+            Node::Leaf(_) => Ok((Self::max_levels(), current)),
+            _ => Err(TreeError::ExpectedLeaf),
+        }
+    }
+
+    /// Inserts a leaf node at the given key.
+    ///
+    /// Returns an error if inserting the leaf would cause the tree's sum to overflow.
+    /// All the database writes are applied in a single [`WriteBatch`], so the update either
+    /// fully lands or leaves the previous root intact.
+    pub async fn insert(
+        &mut self,
+        key: &[u8; HASH_SIZE],
+        leaf: Leaf<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<D::DbError>> {
+        if leaf.sum().checked_add(self.root().await?.sum()).is_none() {
+            return Err(TreeError::SumOverflow);
+        }
+        let empty_tree = self.db.empty_tree();
+        let mut siblings = Vec::with_capacity(Self::max_levels());
+        let mut parents = Vec::with_capacity(Self::max_levels());
+        let (height, node) = self.descend(key, &mut siblings, &mut parents).await?;
+
+        let mut batch = WriteBatch::new();
+        let is_empty = matches!(leaf, Leaf::Empty(_));
+        let mut node = match node {
+            Node::Compact(compact) if compact.key() == key || !is_empty => {
+                batch.delete_leaf(&compact.leaf().hash());
+                batch.delete_compact_leaf(&compact.hash());
+                if compact.key() != key {
+                    // Merge the two leaves into a subtree.
+                    Node::Branch(merge_leaves(
+                        &empty_tree,
+                        &mut batch,
+                        height,
+                        *key,
+                        leaf,
+                        *compact.key(),
+                        compact.leaf().clone(),
+                    ))
+                } else if is_empty {
+                    empty_tree[height].clone()
+                } else {
+                    let new_leaf = CompactLeaf::new(height, *key, leaf.clone(), empty_tree.clone());
+                    batch.insert_leaf(leaf);
+                    batch.insert_compact_leaf(new_leaf.clone());
+                    Node::Compact(new_leaf)
+                }
+            }
+            Node::Branch(_) | Node::Computed(_) if !is_empty => {
+                let new_leaf = CompactLeaf::new(height, *key, leaf.clone(), empty_tree.clone());
+                batch.insert_leaf(leaf);
+                batch.insert_compact_leaf(new_leaf.clone());
+                Node::Compact(new_leaf)
+            }
+            // Deleting a key that isn't stored leaves the tree unchanged.
+            Node::Compact(_) | Node::Branch(_) | Node::Computed(_) => return Ok(()),
+            node => return Err(TreeError::expected_branch(height, &node.hash())),
+        };
+
+        // Walk back up, replacing the branches on the path.
+        for height in (0..siblings.len()).rev() {
+            if parents[height] != empty_tree[height].hash() {
+                batch.delete_branch(&parents[height]);
+            }
+            let (left, right) = step_order(height, key, node, siblings[height].clone());
+            let branch = Branch::new(left, right);
+            if branch.hash() != empty_tree[height].hash() {
+                batch.insert_branch(branch.clone());
+            }
+            node = Node::Branch(branch);
+        }
+        let root = match node {
+            Node::Branch(root) => root,
+            node => return Err(TreeError::expected_branch(0, &node.hash())),
+        };
+        batch.update_root(root);
+        self.db.write_batch(batch).await
+    }
+
+    /// Deletes the leaf stored at `key`.
+    pub async fn delete(&mut self, key: &[u8; HASH_SIZE]) -> Result<(), TreeError<D::DbError>> {
+        self.insert(key, Leaf::Empty(EmptyLeaf::new())).await
+    }
+
+    /// Returns the leaf stored at `key`, or an empty leaf if there is none.
+    ///
+    /// The walk stops at the first compacted leaf or empty subtree on the path, the branches
+    /// below it are never rebuilt.
+    pub async fn get(
+        &self,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<Leaf<HASH_SIZE, H>, TreeError<D::DbError>> {
+        let (height, node) = self.descend(key, &mut Vec::new(), &mut Vec::new()).await?;
+        resolve(&self.db.empty_tree(), height, node, key, None)
+    }
+
+    /// Returns the merkle proof for the given key.
+    ///
+    /// Below a compacted leaf or an empty subtree the siblings are empty subtrees, except for
+    /// the subtree of a compacted leaf whose key diverges from `key`, which is hashed without
+    /// rebuilding its branches.
+    pub async fn merkle_proof(
+        &self,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<Proof<HASH_SIZE, H>, TreeError<D::DbError>> {
+        let mut proof = Vec::with_capacity(Self::max_levels());
+        let (height, node) = self.descend(key, &mut proof, &mut Vec::new()).await?;
+        resolve(&self.db.empty_tree(), height, node, key, Some(&mut proof))?;
+        proof.reverse();
+        Ok(Proof::new(proof))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::Sha256;
+
+    use super::AsyncCompactMSSMT;
+    use crate::{tests::block_on, BlockingDb, CompactMSSMT, Leaf, MemoryDb, TreeError};
+
+    #[test]
+    fn test_async_compact_tree() {
+        let mut tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut async_tree = AsyncCompactMSSMT::new(BlockingDb::new(MemoryDb::<32, Sha256>::new()));
+        block_on(async {
+            for i in 0..10u8 {
+                let key = [i.wrapping_mul(37); 32];
+                let leaf = Leaf::new(vec![i], i as u64 + 1);
+                tree.insert(&key, leaf.clone()).unwrap();
+                async_tree.insert(&key, leaf).await.unwrap();
+                if i % 3 == 0 {
+                    let key = [(i / 2).wrapping_mul(37); 32];
+                    tree.delete(&key).unwrap();
+                    async_tree.delete(&key).await.unwrap();
+                }
+                let root = tree.root().unwrap();
+                assert_eq!(async_tree.root().await.unwrap().hash(), root.hash());
+                for j in 0..10u8 {
+                    let key = [j.wrapping_mul(37); 32];
+                    let leaf = tree.get(&key).unwrap();
+                    assert_eq!(async_tree.get(&key).await.unwrap().hash(), leaf.hash());
+                    let proof = async_tree.merkle_proof(&key).await.unwrap();
+                    assert_eq!(
+                        proof.compress().encode(),
+                        tree.merkle_proof(&key).unwrap().compress().encode()
+                    );
+                    proof
+                        .verify_merkle_proof::<()>(&key, leaf, root.hash())
+                        .unwrap();
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_async_compact_tree_delete_absent() {
+        let mut tree = AsyncCompactMSSMT::new(BlockingDb::new(MemoryDb::<32, Sha256>::new()));
+        block_on(async {
+            tree.insert(&[1; 32], Leaf::new(vec![1], 1)).await.unwrap();
+            let root = tree.root().await.unwrap();
+            let stored = tree.db().inner().get_branches().len();
+            // Neither the empty subtree nor the compacted leaf on the path are touched.
+            tree.delete(&[2; 32]).await.unwrap();
+            tree.delete(&[3; 32]).await.unwrap();
+            assert_eq!(tree.root().await.unwrap().hash(), root.hash());
+            assert_eq!(tree.db().inner().get_branches().len(), stored);
+            assert_eq!(tree.db().inner().get_compact_leaves().len(), 1);
+
+            tree.delete(&[1; 32]).await.unwrap();
+            assert!(matches!(tree.get(&[1; 32]).await.unwrap(), Leaf::Empty(_)));
+            assert!(tree.db().inner().get_compact_leaves().is_empty());
+            assert_eq!(
+                tree.insert(&[1; 32], Leaf::new(vec![1], u64::MAX))
+                    .await
+                    .and(tree.insert(&[2; 32], Leaf::new(vec![2], 1)).await)
+                    .unwrap_err(),
+                TreeError::SumOverflow
+            );
+        });
+    }
+}
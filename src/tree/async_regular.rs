@@ -0,0 +1,236 @@
+//! Merkle Sum Sparse Merkle Tree over an asynchronous database
+
+use std::{marker::PhantomData, sync::Arc};
+
+use crate::{
+    db::AsyncDb,
+    node::{Branch, Hasher, Leaf, Node},
+    EmptyLeaf, Proof, ThreadSafe, TreeError,
+};
+
+use super::{bit_index, path_update};
+
+/// Merkle sum sparse merkle tree stored in an [`AsyncDb`].
+///
+/// It has the same layout and the same semantics as [`MSSMT`](crate::MSSMT), the nodes being
+/// read and written asynchronously.
+pub struct AsyncMSSMT<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, D> {
+    db: D,
+    _phantom: PhantomData<H>,
+}
+
+/// Root node stored in `db`, the empty tree root if there is none.
+pub(super) async fn root_node<
+    const HASH_SIZE: usize,
+    H: Hasher<HASH_SIZE> + Clone + ThreadSafe,
+    D: AsyncDb<HASH_SIZE, H>,
+>(
+    db: &D,
+) -> Branch<HASH_SIZE, H> {
+    match db.get_root_node().await {
+        Some(branch) => branch,
+        None => {
+            let Node::Branch(branch) = db.empty_tree().as_ref()[0].clone() else {
+                unreachable!("Invalid empty tree. The root node should always be a branch.");
+            };
+            branch
+        }
+    }
+}
+
+impl<
+        const HASH_SIZE: usize,
+        H: Hasher<HASH_SIZE> + Clone + ThreadSafe,
+        D: AsyncDb<HASH_SIZE, H>,
+    > AsyncMSSMT<HASH_SIZE, H, D>
+{
+    /// Creates a new mssmt stored in `db`.
+    pub fn new(db: D) -> Self {
+        Self {
+            db,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn db(&self) -> &D {
+        &self.db
+    }
+
+    /// Max height of the tree
+    pub const fn max_levels() -> usize {
+        HASH_SIZE * 8
+    }
+
+    /// Root node of the tree.
+    pub async fn root(&self) -> Result<Branch<HASH_SIZE, H>, TreeError<D::DbError>> {
+        Ok(root_node(&self.db).await)
+    }
+
+    /// Walks down the tree from the root node to the leaf of `key` and returns the leaf with
+    /// the siblings and the parents on the path, from the top.
+    #[allow(clippy::type_complexity)]
+    async fn walk_down(
+        &self,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<
+        (
+            Leaf<HASH_SIZE, H>,
+            Vec<Node<HASH_SIZE, H>>,
+            Vec<[u8; HASH_SIZE]>,
+        ),
+        TreeError<D::DbError>,
+    > {
+        let mut siblings = Vec::with_capacity(Self::max_levels());
+        let mut parents = Vec::with_capacity(Self::max_levels());
+        let mut current = Node::Branch(self.root().await?);
+        for i in 0..Self::max_levels() {
+            let (left, right) = self.db.get_children(i, current.hash()).await?;
+            let (next, sibling) = if bit_index(i, key) == 0 {
+                (left, right)
+            } else {
+                (right, left)
+            };
+            siblings.push(sibling);
+            parents.push(current.hash());
+            current = next;
+        }
+        let Node::Leaf(leaf) = current else {
+            return Err(TreeError::ExpectedLeaf);
+        };
+        Ok((leaf, siblings, parents))
+    }
+
+    /// Insert a leaf in the tree.
+    /// All the database writes are applied in a single [`WriteBatch`](crate::WriteBatch), so
+    /// the update either fully lands or leaves the previous root intact.
+    pub async fn insert(
+        &mut self,
+        key: &[u8; HASH_SIZE],
+        leaf: Leaf<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<D::DbError>> {
+        if leaf.sum().checked_add(self.root().await?.sum()).is_none() {
+            return Err(TreeError::SumOverflow);
+        }
+        let (_, siblings, mut prev_parents) = self.walk_down(key).await?;
+        prev_parents.reverse();
+        let siblings = siblings.into_iter().rev().map(Arc::new).collect::<Vec<_>>();
+
+        let batch = path_update(&self.db.empty_tree(), key, leaf, &siblings, &prev_parents)?;
+        self.db.write_batch(batch).await
+    }
+
+    pub async fn delete(&mut self, key: &[u8; HASH_SIZE]) -> Result<(), TreeError<D::DbError>> {
+        self.insert(key, Leaf::Empty(EmptyLeaf::new())).await
+    }
+
+    /// Returns the leaf stored at `key`, an empty leaf if there is none.
+    pub async fn get(
+        &self,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<Leaf<HASH_SIZE, H>, TreeError<D::DbError>> {
+        Ok(self.walk_down(key).await?.0)
+    }
+
+    pub async fn merkle_proof(
+        &self,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<Proof<HASH_SIZE, H>, TreeError<D::DbError>> {
+        let (_, mut proof, _) = self.walk_down(key).await?;
+        proof.reverse();
+        Ok(Proof::new(proof))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::Sha256;
+
+    use super::AsyncMSSMT;
+    use crate::{tests::block_on, BlockingDb, Leaf, MemoryDb, TreeError, MSSMT};
+
+    #[test]
+    fn test_async_tree() {
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut async_tree = AsyncMSSMT::new(BlockingDb::new(MemoryDb::<32, Sha256>::new()));
+        block_on(async {
+            for i in 0..10u8 {
+                let key = [i.wrapping_mul(37); 32];
+                let leaf = Leaf::new(vec![i], i as u64 + 1);
+                tree.insert(&key, leaf.clone()).unwrap();
+                async_tree.insert(&key, leaf).await.unwrap();
+                if i % 3 == 0 {
+                    let key = [(i / 2).wrapping_mul(37); 32];
+                    tree.delete(&key).unwrap();
+                    async_tree.delete(&key).await.unwrap();
+                }
+                let root = tree.root().unwrap();
+                assert_eq!(async_tree.root().await.unwrap().hash(), root.hash());
+                for j in 0..10u8 {
+                    let key = [j.wrapping_mul(37); 32];
+                    let leaf = tree.get(&key).unwrap();
+                    assert_eq!(async_tree.get(&key).await.unwrap().hash(), leaf.hash());
+                    let proof = async_tree.merkle_proof(&key).await.unwrap();
+                    assert_eq!(
+                        proof.compress().encode(),
+                        tree.merkle_proof(&key).unwrap().compress().encode()
+                    );
+                    proof
+                        .verify_merkle_proof::<()>(&key, leaf, root.hash())
+                        .unwrap();
+                }
+            }
+            // Both trees store the same nodes.
+            let stored = async_tree.db().inner().get_branches();
+            let memory_db = tree
+                .db()
+                .as_any()
+                .downcast_ref::<MemoryDb<32, Sha256>>()
+                .unwrap();
+            assert_eq!(stored.len(), memory_db.get_branches().len());
+            assert!(stored
+                .keys()
+                .all(|key| memory_db.get_branches().contains_key(key)));
+        });
+    }
+
+    #[test]
+    fn test_async_tree_sum_overflow() {
+        let mut tree = AsyncMSSMT::new(BlockingDb::new(MemoryDb::<32, Sha256>::new()));
+        block_on(async {
+            tree.insert(&[1; 32], Leaf::new(vec![1], u64::MAX))
+                .await
+                .unwrap();
+            assert_eq!(
+                tree.insert(&[2; 32], Leaf::new(vec![2], 1))
+                    .await
+                    .unwrap_err(),
+                TreeError::SumOverflow
+            );
+            assert_eq!(tree.root().await.unwrap().sum(), u64::MAX);
+        });
+    }
+
+    #[cfg(feature = "multi-thread")]
+    #[test]
+    fn test_async_tree_send() {
+        fn assert_send<T: Send>(_: T) {}
+        let mut tree = AsyncMSSMT::new(BlockingDb::new(MemoryDb::<32, Sha256>::new()));
+        assert_send(tree.get(&[1; 32]));
+        assert_send(tree.merkle_proof(&[1; 32]));
+        assert_send(tree.insert(&[1; 32], Leaf::new(vec![1], 1)));
+    }
+
+    /// Only compiles if the futures of any [`AsyncDb`](crate::AsyncDb) are `Send`.
+    #[cfg(feature = "multi-thread")]
+    #[allow(dead_code)]
+    fn assert_generic_send<D: crate::AsyncDb<32, Sha256>>(
+        tree: &mut AsyncMSSMT<32, Sha256, D>,
+        compact_tree: &mut crate::AsyncCompactMSSMT<32, Sha256, D>,
+    ) {
+        fn assert_send<T: Send>(_: T) {}
+        assert_send(tree.insert(&[1; 32], Leaf::new(vec![1], 1)));
+        assert_send(tree.merkle_proof(&[1; 32]));
+        assert_send(compact_tree.insert(&[1; 32], Leaf::new(vec![1], 1)));
+        assert_send(compact_tree.merkle_proof(&[1; 32]));
+    }
+}
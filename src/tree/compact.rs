@@ -4,14 +4,28 @@
 //! Instead of storing all intermediate branch nodes, it stores just the leaf and its path information.
 //! This significantly reduces the storage requirements while maintaining the same cryptographic properties.
 
-use std::marker::PhantomData;
+use std::{marker::PhantomData, sync::Arc};
 
 use crate::{
     node::{Branch, CompactLeaf, Hasher, Leaf, Node},
-    Db, EmptyLeaf, Proof, TreeError,
+    CompactExclusionProof, Db, EmptyLeaf, MultiProof, Proof, RangeSumProof, ThreadSafe,
+    TransitionProof, TransitionStep, TreeError, WriteBatch,
 };
 
-use super::regular::bit_index;
+use super::{
+    checked_branch,
+    diff::TreeDiff,
+    integrity::{verify_integrity, IntegrityReport},
+    iter::LeafIter,
+    join,
+    multi::multi_proof,
+    parallel_split, path_cmp,
+    prune::{prune, PruneStats},
+    range::{find_by_cumulative_sum, range_sum, range_sum_proof},
+    regular::bit_index,
+    sort_batch, split_batch,
+    view::{descend, lookup, TreeView},
+};
 
 /// A compact Merkle Sum Sparse Merkle Tree implementation.
 ///
@@ -25,6 +39,9 @@ use super::regular::bit_index;
 pub struct CompactMSSMT<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError> {
     /// The database backend for storing tree nodes
     db: Box<dyn Db<HASH_SIZE, H, DbError = DbError>>,
+    /// Committed roots indexed by their version, `None` if the tree isn't versioned and
+    /// for the pruned versions
+    versions: Option<Vec<Option<Branch<HASH_SIZE, H>>>>,
     /// PhantomData for the hash function type
     _phantom: PhantomData<H>,
 }
@@ -36,10 +53,26 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
     pub fn new(db: Box<dyn Db<HASH_SIZE, H, DbError = DbError>>) -> Self {
         Self {
             db,
+            versions: None,
             _phantom: PhantomData,
         }
     }
 
+    /// Creates a new versioned compact MS-SMT with the given database backend.
+    ///
+    /// Each committed root gets a version number, starting with `0` for the current root of
+    /// `db`, and the replaced nodes are kept in the database so that every version stays
+    /// readable with [`at_version`](Self::at_version).
+    ///
+    /// The version numbers are only kept in memory and don't survive a restart: reopening `db`
+    /// numbers its current root as version `0` again. The nodes of the older roots stay in the
+    /// database but can't be read through the tree anymore.
+    pub fn new_versioned(db: Box<dyn Db<HASH_SIZE, H, DbError = DbError>>) -> Self {
+        let mut tree = Self::new(db);
+        tree.versions = Some(vec![Some(tree.root_node())]);
+        tree
+    }
+
     /// Returns the maximum number of levels in the tree (HASH_SIZE * 8)
     pub fn max_levels() -> usize {
         HASH_SIZE * 8
@@ -54,14 +87,114 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
     ///
     /// If the tree is empty, returns the default empty root node.
     pub fn root(&self) -> Result<Branch<HASH_SIZE, H>, TreeError<DbError>> {
+        Ok(self.root_node())
+    }
+
+    /// Returns the root node stored in the database, the empty tree root if there is none.
+    fn root_node(&self) -> Branch<HASH_SIZE, H> {
         if let Some(branch) = self.db.get_root_node() {
-            Ok(branch)
+            branch
         } else {
             let Node::Branch(branch) = self.db.empty_tree().as_ref()[0].clone() else {
                 unreachable!("Invalid empty tree. The root node should always be a branch.");
             };
-            Ok(branch)
+            branch
+        }
+    }
+
+    /// Returns the version of the current root, `None` if the tree isn't versioned.
+    pub fn version(&self) -> Option<u64> {
+        self.versions
+            .as_ref()
+            .map(|versions| versions.len() as u64 - 1)
+    }
+
+    /// Returns a read-only view of the tree at the root committed as `version`.
+    ///
+    /// Returns [`TreeError::UnknownRoot`] if the tree isn't versioned, or if `version` wasn't
+    /// committed or was pruned.
+    pub fn at_version(
+        &self,
+        version: u64,
+    ) -> Result<TreeView<'_, HASH_SIZE, H, DbError>, TreeError<DbError>> {
+        let root = usize::try_from(version)
+            .ok()
+            .and_then(|version| self.versions.as_ref()?.get(version)?.as_ref())
+            .ok_or(TreeError::UnknownRoot)?;
+        Ok(TreeView::new(self.db.as_ref(), root.clone()))
+    }
+
+    /// Returns a read-only view of the tree at the root whose hash is `root_hash`, either the current
+    /// root or a committed version.
+    ///
+    /// Returns [`TreeError::UnknownRoot`] if there is no such root.
+    pub fn at_root(
+        &self,
+        root_hash: [u8; HASH_SIZE],
+    ) -> Result<TreeView<'_, HASH_SIZE, H, DbError>, TreeError<DbError>> {
+        let current = self.root_node();
+        let root = if current.hash() == root_hash {
+            current
+        } else {
+            self.versions
+                .iter()
+                .flatten()
+                .flatten()
+                .find(|root| root.hash() == root_hash)
+                .ok_or(TreeError::UnknownRoot)?
+                .clone()
+        };
+        Ok(TreeView::new(self.db.as_ref(), root))
+    }
+
+    /// Deletes every node of the database that isn't reachable from the current root or one
+    /// of `keep_roots` and returns how many nodes were deleted and how many bytes the database
+    /// reclaimed.
+    ///
+    /// The versions of a versioned tree whose roots aren't kept can't be read anymore. Returns
+    /// [`TreeError::Unsupported`] if the database can't list its nodes.
+    pub fn prune(
+        &mut self,
+        keep_roots: &[[u8; HASH_SIZE]],
+    ) -> Result<PruneStats, TreeError<DbError>> {
+        let mut roots = keep_roots.to_vec();
+        roots.push(self.root_node().hash());
+        let stats = prune(self.db.as_mut(), &roots)?;
+        for version in self.versions.iter_mut().flatten() {
+            if version
+                .as_ref()
+                .is_some_and(|root| !roots.contains(&root.hash()))
+            {
+                *version = None;
+            }
         }
+        Ok(stats)
+    }
+
+    /// Checks every node reachable from the current root and the committed versions, and
+    /// lists the stored nodes that none of them reaches.
+    ///
+    /// Branch hashes and sums are recomputed from their children, leaf hashes from their
+    /// value and sum, and compact leaf hashes from their key and height. Returns
+    /// [`TreeError::Unsupported`] if the database can't list its nodes.
+    pub fn verify_integrity(&self) -> Result<IntegrityReport<HASH_SIZE>, TreeError<DbError>> {
+        let mut roots = vec![self.root_node()];
+        roots.extend(self.versions.iter().flatten().flatten().cloned());
+        verify_integrity(self.db.as_ref(), &roots)
+    }
+
+    /// Applies `batch` to the database. A versioned tree keeps the replaced nodes and records
+    /// the new root as the next version.
+    fn commit(&mut self, mut batch: WriteBatch<HASH_SIZE, H>) -> Result<(), TreeError<DbError>> {
+        if self.versions.is_some() {
+            batch.retain_insertions();
+        }
+        self.db.write_batch(batch)?;
+        let root = self.root_node();
+        if let Some(versions) = &mut self.versions {
+            versions.push(Some(root));
+        }
+        Ok(())
     }
 
     /// Walks down the tree following the given path, calling the provided function at each level.
@@ -89,7 +222,7 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
             // Get the children of the current node
             let (left, right) = self.db.get_children(i, current.hash())?;
             // Order the children based on the path
-            let (mut next, mut sibling) = Self::step_order(i, path, left, right);
+            let (mut next, mut sibling) = step_order(i, path, left, right);
             match next {
                 // If the next node is a compact leaf we can extract the branches
                 // on the path to the leaf.
@@ -116,10 +249,12 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
                             // continue walking down.
                             let branch = match &current {
                                 Node::Branch(b) => b,
-                                _ => return Err(TreeError::ExpectedBranch),
+                                node => {
+                                    return Err(TreeError::expected_branch(j + 1, &node.hash()))
+                                }
                             };
                             // Get the next and sibling nodes
-                            let (n, s) = Self::step_order(
+                            let (n, s) = step_order(
                                 j + 1,
                                 path,
                                 branch.left().clone(),
@@ -162,6 +297,8 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
     /// * `key2` - The key of the second leaf  
     /// * `leaf2` - The second leaf node
     ///
+    /// The new nodes are written to the database in a single [`WriteBatch`].
+    ///
     /// # Returns
     ///
     /// Returns a branch node that is the root of the merged subtree
@@ -173,41 +310,17 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
         key2: [u8; HASH_SIZE],
         leaf2: Leaf<HASH_SIZE, H>,
     ) -> Result<Branch<HASH_SIZE, H>, TreeError<DbError>> {
-        // Find the common prefix first
-        let mut i = 0;
-        // As long as the key bits are the same we can continue
-        while i < Self::max_levels() && bit_index(i, &key1) == bit_index(i, &key2) {
-            i += 1;
-        }
-
-        // Now we create two compacted leaves and insert them as children of
-        // a newly created branch
-        let node1 = CompactLeaf::new(i + 1, key1, leaf1.clone(), self.db.empty_tree());
-        let node2 = CompactLeaf::new(i + 1, key2, leaf2.clone(), self.db.empty_tree());
-        // Insert the leaves into the database. This is not strictly necessary but it's useful
-        // If we want to avoid inserting the same leaf twice.
-        self.db.insert_leaf(leaf1)?;
-        self.db.insert_leaf(leaf2)?;
-        // Insert the compacted leaves into the database
-        self.db.insert_compact_leaf(node1.clone())?;
-        self.db.insert_compact_leaf(node2.clone())?;
-        let (left, right) = Self::step_order(i, &key1, Node::Compact(node1), Node::Compact(node2));
-        let mut parent = Branch::new(left, right);
-        self.db.insert_branch(parent.clone())?;
-
-        // From here we'll walk up to the current level and create branches
-        // along the way.
-        for i in (height..i).rev() {
-            let (left, right) = Self::step_order(
-                i,
-                &key1,
-                Node::Branch(parent),
-                self.db.empty_tree()[i + 1].clone(),
-            );
-            parent = Branch::new(left, right);
-            self.db.insert_branch(parent.clone())?;
-        }
-
+        let mut batch = WriteBatch::new();
+        let parent = merge_leaves(
+            &self.db.empty_tree(),
+            &mut batch,
+            height,
+            key1,
+            leaf1,
+            key2,
+            leaf2,
+        );
+        self.db.write_batch(batch)?;
         Ok(parent)
     }
 
@@ -217,8 +330,11 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
     /// 1. Inserting into an empty subtree (creates a new compact leaf)
     /// 2. Replacing an existing leaf at the same key
     /// 3. Merging with an existing leaf at a different key (creates a new subtree)
+    ///
+    /// The database isn't modified, all the writes are queued in `batch`.
     fn insert_leaf(
-        &mut self,
+        &self,
+        batch: &mut WriteBatch<HASH_SIZE, H>,
         key: &[u8; HASH_SIZE],
         height: usize,
         root_hash: &[u8; HASH_SIZE],
@@ -244,19 +360,19 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
                     // subtree then replace it with a compacted leaf.
                     let new_leaf =
                         CompactLeaf::new(next_height, *key, leaf.clone(), self.db.empty_tree());
-                    self.db.insert_leaf(leaf)?;
-                    self.db.insert_compact_leaf(new_leaf.clone())?;
+                    batch.insert_leaf(leaf);
+                    batch.insert_compact_leaf(new_leaf.clone());
                     Node::Compact(new_leaf)
                 } else {
                     // Not an empty subtree, recurse down the tree to find
                     // the insertion point for the leaf.
-                    Node::Branch(self.insert_leaf(key, next_height, &node.hash(), leaf)?)
+                    Node::Branch(self.insert_leaf(batch, key, next_height, &node.hash(), leaf)?)
                 }
             }
             Node::Compact(node) => {
                 // First delete the old leaf.
-                self.db.delete_leaf(&node.leaf().hash())?;
-                self.db.delete_compact_leaf(&node.hash())?;
+                batch.delete_leaf(&node.leaf().hash());
+                batch.delete_compact_leaf(&node.hash());
 
                 if *key == *node.key() {
                     // Replace of an existing leaf.
@@ -272,19 +388,21 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
                     } else {
                         let new_leaf =
                             CompactLeaf::new(next_height, *key, leaf.clone(), self.db.empty_tree());
-                        self.db.insert_leaf(leaf)?;
-                        self.db.insert_compact_leaf(new_leaf.clone())?;
+                        batch.insert_leaf(leaf);
+                        batch.insert_compact_leaf(new_leaf.clone());
                         Node::Compact(new_leaf)
                     }
                 } else {
                     // Merge the two leaves into a subtree.
-                    Node::Branch(self.merge(
+                    Node::Branch(merge_leaves(
+                        &self.db.empty_tree(),
+                        batch,
                         next_height,
                         *key,
                         leaf,
                         *node.key(),
                         node.leaf().clone(),
-                    )?)
+                    ))
                 }
             }
             Node::Computed(node) => {
@@ -294,20 +412,20 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
                     // subtree then replace it with a compacted leaf.
                     let new_leaf =
                         CompactLeaf::new(next_height, *key, leaf.clone(), self.db.empty_tree());
-                    self.db.insert_leaf(leaf)?;
-                    self.db.insert_compact_leaf(new_leaf.clone())?;
+                    batch.insert_leaf(leaf);
+                    batch.insert_compact_leaf(new_leaf.clone());
                     Node::Compact(new_leaf)
                 } else {
                     // Not an empty subtree, recurse down the tree to find
                     // the insertion point for the leaf.
-                    Node::Branch(self.insert_leaf(key, next_height, &node.hash(), leaf)?)
+                    Node::Branch(self.insert_leaf(batch, key, next_height, &node.hash(), leaf)?)
                 }
             }
-            _ => return Err(TreeError::ExpectedBranch),
+            next => return Err(TreeError::expected_branch(next_height, &next.hash())),
         };
         // Delete the old root if not empty
         if *root_hash != self.db.empty_tree()[height].hash() {
-            self.db.delete_branch(root_hash)?;
+            batch.delete_branch(root_hash);
         }
         // Create the new root
         let branch = if is_left {
@@ -318,7 +436,7 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
 
         // Only insert this new branch if not a default one
         if branch.hash() != self.db.empty_tree()[height].hash() {
-            self.db.insert_branch(branch.clone())?;
+            batch.insert_branch(branch.clone());
         }
 
         Ok(branch)
@@ -333,7 +451,9 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
     ///
     /// # Returns
     ///
-    /// Returns an error if inserting the leaf would cause the tree's sum to overflow
+    /// Returns an error if inserting the leaf would cause the tree's sum to overflow.
+    /// All the database writes are applied in a single [`WriteBatch`], so the update either
+    /// fully lands or leaves the previous root intact.
     pub fn insert(
         &mut self,
         key: &[u8; HASH_SIZE],
@@ -357,32 +477,285 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
             return Err(TreeError::SumOverflow);
         }
 
-        let new_root = self.insert_leaf(key, 0, &root.hash(), leaf)?;
-        self.db.update_root(new_root)
+        let mut batch = WriteBatch::new();
+        let new_root = self.insert_leaf(&mut batch, key, 0, &root.hash(), leaf)?;
+        batch.update_root(new_root);
+        self.commit(batch)
+    }
+
+    /// Applies the `updates` in order and returns the [`TransitionProof`] from the previous
+    /// root to the new one. Empty leaves delete their key.
+    ///
+    /// Stops at the first update that fails, the previous ones staying applied.
+    #[allow(clippy::type_complexity)]
+    pub fn apply_transition(
+        &mut self,
+        updates: &[([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)],
+    ) -> Result<TransitionProof<HASH_SIZE, H>, TreeError<DbError>> {
+        let mut transition = TransitionProof::new(Vec::with_capacity(updates.len()));
+        for (key, leaf) in updates {
+            let (old_leaf, proof) = self.get_with_proof(key)?;
+            self.insert(key, leaf.clone())?;
+            transition.push(TransitionStep::new(*key, old_leaf, leaf.clone(), proof));
+        }
+        Ok(transition)
     }
 
     pub fn delete(&mut self, key: &[u8; HASH_SIZE]) -> Result<(), TreeError<DbError>> {
         let root = self.root()?;
-        let new_root = self.insert_leaf(key, 0, &root.hash(), Leaf::Empty(EmptyLeaf::new()))?;
-        self.db.update_root(new_root)?;
-        Ok(())
+        let mut batch = WriteBatch::new();
+        let new_root = self.insert_leaf(
+            &mut batch,
+            key,
+            0,
+            &root.hash(),
+            Leaf::Empty(EmptyLeaf::new()),
+        )?;
+        batch.update_root(new_root);
+        self.commit(batch)
     }
 
-    /// Helper function to order nodes based on a key bit at the given height.
+    /// Inserts several leaves in the tree at once.
+    ///
+    /// The keys are sorted so that leaves sharing a path prefix share the walk down, and each
+    /// touched branch is hashed only once. The resulting root is the same as inserting the
+    /// leaves one by one. If a key appears several times, the last leaf wins.
+    /// All the database writes are applied in a single [`WriteBatch`].
+    ///
+    /// # Arguments
     ///
-    /// Returns the nodes in (next, sibling) order based on whether the key bit is 0 or 1.
-    #[inline]
-    fn step_order(
+    /// * `leaves` - The `(key, leaf)` pairs to insert. Empty leaves delete their key.
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if inserting the leaves would cause the tree's sum to overflow
+    ///
+    /// With the `multi-thread` feature, the subtrees of large batches are built concurrently
+    /// on scoped threads.
+    #[allow(clippy::type_complexity)]
+    pub fn insert_batch(
+        &mut self,
+        leaves: &[([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)],
+    ) -> Result<(), TreeError<DbError>>
+    where
+        H: ThreadSafe,
+        DbError: ThreadSafe,
+    {
+        let entries = sort_batch(leaves);
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::new();
+        let root =
+            match self.insert_batch_at(&mut batch, 0, Node::Branch(self.root()?), &entries)? {
+                Node::Branch(root) => root,
+                node => return Err(TreeError::expected_branch(0, &node.hash())),
+            };
+        batch.update_root(root);
+        self.commit(batch)
+    }
+
+    /// Inserts the path-sorted `entries` in the subtree rooted at `node` and returns the new
+    /// subtree root. All the entries share the path of `node` up to `height`.
+    #[allow(clippy::type_complexity)]
+    fn insert_batch_at(
+        &self,
+        batch: &mut WriteBatch<HASH_SIZE, H>,
         height: usize,
-        key: &[u8; HASH_SIZE],
-        left: Node<HASH_SIZE, H>,
-        right: Node<HASH_SIZE, H>,
-    ) -> (Node<HASH_SIZE, H>, Node<HASH_SIZE, H>) {
-        if bit_index(height, key) == 0 {
-            (left, right)
+        node: Node<HASH_SIZE, H>,
+        entries: &[([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)],
+    ) -> Result<Node<HASH_SIZE, H>, TreeError<DbError>>
+    where
+        H: ThreadSafe,
+        DbError: ThreadSafe,
+    {
+        if entries.is_empty() {
+            return Ok(node);
+        }
+        // The root always stays a branch, even when it's empty.
+        if height > 0 && node.hash() == self.db.empty_tree()[height].hash() {
+            return self.build_subtree(batch, height, entries);
+        }
+        match node {
+            Node::Compact(compact) => {
+                // The compacted leaf is rebuilt along with the new ones unless it's replaced.
+                batch.delete_leaf(&compact.leaf().hash());
+                batch.delete_compact_leaf(&compact.hash());
+                let mut entries = entries.to_vec();
+                if let Err(index) =
+                    entries.binary_search_by(|(key, _)| path_cmp(key, compact.key()))
+                {
+                    entries.insert(index, (*compact.key(), compact.leaf().clone()));
+                }
+                self.build_subtree(batch, height, &entries)
+            }
+            Node::Branch(_) | Node::Computed(_) => {
+                let (left, right) = self.db.get_children(height, node.hash())?;
+                let (left_entries, right_entries) = split_batch(height, entries);
+                let (left, right) =
+                    if parallel_split(height, left_entries.len(), right_entries.len()) {
+                        // The right subtree writes to its own batch which is appended
+                        // afterwards to keep the same order as a sequential build.
+                        let mut right_batch = WriteBatch::new();
+                        let (left, right) = join(
+                            || self.insert_batch_at(batch, height + 1, left, left_entries),
+                            || {
+                                self.insert_batch_at(
+                                    &mut right_batch,
+                                    height + 1,
+                                    right,
+                                    right_entries,
+                                )
+                            },
+                        );
+                        batch.extend(right_batch);
+                        (left?, right?)
+                    } else {
+                        (
+                            self.insert_batch_at(batch, height + 1, left, left_entries)?,
+                            self.insert_batch_at(batch, height + 1, right, right_entries)?,
+                        )
+                    };
+                let branch = checked_branch(left, right)?;
+                let empty_hash = self.db.empty_tree()[height].hash();
+                if branch.hash() != node.hash() {
+                    if node.hash() != empty_hash {
+                        batch.delete_branch(&node.hash());
+                    }
+                    if branch.hash() != empty_hash {
+                        batch.insert_branch(branch.clone());
+                    }
+                }
+                Ok(Node::Branch(branch))
+            }
+            node => Err(TreeError::expected_branch(height, &node.hash())),
+        }
+    }
+
+    /// Builds a new subtree at `height` holding the path-sorted `entries`. Leaves that are
+    /// alone in their subtree are compacted. Empty leaves are skipped.
+    #[allow(clippy::type_complexity)]
+    fn build_subtree(
+        &self,
+        batch: &mut WriteBatch<HASH_SIZE, H>,
+        height: usize,
+        entries: &[([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)],
+    ) -> Result<Node<HASH_SIZE, H>, TreeError<DbError>>
+    where
+        H: ThreadSafe,
+        DbError: ThreadSafe,
+    {
+        let empty_leaf = self
+            .db
+            .empty_tree()
+            .last()
+            .expect("Empty tree should have a last element")
+            .hash();
+        let mut non_empty = entries.iter().filter(|(_, leaf)| leaf.hash() != empty_leaf);
+        match (non_empty.next(), non_empty.next()) {
+            (None, _) => return Ok(self.db.empty_tree()[height].clone()),
+            (Some((key, leaf)), None) if height > 0 => {
+                let compact = CompactLeaf::new(height, *key, leaf.clone(), self.db.empty_tree());
+                batch.insert_leaf(leaf.clone());
+                batch.insert_compact_leaf(compact.clone());
+                return Ok(Node::Compact(compact));
+            }
+            _ => {}
+        }
+        let (left_entries, right_entries) = split_batch(height, entries);
+        let (left, right) = if parallel_split(height, left_entries.len(), right_entries.len()) {
+            let mut right_batch = WriteBatch::new();
+            let (left, right) = join(
+                || self.build_subtree(batch, height + 1, left_entries),
+                || self.build_subtree(&mut right_batch, height + 1, right_entries),
+            );
+            batch.extend(right_batch);
+            (left?, right?)
         } else {
-            (right, left)
+            (
+                self.build_subtree(batch, height + 1, left_entries)?,
+                self.build_subtree(batch, height + 1, right_entries)?,
+            )
+        };
+        let branch = checked_branch(left, right)?;
+        if branch.hash() != self.db.empty_tree()[height].hash() {
+            batch.insert_branch(branch.clone());
         }
+        Ok(Node::Branch(branch))
+    }
+
+    /// Returns an iterator over all the non-empty leaves of the tree in ascending key order.
+    ///
+    /// Keys are read from the compacted leaves so the empty part of their paths is never
+    /// walked.
+    pub fn iter(&self) -> LeafIter<'_, HASH_SIZE, H, DbError> {
+        LeafIter::new(self.db.as_ref())
+    }
+
+    /// Iterate over the leaves that differ from the tree whose root hash is `root_a` to the one
+    /// whose root hash is `root_b`, both stored in the database of this tree.
+    /// See [`TreeDiff`] for how the trees are compared.
+    pub fn diff(
+        &self,
+        root_a: [u8; HASH_SIZE],
+        root_b: [u8; HASH_SIZE],
+    ) -> TreeDiff<'_, HASH_SIZE, H, DbError> {
+        TreeDiff::new(self.db.as_ref(), root_a, root_b)
+    }
+
+    /// Returns an iterator over the non-empty leaves whose keys are in `[start, end)`, keys
+    /// being compared as bytes. The leaves are yielded in ascending key order.
+    pub fn range(
+        &self,
+        start: &[u8; HASH_SIZE],
+        end: &[u8; HASH_SIZE],
+    ) -> LeafIter<'_, HASH_SIZE, H, DbError> {
+        LeafIter::new(self.db.as_ref()).with_range(*start, *end)
+    }
+
+    /// Returns the sum of the leaves whose keys are in `[start, end)`.
+    ///
+    /// Subtrees fully inside the range aren't walked, their sum is read from their root. The
+    /// path of a key starts with the low bits of its bytes, which don't order the keys, so on
+    /// the prefixes shared with `start` and `end` up to 255 branches per byte are fetched from
+    /// the database, instead of the 8 of a single path.
+    pub fn range_sum(
+        &self,
+        start: &[u8; HASH_SIZE],
+        end: &[u8; HASH_SIZE],
+    ) -> Result<u64, TreeError<DbError>> {
+        range_sum(self.db.as_ref(), Node::Branch(self.root()?), start, end)
+    }
+
+    /// Returns a proof that the leaves whose keys are in `[start, end)` sum to
+    /// [`range_sum`](Self::range_sum), without revealing them.
+    ///
+    /// Compact leaves on the boundary of the range are extracted to be split like regular
+    /// subtrees, so the proof is the same as for the regular layout.
+    pub fn range_sum_proof(
+        &self,
+        start: &[u8; HASH_SIZE],
+        end: &[u8; HASH_SIZE],
+    ) -> Result<RangeSumProof<HASH_SIZE, H>, TreeError<DbError>> {
+        range_sum_proof(self.db.as_ref(), Node::Branch(self.root()?), start, end)
+    }
+
+    /// Finds the leaf whose cumulative sum interval contains `target`, with its key and merkle
+    /// proof.
+    ///
+    /// Leaves are taken in tree path order, not in the key order of [`iter`](Self::iter), each
+    /// one covering `[before, before + sum)` where `before` is the sum of the leaves before it. The tree is
+    /// descended using the sums of the branches and stops at the first compacted leaf, so only
+    /// one path is read. Returns `None` if `target` isn't below the sum of the tree.
+    #[allow(clippy::type_complexity)]
+    pub fn find_by_cumulative_sum(
+        &self,
+        target: u64,
+    ) -> Result<
+        Option<([u8; HASH_SIZE], Leaf<HASH_SIZE, H>, Proof<HASH_SIZE, H>)>,
+        TreeError<DbError>,
+    > {
+        find_by_cumulative_sum(self.db.as_ref(), Node::Branch(self.root()?), target)
     }
 
     /// Returns the merkle proof for the given key.
@@ -407,12 +780,152 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
         proof.reverse();
         Ok(Proof::new(proof))
     }
+
+    /// Returns a merkle proof of all the `keys` at once, in which each sibling is stored once.
+    ///
+    /// The proof is verified with the leaves stored at the keys, empty leaves for the keys
+    /// that aren't in the tree. Compact leaves on the paths are extracted so the proof is the
+    /// same as for the regular layout.
+    pub fn multi_proof(
+        &self,
+        keys: &[[u8; HASH_SIZE]],
+    ) -> Result<MultiProof<HASH_SIZE, H>, TreeError<DbError>> {
+        multi_proof(self.db.as_ref(), Node::Branch(self.root()?), keys)
+    }
+
+    /// Returns the leaf stored at `key`, or an empty leaf if there is none.
+    ///
+    /// The walk stops at the first compacted leaf or empty subtree on the path, the branches
+    /// below it are never rebuilt.
+    pub fn get(&self, key: &[u8; HASH_SIZE]) -> Result<Leaf<HASH_SIZE, H>, TreeError<DbError>> {
+        lookup(self.db.as_ref(), self.root()?, key, None)
+    }
+
+    /// Returns `true` if a non-empty leaf is stored at `key`.
+    pub fn contains(&self, key: &[u8; HASH_SIZE]) -> Result<bool, TreeError<DbError>> {
+        Ok(matches!(self.get(key)?, Leaf::NonEmpty(_)))
+    }
+
+    /// Returns the leaf stored at `key`, or an empty leaf if there is none, with its merkle
+    /// proof.
+    ///
+    /// Below a compacted leaf or an empty subtree the siblings are empty subtrees, except for
+    /// the subtree of a compacted leaf whose key diverges from `key`, which is hashed without
+    /// rebuilding its branches.
+    pub fn get_with_proof(
+        &self,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<(Leaf<HASH_SIZE, H>, Proof<HASH_SIZE, H>), TreeError<DbError>> {
+        let mut proof = Vec::with_capacity(Self::max_levels());
+        let leaf = lookup(self.db.as_ref(), self.root()?, key, Some(&mut proof))?;
+        // Reverse the proof to get the correct order
+        proof.reverse();
+        Ok((leaf, Proof::new(proof)))
+    }
+
+    /// Returns the merkle proof that no leaf is stored at `key`, to be verified with
+    /// [`Proof::verify_exclusion`].
+    ///
+    /// Returns [`TreeError::ExpectedEmptyLeaf`] if a leaf is stored at `key`.
+    pub fn exclusion_proof(
+        &self,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<Proof<HASH_SIZE, H>, TreeError<DbError>> {
+        match self.get_with_proof(key)? {
+            (Leaf::Empty(_), proof) => Ok(proof),
+            (Leaf::NonEmpty(_), _) => Err(TreeError::ExpectedEmptyLeaf),
+        }
+    }
+
+    /// Returns a proof that no leaf is stored at `key` which stops where the path of `key`
+    /// reaches an empty subtree or a compacted leaf with another key, instead of holding the
+    /// siblings down to the leaf level.
+    ///
+    /// Returns [`TreeError::ExpectedEmptyLeaf`] if a leaf is stored at `key`.
+    pub fn compact_exclusion_proof(
+        &self,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<CompactExclusionProof<HASH_SIZE, H>, TreeError<DbError>> {
+        let mut siblings = Vec::new();
+        let (_, node) = descend(self.db.as_ref(), self.root()?, key, Some(&mut siblings))?;
+        // Order the siblings from the bottom like in a regular proof.
+        siblings.reverse();
+        match node {
+            Node::Compact(compact) if compact.key() != key => Ok(CompactExclusionProof::new(
+                siblings,
+                Some((*compact.key(), compact.leaf().clone())),
+            )),
+            Node::Compact(_) | Node::Leaf(Leaf::NonEmpty(_)) => Err(TreeError::ExpectedEmptyLeaf),
+            _ => Ok(CompactExclusionProof::new(siblings, None)),
+        }
+    }
+}
+
+/// Queues in `batch` the subtree at `height` holding the two leaves whose keys share their
+/// path down to `height`, and returns its root. See [`CompactMSSMT::merge`].
+pub(crate) fn merge_leaves<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone>(
+    empty_tree: &Arc<Vec<Node<HASH_SIZE, H>>>,
+    batch: &mut WriteBatch<HASH_SIZE, H>,
+    height: usize,
+    key1: [u8; HASH_SIZE],
+    leaf1: Leaf<HASH_SIZE, H>,
+    key2: [u8; HASH_SIZE],
+    leaf2: Leaf<HASH_SIZE, H>,
+) -> Branch<HASH_SIZE, H> {
+    // Find the common prefix first
+    let mut i = 0;
+    // As long as the key bits are the same we can continue
+    while i < HASH_SIZE * 8 && bit_index(i, &key1) == bit_index(i, &key2) {
+        i += 1;
+    }
+
+    // Now we create two compacted leaves and insert them as children of
+    // a newly created branch
+    let node1 = CompactLeaf::new(i + 1, key1, leaf1.clone(), empty_tree.clone());
+    let node2 = CompactLeaf::new(i + 1, key2, leaf2.clone(), empty_tree.clone());
+    // Insert the leaves into the database. This is not strictly necessary but it's useful
+    // If we want to avoid inserting the same leaf twice.
+    batch.insert_leaf(leaf1);
+    batch.insert_leaf(leaf2);
+    // Insert the compacted leaves into the database
+    batch.insert_compact_leaf(node1.clone());
+    batch.insert_compact_leaf(node2.clone());
+    let (left, right) = step_order(i, &key1, Node::Compact(node1), Node::Compact(node2));
+    let mut parent = Branch::new(left, right);
+    batch.insert_branch(parent.clone());
+
+    // From here we'll walk up to the current level and create branches
+    // along the way.
+    for i in (height..i).rev() {
+        let (left, right) = step_order(i, &key1, Node::Branch(parent), empty_tree[i + 1].clone());
+        parent = Branch::new(left, right);
+        batch.insert_branch(parent.clone());
+    }
+
+    parent
+}
+
+/// Helper function to order nodes based on a key bit at the given height.
+///
+/// Returns the nodes in (next, sibling) order based on whether the key bit is 0 or 1.
+#[inline]
+pub(crate) fn step_order<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone>(
+    height: usize,
+    key: &[u8; HASH_SIZE],
+    left: Node<HASH_SIZE, H>,
+    right: Node<HASH_SIZE, H>,
+) -> (Node<HASH_SIZE, H>, Node<HASH_SIZE, H>) {
+    if bit_index(height, key) == 0 {
+        (left, right)
+    } else {
+        (right, left)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::CompactMSSMT;
-    use crate::{EmptyLeaf, EmptyTree, Leaf, MemoryDb, TreeError};
+    use crate::{EmptyLeaf, EmptyTree, Leaf, MemoryDb, TreeError, MSSMT};
     use hex_literal::hex;
     use sha2::Sha256;
 
@@ -545,4 +1058,208 @@ mod test {
             EmptyTree::<32, Sha256>::empty_tree()[0].hash()
         );
     }
+
+    #[test]
+    fn test_compact_tree_unchanged_insertion() {
+        let db = Box::new(MemoryDb::<32, Sha256>::new());
+        let mut compact_mssmt = CompactMSSMT::<32, Sha256, ()>::new(db);
+        let leaf = Leaf::new([1; 32].to_vec(), 1);
+        compact_mssmt.insert(&[1; 32], leaf.clone()).unwrap();
+        compact_mssmt.insert(&[7; 32], leaf.clone()).unwrap();
+        let root = compact_mssmt.root().unwrap();
+        // Neither deleting an absent key nor inserting the same leaf again changes the tree.
+        compact_mssmt.delete(&[3; 32]).unwrap();
+        compact_mssmt.insert(&[7; 32], leaf.clone()).unwrap();
+        assert_eq!(compact_mssmt.root().unwrap().hash(), root.hash());
+        assert_eq!(compact_mssmt.get(&[7; 32]).unwrap().hash(), leaf.hash());
+        assert_eq!(compact_mssmt.get(&[1; 32]).unwrap().hash(), leaf.hash());
+    }
+
+    #[test]
+    fn test_compact_merge() {
+        let mut compact_mssmt = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::new()));
+        let (leaf1, leaf2) = (Leaf::new(vec![1], 1), Leaf::new(vec![2], 2));
+        let branch = compact_mssmt
+            .merge(0, [1; 32], leaf1.clone(), [2; 32], leaf2.clone())
+            .unwrap();
+        assert!(compact_mssmt.db().get_children(0, branch.hash()).is_ok());
+
+        let mut expected = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::new()));
+        expected.insert(&[1; 32], leaf1).unwrap();
+        expected.insert(&[2; 32], leaf2).unwrap();
+        assert_eq!(branch.hash(), expected.root().unwrap().hash());
+    }
+
+    #[test]
+    fn test_compact_insert_batch() {
+        let leaves = (0..20u8)
+            .map(|i| ([i.wrapping_mul(37); 32], Leaf::new(vec![i + 1], i as u64)))
+            .collect::<Vec<_>>();
+        let mut tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut batch_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for (key, leaf) in &leaves {
+            tree.insert(key, leaf.clone()).unwrap();
+        }
+        // The second batch has to split the compact leaves inserted by the first one.
+        batch_tree.insert_batch(&leaves[..10]).unwrap();
+        batch_tree.insert_batch(&leaves[10..]).unwrap();
+        assert_eq!(
+            tree.root().unwrap().hash(),
+            batch_tree.root().unwrap().hash()
+        );
+        for (key, leaf) in &leaves {
+            assert_eq!(
+                batch_tree.walk_down(key, |_, _, _, _| {}).unwrap().hash(),
+                leaf.hash()
+            );
+        }
+
+        // Mix replacements and deletions in the same batch.
+        let updates = leaves
+            .iter()
+            .enumerate()
+            .map(|(i, (key, _))| {
+                if i % 2 == 0 {
+                    (*key, Leaf::Empty(EmptyLeaf::new()))
+                } else {
+                    (*key, Leaf::new(vec![42], i as u64))
+                }
+            })
+            .collect::<Vec<_>>();
+        for (key, leaf) in &updates {
+            tree.insert(key, leaf.clone()).unwrap();
+        }
+        batch_tree.insert_batch(&updates).unwrap();
+        assert_eq!(
+            tree.root().unwrap().hash(),
+            batch_tree.root().unwrap().hash()
+        );
+
+        let deletions = leaves
+            .iter()
+            .map(|(key, _)| (*key, Leaf::Empty(EmptyLeaf::new())))
+            .collect::<Vec<_>>();
+        batch_tree.insert_batch(&deletions).unwrap();
+        assert_eq!(
+            batch_tree.root().unwrap().hash(),
+            EmptyTree::<32, Sha256>::empty_tree()[0].hash()
+        );
+    }
+
+    #[test]
+    fn test_compact_insert_batch_single_leaf() {
+        let leaf = Leaf::new([1; 32].to_vec(), 1);
+        let mut tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut batch_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        tree.insert(&[0; 32], leaf.clone()).unwrap();
+        batch_tree.insert_batch(&[([0; 32], leaf)]).unwrap();
+        assert_eq!(
+            tree.root().unwrap().hash(),
+            batch_tree.root().unwrap().hash()
+        );
+    }
+
+    #[test]
+    fn test_compact_insert_batch_overflow() {
+        let mut tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        tree.insert(&[0; 32], Leaf::new([1; 32].to_vec(), u64::MAX))
+            .unwrap();
+        let root = tree.root().unwrap();
+        assert_eq!(
+            tree.insert_batch(&[([1; 32], Leaf::new([1; 32].to_vec(), 1))])
+                .unwrap_err(),
+            TreeError::SumOverflow
+        );
+        assert_eq!(tree.root().unwrap().hash(), root.hash());
+    }
+
+    #[test]
+    fn test_compact_get() {
+        let mut tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut regular_tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let leaves = [([1; 32], 1), ([3; 32], 2), ([0x80; 32], 3)]
+            .map(|(key, sum)| (key, Leaf::new(vec![sum as u8], sum)));
+        for (key, leaf) in &leaves {
+            tree.insert(key, leaf.clone()).unwrap();
+            regular_tree.insert(key, leaf.clone()).unwrap();
+        }
+        let root = tree.root().unwrap();
+        assert_eq!(root.hash(), regular_tree.root().unwrap().hash());
+
+        // [1; 32] and [3; 32] share their first bit, [5; 32] diverges from [1; 32] at bit 2,
+        // [2; 32] is in an empty subtree and [0x81; 32] diverges from [0x80; 32] at bit 0 of
+        // the last byte.
+        let mut missing_key = [0x80; 32];
+        missing_key[31] = 0x81;
+        let keys = [[1; 32], [3; 32], [0x80; 32], [5; 32], [2; 32], missing_key];
+        for key in keys {
+            let expected = regular_tree.get(&key).unwrap();
+            let leaf = tree.get(&key).unwrap();
+            assert_eq!(leaf.hash(), expected.hash());
+            assert_eq!(
+                tree.contains(&key).unwrap(),
+                leaves.iter().any(|(k, _)| *k == key)
+            );
+
+            let (leaf, proof) = tree.get_with_proof(&key).unwrap();
+            assert_eq!(leaf.hash(), expected.hash());
+            let expected_proof = tree.merkle_proof(&key).unwrap();
+            assert_eq!(proof.nodes().len(), expected_proof.nodes().len());
+            for (node, expected) in proof.nodes().iter().zip(expected_proof.nodes()) {
+                assert_eq!(node.hash(), expected.hash());
+                assert_eq!(node.sum(), expected.sum());
+            }
+            assert!(proof
+                .verify_merkle_proof::<()>(&key, leaf, root.hash())
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn test_compact_strict_db() {
+        // A strict database errors on any missing child, valid trees never read one.
+        let leaves = (0..20u8)
+            .map(|i| ([i.wrapping_mul(37); 32], Leaf::new(vec![i + 1], i as u64)))
+            .collect::<Vec<_>>();
+        let mut tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::new_strict()));
+        let mut expected = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for (key, leaf) in &leaves[..10] {
+            tree.insert(key, leaf.clone()).unwrap();
+            expected.insert(key, leaf.clone()).unwrap();
+        }
+        tree.insert_batch(&leaves[10..]).unwrap();
+        expected.insert_batch(&leaves[10..]).unwrap();
+        for (key, _) in leaves.iter().step_by(3) {
+            tree.delete(key).unwrap();
+            expected.delete(key).unwrap();
+        }
+        let root = tree.root().unwrap();
+        assert_eq!(root.hash(), expected.root().unwrap().hash());
+        for (i, (key, leaf)) in leaves.iter().enumerate() {
+            let leaf = if i % 3 == 0 {
+                Leaf::Empty(EmptyLeaf::new())
+            } else {
+                leaf.clone()
+            };
+            assert_eq!(tree.get(key).unwrap().hash(), leaf.hash());
+            tree.merkle_proof(key)
+                .unwrap()
+                .verify_merkle_proof::<()>(key, leaf, root.hash())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_compact_get_empty() {
+        let tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        assert_eq!(
+            tree.get(&[1; 32]).unwrap().hash(),
+            EmptyLeaf::<32, Sha256>::new().hash()
+        );
+        assert!(!tree.contains(&[1; 32]).unwrap());
+        let (leaf, proof) = tree.get_with_proof(&[1; 32]).unwrap();
+        assert!(proof
+            .verify_merkle_proof::<()>(&[1; 32], leaf, tree.root().unwrap().hash())
+            .is_ok());
+    }
 }
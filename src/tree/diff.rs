@@ -0,0 +1,347 @@
+//! Differences between two trees stored in the same database.
+
+use std::cmp::Ordering;
+
+use crate::{
+    db::Db,
+    node::{ComputedNode, Hasher, Leaf, Node},
+    TreeError,
+};
+
+use super::LeafIter;
+
+/// A leaf that differs between two trees, yielded by [`TreeDiff`].
+#[derive(Debug, Clone)]
+pub enum DiffEntry<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
+    /// The key is only in the second tree.
+    Added([u8; HASH_SIZE], Leaf<HASH_SIZE, H>),
+    /// The key is only in the first tree.
+    Removed([u8; HASH_SIZE], Leaf<HASH_SIZE, H>),
+    /// The key holds the first leaf in the first tree and the second one in the second tree.
+    Modified([u8; HASH_SIZE], Leaf<HASH_SIZE, H>, Leaf<HASH_SIZE, H>),
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> DiffEntry<HASH_SIZE, H> {
+    /// Returns the key of the entry.
+    pub fn key(&self) -> &[u8; HASH_SIZE] {
+        match self {
+            Self::Added(key, _) | Self::Removed(key, _) | Self::Modified(key, _, _) => key,
+        }
+    }
+}
+
+/// Iterator over the leaves that differ between two trees stored in the same database, created
+/// by [`MSSMT::diff`](crate::MSSMT::diff) and [`CompactMSSMT::diff`](crate::CompactMSSMT::diff).
+///
+/// Both trees are walked down together and the subtrees whose hashes match are skipped. Below
+/// an empty subtree or a compacted leaf, one side holds at most one leaf, so the leaves of both
+/// sides are listed with [`LeafIter`] and compared. Entries are yielded in ascending key order:
+/// like in [`LeafIter`], the 8 levels holding a byte of the keys are walked together and what
+/// they lead to is sorted by that byte.
+pub struct TreeDiff<'a, const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError> {
+    db: &'a dyn Db<HASH_SIZE, H, DbError = DbError>,
+    /// Steps left, the next one last.
+    stack: Vec<Step<HASH_SIZE, H>>,
+}
+
+/// A step left in a [`TreeDiff`].
+enum Step<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone> {
+    /// Nodes to compare with their height and the key bits above them.
+    Compare(
+        usize,
+        [u8; HASH_SIZE],
+        Node<HASH_SIZE, H>,
+        Node<HASH_SIZE, H>,
+    ),
+    /// An entry found but not yielded yet.
+    Entry(DiffEntry<HASH_SIZE, H>),
+}
+
+impl<'a, const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
+    TreeDiff<'a, HASH_SIZE, H, DbError>
+{
+    /// Creates an iterator over the differences from the tree whose root hash is `root_a` to
+    /// the one whose root hash is `root_b`, both stored in `db`.
+    pub(crate) fn new(
+        db: &'a dyn Db<HASH_SIZE, H, DbError = DbError>,
+        root_a: [u8; HASH_SIZE],
+        root_b: [u8; HASH_SIZE],
+    ) -> Self {
+        // The roots are only walked down by hash.
+        let root = |hash| Node::Computed(ComputedNode::new(hash, 0));
+        Self {
+            db,
+            stack: vec![Step::Compare(0, [0; HASH_SIZE], root(root_a), root(root_b))],
+        }
+    }
+
+    /// Compares the nodes at `height` whose path is `key` down to the end of their byte of the
+    /// keys, pushing the nodes left to compare there and the entries found above, by that byte.
+    fn compare(
+        &mut self,
+        height: usize,
+        key: [u8; HASH_SIZE],
+        a: Node<HASH_SIZE, H>,
+        b: Node<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<DbError>> {
+        let byte = height / 8;
+        let next = (byte + 1) * 8;
+        let mut steps = Vec::new();
+        let mut walk = vec![(height, key, a, b)];
+        while let Some((height, key, a, b)) = walk.pop() {
+            if a.hash() == b.hash() {
+                continue;
+            }
+            let is_split = |node: &Node<HASH_SIZE, H>| {
+                matches!(node, Node::Branch(_) | Node::Computed(_))
+                    && node.hash() != self.db.empty_tree()[height].hash()
+            };
+            if height < HASH_SIZE * 8 && is_split(&a) && is_split(&b) {
+                if height == next {
+                    steps.push(Step::Compare(height, key, a, b));
+                    continue;
+                }
+                let (a_left, a_right) = self.db.get_children(height, a.hash())?;
+                let (b_left, b_right) = self.db.get_children(height, b.hash())?;
+                let mut right_key = key;
+                right_key[height / 8] |= 1 << (height % 8);
+                walk.push((height + 1, right_key, a_right, b_right));
+                walk.push((height + 1, key, a_left, b_left));
+                continue;
+            }
+            self.list(height, key, a, b, &mut steps)?;
+        }
+        // Subtrees below `height` don't share their byte of the keys, and the entries of one
+        // subtree are listed in key order, which the stable sort keeps.
+        let key_byte = |step: &Step<HASH_SIZE, H>| match step {
+            Step::Compare(_, key, _, _) => key[byte],
+            Step::Entry(entry) => entry.key()[byte],
+        };
+        steps.sort_by_key(key_byte);
+        self.stack.extend(steps.into_iter().rev());
+        Ok(())
+    }
+
+    /// Lists the leaves below the nodes at `height` whose path is `key` and pushes the entries
+    /// they differ by to `steps`, in key order.
+    fn list(
+        &self,
+        height: usize,
+        key: [u8; HASH_SIZE],
+        a: Node<HASH_SIZE, H>,
+        b: Node<HASH_SIZE, H>,
+        steps: &mut Vec<Step<HASH_SIZE, H>>,
+    ) -> Result<(), TreeError<DbError>> {
+        let a_leaves =
+            LeafIter::from_node(self.db, height, key, a).collect::<Result<Vec<_>, _>>()?;
+        let b_leaves =
+            LeafIter::from_node(self.db, height, key, b).collect::<Result<Vec<_>, _>>()?;
+        let mut a_leaves = a_leaves.into_iter().peekable();
+        let mut b_leaves = b_leaves.into_iter().peekable();
+        loop {
+            let order = match (a_leaves.peek(), b_leaves.peek()) {
+                (None, None) => break,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((a_key, _)), Some((b_key, _))) => a_key.cmp(b_key),
+            };
+            match order {
+                Ordering::Less => {
+                    let (key, leaf) = a_leaves.next().expect("peeked");
+                    steps.push(Step::Entry(DiffEntry::Removed(key, leaf)));
+                }
+                Ordering::Greater => {
+                    let (key, leaf) = b_leaves.next().expect("peeked");
+                    steps.push(Step::Entry(DiffEntry::Added(key, leaf)));
+                }
+                Ordering::Equal => {
+                    let (key, a_leaf) = a_leaves.next().expect("peeked");
+                    let (_, b_leaf) = b_leaves.next().expect("peeked");
+                    if a_leaf.hash() != b_leaf.hash() {
+                        steps.push(Step::Entry(DiffEntry::Modified(key, a_leaf, b_leaf)));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError> Iterator
+    for TreeDiff<'_, HASH_SIZE, H, DbError>
+{
+    type Item = Result<DiffEntry<HASH_SIZE, H>, TreeError<DbError>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (height, key, a, b) = match self.stack.pop()? {
+                Step::Entry(entry) => return Some(Ok(entry)),
+                Step::Compare(height, key, a, b) => (height, key, a, b),
+            };
+            if let Err(error) = self.compare(height, key, a, b) {
+                // Stop the iteration after an error.
+                self.stack.clear();
+                return Some(Err(error));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::Sha256;
+
+    use super::DiffEntry;
+    use crate::{CompactMSSMT, Db, EmptyLeaf, Leaf, MemoryDb, MSSMT};
+
+    /// Returns a database holding the nodes of both databases, and the root of `b`.
+    fn merge(
+        a: &dyn Db<32, Sha256, DbError = ()>,
+        b: &dyn Db<32, Sha256, DbError = ()>,
+    ) -> MemoryDb<32, Sha256> {
+        let mut merged = MemoryDb::default();
+        for db in [a, b] {
+            let db = db.as_any().downcast_ref::<MemoryDb<32, Sha256>>().unwrap();
+            for branch in db.get_branches().values() {
+                merged.insert_branch(branch.clone()).unwrap();
+            }
+            for leaf in db.get_leaves().values() {
+                merged.insert_leaf(leaf.clone()).unwrap();
+            }
+            for compact in db.get_compact_leaves().values() {
+                merged.insert_compact_leaf(compact.clone()).unwrap();
+            }
+        }
+        merged.update_root(b.get_root_node().unwrap()).unwrap();
+        merged
+    }
+
+    fn leaves() -> Vec<([u8; 32], Leaf<32, Sha256>)> {
+        (0..20u8)
+            .map(|i| ([i.wrapping_mul(37); 32], Leaf::new(vec![i + 1], i as u64)))
+            .collect()
+    }
+
+    /// Updates of `leaves` and the entries they yield, in key order.
+    #[allow(clippy::type_complexity)]
+    fn updates() -> (
+        Vec<([u8; 32], Leaf<32, Sha256>)>,
+        Vec<DiffEntry<32, Sha256>>,
+    ) {
+        let leaves = leaves();
+        let updates = vec![
+            ([1; 32], Leaf::new(vec![1], 1)),
+            (leaves[3].0, Leaf::Empty(EmptyLeaf::new())),
+            (leaves[5].0, Leaf::new(vec![50], 50)),
+            (leaves[6].0, leaves[6].1.clone()),
+            ([0xfe; 32], Leaf::new(vec![2], 2)),
+        ];
+        let mut entries = vec![
+            DiffEntry::Added([1; 32], updates[0].1.clone()),
+            DiffEntry::Removed(leaves[3].0, leaves[3].1.clone()),
+            DiffEntry::Modified(leaves[5].0, leaves[5].1.clone(), updates[2].1.clone()),
+            DiffEntry::Added([0xfe; 32], updates[4].1.clone()),
+        ];
+        entries.sort_by_key(|entry| *entry.key());
+        (updates, entries)
+    }
+
+    fn assert_entries(got: Vec<DiffEntry<32, Sha256>>, expected: &[DiffEntry<32, Sha256>]) {
+        assert_eq!(got.len(), expected.len());
+        for (got, expected) in got.iter().zip(expected) {
+            assert_eq!(got.key(), expected.key());
+            match (got, expected) {
+                (DiffEntry::Added(_, a), DiffEntry::Added(_, b))
+                | (DiffEntry::Removed(_, a), DiffEntry::Removed(_, b)) => {
+                    assert_eq!(a.hash(), b.hash())
+                }
+                (DiffEntry::Modified(_, a, c), DiffEntry::Modified(_, b, d)) => {
+                    assert_eq!(a.hash(), b.hash());
+                    assert_eq!(c.hash(), d.hash());
+                }
+                _ => panic!("unexpected entry {got:?}, expected {expected:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let (updates, entries) = updates();
+        let mut tree_a = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut tree_b = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for (key, leaf) in leaves() {
+            tree_a.insert(&key, leaf.clone()).unwrap();
+            tree_b.insert(&key, leaf).unwrap();
+        }
+        for (key, leaf) in &updates {
+            tree_b.insert(key, leaf.clone()).unwrap();
+        }
+        let (root_a, root_b) = (tree_a.root().unwrap().hash(), tree_b.root().unwrap().hash());
+        let tree = MSSMT::<32, Sha256, ()>::new(Box::new(merge(tree_a.db(), tree_b.db())));
+
+        let got = tree
+            .diff(root_a, root_b)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_entries(got, &entries);
+        assert_eq!(tree.diff(root_a, root_a).count(), 0);
+        // The reverse diff swaps the additions and removals.
+        let got = tree
+            .diff(root_b, root_a)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let reversed = entries
+            .into_iter()
+            .map(|entry| match entry {
+                DiffEntry::Added(key, leaf) => DiffEntry::Removed(key, leaf),
+                DiffEntry::Removed(key, leaf) => DiffEntry::Added(key, leaf),
+                DiffEntry::Modified(key, a, b) => DiffEntry::Modified(key, b, a),
+            })
+            .collect::<Vec<_>>();
+        assert_entries(got, &reversed);
+    }
+
+    #[test]
+    fn test_compact_diff() {
+        let (updates, entries) = updates();
+        let mut tree_a = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut tree_b = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for (key, leaf) in leaves() {
+            tree_a.insert(&key, leaf.clone()).unwrap();
+            tree_b.insert(&key, leaf).unwrap();
+        }
+        for (key, leaf) in &updates {
+            tree_b.insert(key, leaf.clone()).unwrap();
+        }
+        let (root_a, root_b) = (tree_a.root().unwrap().hash(), tree_b.root().unwrap().hash());
+        let tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(merge(tree_a.db(), tree_b.db())));
+
+        let got = tree
+            .diff(root_a, root_b)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_entries(got, &entries);
+        assert_eq!(tree.diff(root_b, root_b).count(), 0);
+    }
+
+    #[test]
+    fn test_diff_empty() {
+        let mut tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let empty_root = tree.root().unwrap().hash();
+        for (key, leaf) in leaves() {
+            tree.insert(&key, leaf).unwrap();
+        }
+        let root = tree.root().unwrap().hash();
+        let mut expected = leaves();
+        expected.sort_by_key(|(key, _)| *key);
+        let expected = expected
+            .into_iter()
+            .map(|(key, leaf)| DiffEntry::Added(key, leaf))
+            .collect::<Vec<_>>();
+        let got = tree
+            .diff(empty_root, root)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_entries(got, &expected);
+    }
+}
@@ -0,0 +1,417 @@
+//! Integrity check of the nodes stored in a database.
+
+use std::collections::HashSet;
+
+use crate::{
+    db::{Db, StoredNode},
+    node::{Branch, CompactLeaf, Hasher, Leaf, Node, Sum},
+    TreeError,
+};
+
+/// An inconsistency found by an integrity check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityIssue<const HASH_SIZE: usize> {
+    /// Hash of a branch doesn't match the one recomputed from its children
+    BranchHash {
+        /// Height of the branch
+        height: usize,
+        /// Hash the branch is stored under
+        hash: [u8; HASH_SIZE],
+        /// Hash recomputed from its children
+        computed: [u8; HASH_SIZE],
+    },
+    /// Sum of a branch doesn't match the sum of its children
+    BranchSum {
+        /// Height of the branch
+        height: usize,
+        /// Hash of the branch
+        hash: [u8; HASH_SIZE],
+        /// Sum stored in the branch
+        sum: Sum,
+        /// Sum of its children
+        computed: Sum,
+    },
+    /// Sum of the children of a branch overflows
+    SumOverflow {
+        /// Height of the branch
+        height: usize,
+        /// Hash of the branch
+        hash: [u8; HASH_SIZE],
+    },
+    /// Hash of a leaf doesn't match the one recomputed from its value and sum
+    LeafHash {
+        /// Hash the leaf is stored under
+        hash: [u8; HASH_SIZE],
+        /// Hash recomputed from its value and sum
+        computed: [u8; HASH_SIZE],
+    },
+    /// Hash of a compact leaf doesn't match the one recomputed from its key and height
+    CompactLeafHash {
+        /// Height of the compact leaf
+        height: usize,
+        /// Hash the compact leaf is stored under
+        hash: [u8; HASH_SIZE],
+        /// Hash recomputed from its key, leaf and height
+        computed: [u8; HASH_SIZE],
+    },
+    /// A reachable node isn't stored in the database
+    MissingNode {
+        /// Height of the missing node
+        height: usize,
+        /// Hash of the missing node
+        hash: [u8; HASH_SIZE],
+    },
+    /// A reachable node expected to be a branch is stored as a leaf
+    ExpectedBranch {
+        /// Height of the node
+        height: usize,
+        /// Hash of the node
+        hash: [u8; HASH_SIZE],
+    },
+}
+
+/// Result of an integrity check.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport<const HASH_SIZE: usize> {
+    /// Number of reachable nodes checked, empty subtrees excluded
+    pub nodes: usize,
+    /// Inconsistencies found, in the order they were found
+    pub issues: Vec<IntegrityIssue<HASH_SIZE>>,
+    /// Stored nodes that no root reaches. They aren't inconsistencies: a regular tree keeps
+    /// the leaves it replaces, and [`prune`](crate::MSSMT::prune) deletes them.
+    pub orphans: Vec<StoredNode<HASH_SIZE>>,
+}
+
+impl<const HASH_SIZE: usize> IntegrityReport<HASH_SIZE> {
+    /// Whether no inconsistency was found, orphans aside.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Checks every node of `db` reachable from one of the `roots`, then lists the stored nodes
+/// none of them reaches.
+///
+/// Branches are checked against the children returned by [`Db::get_children`], so a
+/// database that reads unknown children as empty subtrees reports them as hash mismatches
+/// rather than missing nodes. Only database errors abort the check.
+pub(crate) fn verify_integrity<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
+    db: &dyn Db<HASH_SIZE, H, DbError = DbError>,
+    roots: &[Branch<HASH_SIZE, H>],
+) -> Result<IntegrityReport<HASH_SIZE>, TreeError<DbError>> {
+    let empty_tree = db.empty_tree();
+    let mut report = IntegrityReport::default();
+    let mut reachable = HashSet::new();
+    let mut stack = roots
+        .iter()
+        .map(|root| (0, Node::Branch(root.clone())))
+        .collect::<Vec<_>>();
+    while let Some((height, node)) = stack.pop() {
+        let hash = node.hash();
+        if hash == empty_tree[height].hash() || !reachable.insert(hash) {
+            continue;
+        }
+        report.nodes += 1;
+        match node {
+            Node::Leaf(leaf) => check_leaf(&mut report, &leaf),
+            Node::Compact(compact) => {
+                // The leaf of a compacted leaf is stored on its own too.
+                reachable.insert(compact.leaf().hash());
+                check_leaf(&mut report, compact.leaf());
+                let computed = CompactLeaf::<HASH_SIZE, H>::new(
+                    height,
+                    *compact.key(),
+                    compact.leaf().clone(),
+                    empty_tree.clone(),
+                )
+                .hash();
+                if computed != hash {
+                    report.issues.push(IntegrityIssue::CompactLeafHash {
+                        height,
+                        hash,
+                        computed,
+                    });
+                }
+            }
+            Node::Branch(_) | Node::Computed(_) => {
+                let (left, right) = match db.get_children(height, hash) {
+                    Ok(children) => children,
+                    Err(TreeError::NodeNotFound {
+                        height: error_height,
+                        hash: error_hash,
+                    }) => {
+                        let (height, hash) = error_node(error_height, &error_hash, height, hash);
+                        report
+                            .issues
+                            .push(IntegrityIssue::MissingNode { height, hash });
+                        continue;
+                    }
+                    Err(TreeError::ExpectedBranch {
+                        height: error_height,
+                        hash: error_hash,
+                    }) => {
+                        let (height, hash) = error_node(error_height, &error_hash, height, hash);
+                        report
+                            .issues
+                            .push(IntegrityIssue::ExpectedBranch { height, hash });
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                match left.sum().checked_add(right.sum()) {
+                    Some(computed) => {
+                        if computed != node.sum() {
+                            report.issues.push(IntegrityIssue::BranchSum {
+                                height,
+                                hash,
+                                sum: node.sum(),
+                                computed,
+                            });
+                        }
+                        let computed = Branch::new(left.clone(), right.clone()).hash();
+                        if computed != hash {
+                            report.issues.push(IntegrityIssue::BranchHash {
+                                height,
+                                hash,
+                                computed,
+                            });
+                        }
+                    }
+                    None => report
+                        .issues
+                        .push(IntegrityIssue::SumOverflow { height, hash }),
+                }
+                stack.push((height + 1, right));
+                stack.push((height + 1, left));
+            }
+        }
+    }
+
+    report.orphans = db
+        .stored_nodes()?
+        .into_iter()
+        .filter(|node| !reachable.contains(&node.hash))
+        .collect();
+    Ok(report)
+}
+
+fn check_leaf<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone>(
+    report: &mut IntegrityReport<HASH_SIZE>,
+    leaf: &Leaf<HASH_SIZE, H>,
+) {
+    let computed = Leaf::<HASH_SIZE, H>::new(leaf.value().to_vec(), leaf.sum()).hash();
+    if computed != leaf.hash() {
+        report.issues.push(IntegrityIssue::LeafHash {
+            hash: leaf.hash(),
+            computed,
+        });
+    }
+}
+
+/// Returns the height and hash of the node a [`TreeError`] of the database is about, falling
+/// back to the `height` and `hash` of the walked node if the error doesn't carry a hash of
+/// `HASH_SIZE` bytes.
+fn error_node<const HASH_SIZE: usize>(
+    error_height: usize,
+    error_hash: &[u8],
+    height: usize,
+    hash: [u8; HASH_SIZE],
+) -> (usize, [u8; HASH_SIZE]) {
+    match error_hash.try_into() {
+        Ok(error_hash) => (error_height, error_hash),
+        Err(_) => (height, hash),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::Sha256;
+
+    use super::{error_node, IntegrityIssue};
+    use crate::{
+        Branch, CompactLeaf, CompactMSSMT, Db, Leaf, MemoryDb, Node, NodeKind, StoredNode, MSSMT,
+    };
+
+    fn leaves() -> Vec<([u8; 32], Leaf<32, Sha256>)> {
+        (0..10u8)
+            .map(|i| ([i.wrapping_mul(37); 32], Leaf::new(vec![i + 1], i as u64)))
+            .collect()
+    }
+
+    /// Stores in `db` a root whose only child is `compact`, on its left at height 1. The
+    /// compact leaf itself isn't stored.
+    fn insert_root(db: &mut MemoryDb<32, Sha256>, compact: &CompactLeaf<32, Sha256>) {
+        let root = Branch::new(Node::Compact(compact.clone()), db.empty_tree()[1].clone());
+        db.insert_branch(root.clone()).unwrap();
+        db.update_root(root).unwrap();
+    }
+
+    #[test]
+    fn test_verify_integrity() {
+        let leaves = leaves();
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut compact_tree =
+            CompactMSSMT::<32, Sha256, ()>::new_versioned(Box::new(MemoryDb::default()));
+        assert_eq!(tree.verify_integrity().unwrap().nodes, 0);
+        for (key, leaf) in &leaves {
+            tree.insert(key, leaf.clone()).unwrap();
+            compact_tree.insert(key, leaf.clone()).unwrap();
+        }
+        tree.insert(&leaves[2].0, Leaf::new(vec![42], 42)).unwrap();
+        tree.delete(&leaves[3].0).unwrap();
+        compact_tree.delete(&leaves[3].0).unwrap();
+
+        // The regular tree keeps the replaced leaves, and the empty leaf of the deletion.
+        let report = tree.verify_integrity().unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+        let orphans = report
+            .orphans
+            .iter()
+            .map(|node| node.hash)
+            .collect::<Vec<_>>();
+        assert_eq!(orphans.len(), 3);
+        assert!(orphans.contains(&leaves[2].1.hash()));
+        assert!(orphans.contains(&leaves[3].1.hash()));
+        assert_eq!(
+            report.nodes + orphans.len(),
+            tree.db().stored_nodes().unwrap().len()
+        );
+        // The leaves of the compact leaves are reached through them and aren't counted.
+        let stored = compact_tree.db().stored_nodes().unwrap();
+        let report = compact_tree.verify_integrity().unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert!(report.orphans.is_empty());
+        assert_eq!(
+            report.nodes,
+            stored
+                .iter()
+                .filter(|node| node.kind != NodeKind::Leaf)
+                .count()
+        );
+    }
+
+    #[test]
+    fn test_verify_integrity_corrupted() {
+        let empty_tree = MemoryDb::<32, Sha256>::new().empty_tree();
+        let key = [0; 32];
+        let leaf = Leaf::<32, Sha256>::new(vec![1], 1);
+        let compact = CompactLeaf::new(1, key, leaf.clone(), empty_tree.clone());
+
+        // Compact leaf whose hash isn't the one of its path.
+        let bad_compact = unsafe { CompactLeaf::new_with_hash([1; 32], leaf.clone(), key) };
+        let mut db = MemoryDb::new();
+        insert_root(&mut db, &bad_compact);
+        db.insert_compact_leaf(bad_compact).unwrap();
+        db.insert_leaf(leaf.clone()).unwrap();
+        let report = CompactMSSMT::<32, Sha256, ()>::new(Box::new(db))
+            .verify_integrity()
+            .unwrap();
+        assert_eq!(
+            report.issues,
+            vec![IntegrityIssue::CompactLeafHash {
+                height: 1,
+                hash: [1; 32],
+                computed: compact.hash(),
+            }]
+        );
+
+        // Leaf whose hash isn't the one of its value and sum.
+        let bad_leaf = unsafe { Leaf::<32, Sha256>::new_with_hash(vec![1], 1, [2; 32]) };
+        let bad_compact = CompactLeaf::new(1, key, bad_leaf.clone(), empty_tree.clone());
+        let mut db = MemoryDb::new();
+        insert_root(&mut db, &bad_compact);
+        db.insert_compact_leaf(bad_compact).unwrap();
+        db.insert_leaf(bad_leaf).unwrap();
+        let report = CompactMSSMT::<32, Sha256, ()>::new(Box::new(db))
+            .verify_integrity()
+            .unwrap();
+        assert_eq!(
+            report.issues,
+            vec![IntegrityIssue::LeafHash {
+                hash: [2; 32],
+                computed: leaf.hash(),
+            }]
+        );
+
+        // Root whose hash and sum don't match its children, and an unreachable leaf.
+        let mut db = MemoryDb::<32, Sha256>::new();
+        let left = Node::Compact(compact.clone());
+        let right = empty_tree[1].clone();
+        let root = Branch::new(left.clone(), right.clone());
+        let bad_root = unsafe { Branch::new_with_hash(left, right, [3; 32], 2) };
+        let orphan = Leaf::<32, Sha256>::new(vec![2], 2);
+        db.insert_branch(bad_root.clone()).unwrap();
+        db.insert_compact_leaf(compact.clone()).unwrap();
+        db.insert_leaf(leaf).unwrap();
+        db.insert_leaf(orphan.clone()).unwrap();
+        db.update_root(bad_root).unwrap();
+        let report = MSSMT::<32, Sha256, ()>::new(Box::new(db))
+            .verify_integrity()
+            .unwrap();
+        assert_eq!(report.nodes, 2);
+        assert_eq!(
+            report.issues,
+            vec![
+                IntegrityIssue::BranchSum {
+                    height: 0,
+                    hash: [3; 32],
+                    sum: 2,
+                    computed: 1,
+                },
+                IntegrityIssue::BranchHash {
+                    height: 0,
+                    hash: [3; 32],
+                    computed: root.hash(),
+                },
+            ]
+        );
+        assert_eq!(
+            report.orphans,
+            vec![StoredNode {
+                kind: NodeKind::Leaf,
+                hash: orphan.hash(),
+                size: 32 + 1 + 8,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_verify_integrity_missing_node() {
+        let empty_tree = MemoryDb::<32, Sha256>::new().empty_tree();
+        let compact = CompactLeaf::new(1, [0; 32], Leaf::new(vec![1], 1), empty_tree);
+
+        // A lenient database reads the missing compact leaf as an empty subtree.
+        let mut db = MemoryDb::new();
+        insert_root(&mut db, &compact);
+        let report = CompactMSSMT::<32, Sha256, ()>::new(Box::new(db))
+            .verify_integrity()
+            .unwrap();
+        assert!(matches!(
+            report.issues.as_slice(),
+            [
+                IntegrityIssue::BranchSum { height: 0, .. },
+                IntegrityIssue::BranchHash { height: 0, .. }
+            ]
+        ));
+
+        let mut db = MemoryDb::new_strict();
+        insert_root(&mut db, &compact);
+        let report = CompactMSSMT::<32, Sha256, ()>::new(Box::new(db))
+            .verify_integrity()
+            .unwrap();
+        assert_eq!(
+            report.issues,
+            vec![IntegrityIssue::MissingNode {
+                height: 1,
+                hash: compact.hash(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_error_node() {
+        assert_eq!(error_node(2, &[1; 32], 1, [3; 32]), (2, [1; 32]));
+        // A database error carrying a malformed hash falls back to the walked node.
+        assert_eq!(error_node(2, &[1; 4], 1, [3; 32]), (1, [3; 32]));
+    }
+}
@@ -0,0 +1,305 @@
+//! Iteration over the leaves of a tree.
+
+use crate::{
+    db::Db,
+    node::{Hasher, Leaf, Node},
+    TreeError,
+};
+
+use super::range::{contains, overlap, Overlap};
+
+/// Iterator over the non-empty leaves of a tree, created by [`MSSMT::iter`](crate::MSSMT::iter)
+/// and [`CompactMSSMT::iter`](crate::CompactMSSMT::iter).
+///
+/// Leaves are yielded in ascending key order. The path of a key starts with the least
+/// significant bit of its first byte, so the subtrees of the 8 levels holding a byte of the
+/// keys are read together and sorted by that byte: at most 256 subtrees per byte are kept in
+/// memory. Empty subtrees are skipped without reading them from the database.
+///
+/// When created with `range`, only the leaves whose keys are in `[start, end)` are yielded,
+/// the bounds being compared to the keys in lexicographic order, and subtrees outside of the
+/// range are skipped.
+pub struct LeafIter<'a, const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError> {
+    db: &'a dyn Db<HASH_SIZE, H, DbError = DbError>,
+    /// Nodes left to visit with their height and the key bits above them, the next one last.
+    stack: Vec<(usize, [u8; HASH_SIZE], Node<HASH_SIZE, H>)>,
+    /// Bounds of the iterated keys.
+    range: Option<([u8; HASH_SIZE], [u8; HASH_SIZE])>,
+}
+
+impl<'a, const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
+    LeafIter<'a, HASH_SIZE, H, DbError>
+{
+    /// Creates an iterator over the leaves of the tree stored in `db`.
+    pub(crate) fn new(db: &'a dyn Db<HASH_SIZE, H, DbError = DbError>) -> Self {
+        let root = match db.get_root_node() {
+            Some(root) => Node::Branch(root),
+            None => db.empty_tree()[0].clone(),
+        };
+        Self::from_node(db, 0, [0; HASH_SIZE], root)
+    }
+
+    /// Creates an iterator over the leaves of the subtree rooted at `node`, `key` holding the
+    /// path to `node`.
+    pub(crate) fn from_node(
+        db: &'a dyn Db<HASH_SIZE, H, DbError = DbError>,
+        height: usize,
+        key: [u8; HASH_SIZE],
+        node: Node<HASH_SIZE, H>,
+    ) -> Self {
+        Self {
+            db,
+            stack: vec![(height, key, node)],
+            range: None,
+        }
+    }
+
+    /// Restricts the iterator to the keys in `[start, end)`.
+    pub(crate) fn with_range(mut self, start: [u8; HASH_SIZE], end: [u8; HASH_SIZE]) -> Self {
+        self.range = Some((start, end));
+        self
+    }
+
+    /// Returns `true` if the node at `height` whose path is `key` has keys in the range.
+    fn in_range(&self, height: usize, key: &[u8; HASH_SIZE], node: &Node<HASH_SIZE, H>) -> bool {
+        let Some((start, end)) = &self.range else {
+            return true;
+        };
+        match node {
+            Node::Compact(compact) => contains(compact.key(), start, end),
+            _ => overlap(height, key, start, end) != Overlap::Outside,
+        }
+    }
+
+    /// Pushes the non-empty subtrees of the branch at `height` down to the next byte of the
+    /// keys, and the leaves above it, in ascending key order.
+    fn expand(
+        &mut self,
+        height: usize,
+        key: [u8; HASH_SIZE],
+        node: Node<HASH_SIZE, H>,
+    ) -> Result<(), TreeError<DbError>> {
+        let byte = height / 8;
+        let next = (byte + 1) * 8;
+        let mut expanded = Vec::new();
+        let mut walk = vec![(height, key, node)];
+        while let Some((height, key, node)) = walk.pop() {
+            if node.hash() == self.db.empty_tree()[height].hash()
+                || !self.in_range(height, &key, &node)
+            {
+                continue;
+            }
+            match node {
+                Node::Branch(_) | Node::Computed(_) if height < next => {
+                    let (left, right) = self.db.get_children(height, node.hash())?;
+                    let mut right_key = key;
+                    right_key[byte] |= 1 << (height % 8);
+                    walk.push((height + 1, right_key, right));
+                    walk.push((height + 1, key, left));
+                }
+                Node::Compact(ref compact) => expanded.push((height, *compact.key(), node)),
+                _ => expanded.push((height, key, node)),
+            }
+        }
+        // Subtrees and compacted leaves below `height` don't share their byte of the keys.
+        expanded.sort_by_key(|(_, key, _)| std::cmp::Reverse(key[byte]));
+        self.stack.extend(expanded);
+        Ok(())
+    }
+}
+
+impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError> Iterator
+    for LeafIter<'_, HASH_SIZE, H, DbError>
+{
+    type Item = Result<([u8; HASH_SIZE], Leaf<HASH_SIZE, H>), TreeError<DbError>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((height, key, node)) = self.stack.pop() {
+            if node.hash() == self.db.empty_tree()[height].hash()
+                || !self.in_range(height, &key, &node)
+            {
+                continue;
+            }
+            match node {
+                Node::Leaf(leaf) => return Some(Ok((key, leaf))),
+                Node::Compact(compact) => {
+                    return Some(Ok((*compact.key(), compact.leaf().clone())))
+                }
+                Node::Branch(_) | Node::Computed(_) => {
+                    if let Err(error) = self.expand(height, key, node) {
+                        // Stop the iteration after an error.
+                        self.stack.clear();
+                        return Some(Err(error));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::Sha256;
+
+    use crate::{Branch, CompactMSSMT, Db, EmptyLeaf, Leaf, MemoryDb, Node, TreeError, MSSMT};
+
+    fn leaves() -> Vec<([u8; 32], Leaf<32, Sha256>)> {
+        (0..20u8)
+            .map(|i| ([i.wrapping_mul(37); 32], Leaf::new(vec![i + 1], i as u64)))
+            .collect()
+    }
+
+    #[test]
+    fn test_iter_empty() {
+        let tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        assert_eq!(tree.iter().count(), 0);
+        let tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        assert_eq!(tree.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_iter_key_order() {
+        // Keys whose tree paths are ordered differently, in the first byte and in later ones.
+        let mut keys = vec![[2; 32], [1; 32], [0x80; 32], [0x01; 32], [0x40; 32]];
+        keys[3][5] = 0x80;
+        keys[4][31] = 0x01;
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut compact_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for key in &keys {
+            tree.insert(key, Leaf::new(key.to_vec(), 1)).unwrap();
+            compact_tree
+                .insert(key, Leaf::new(key.to_vec(), 1))
+                .unwrap();
+        }
+        keys.sort();
+        let got = tree
+            .iter()
+            .map(|item| item.map(|(key, _)| key))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(got, keys);
+        let got = compact_tree
+            .iter()
+            .map(|item| item.map(|(key, _)| key))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(got, keys);
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut leaves = leaves();
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut compact_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for (key, leaf) in &leaves {
+            tree.insert(key, leaf.clone()).unwrap();
+            compact_tree.insert(key, leaf.clone()).unwrap();
+        }
+        tree.delete(&leaves[3].0).unwrap();
+        compact_tree.delete(&leaves[3].0).unwrap();
+        leaves.remove(3);
+        leaves.sort_by_key(|(key, _)| *key);
+
+        let items = tree.iter().collect::<Result<Vec<_>, _>>().unwrap();
+        let compact_items = compact_tree.iter().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(items.len(), leaves.len());
+        assert_eq!(compact_items.len(), leaves.len());
+        for (((key, leaf), (tree_key, tree_leaf)), (compact_key, compact_leaf)) in
+            leaves.iter().zip(items).zip(compact_items)
+        {
+            assert_eq!(*key, tree_key);
+            assert_eq!(*key, compact_key);
+            assert_eq!(leaf.hash(), tree_leaf.hash());
+            assert_eq!(leaf.hash(), compact_leaf.hash());
+        }
+    }
+
+    #[test]
+    fn test_iter_empty_leaf() {
+        let mut compact_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        compact_tree
+            .insert(&[1; 32], Leaf::Empty(EmptyLeaf::new()))
+            .unwrap();
+        assert_eq!(compact_tree.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_iter_missing_node() {
+        let mut db = MemoryDb::<32, Sha256>::default();
+        let leaf = Leaf::new(vec![1], 1);
+        let missing = Branch::new(Node::Leaf(leaf.clone()), Node::Leaf(leaf));
+        let mut current = Node::Branch(missing);
+        for height in (0..255).rev() {
+            current = Node::new_branch(current, db.empty_tree()[height + 1].clone());
+        }
+        let Node::Branch(root) = current else {
+            unreachable!()
+        };
+        let root_hash = root.hash();
+        db.update_root(root).unwrap();
+        let tree = MSSMT::<32, Sha256, ()>::new(Box::new(db));
+        let mut iter = tree.iter();
+        assert_eq!(
+            iter.next().unwrap().unwrap_err(),
+            TreeError::node_not_found(0, &root_hash)
+        );
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_range() {
+        let mut leaves = leaves();
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut compact_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for (key, leaf) in &leaves {
+            tree.insert(key, leaf.clone()).unwrap();
+            compact_tree.insert(key, leaf.clone()).unwrap();
+        }
+        leaves.sort_by_key(|(key, _)| *key);
+
+        for (start, end) in [(0, leaves.len()), (3, 11), (5, 6), (7, 7), (0, 1), (12, 20)] {
+            let start_key = leaves[start].0;
+            let end_key = leaves.get(end).map(|(key, _)| *key).unwrap_or([0xff; 32]);
+            let expected = leaves[start..end]
+                .iter()
+                .map(|(key, leaf)| (*key, leaf.hash()))
+                .collect::<Vec<_>>();
+            let expected_sum = leaves[start..end]
+                .iter()
+                .map(|(_, leaf)| leaf.sum())
+                .sum::<u64>();
+            let items = tree
+                .range(&start_key, &end_key)
+                .map(|item| item.map(|(key, leaf)| (key, leaf.hash())))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let compact_items = compact_tree
+                .range(&start_key, &end_key)
+                .map(|item| item.map(|(key, leaf)| (key, leaf.hash())))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(items, expected);
+            assert_eq!(compact_items, expected);
+            assert_eq!(tree.range_sum(&start_key, &end_key).unwrap(), expected_sum);
+            assert_eq!(
+                compact_tree.range_sum(&start_key, &end_key).unwrap(),
+                expected_sum
+            );
+        }
+    }
+
+    #[test]
+    fn test_range_sum_bounds() {
+        let leaves = leaves();
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for (key, leaf) in &leaves {
+            tree.insert(key, leaf.clone()).unwrap();
+        }
+        let root = tree.root().unwrap();
+        // The whole key space is covered by the root.
+        assert_eq!(tree.range_sum(&[0; 32], &[0xff; 32]).unwrap(), root.sum());
+        // An empty range.
+        assert_eq!(tree.range_sum(&[0xff; 32], &[0; 32]).unwrap(), 0);
+    }
+}
@@ -1,15 +1,33 @@
+mod async_compact;
+mod async_regular;
 mod compact;
+mod diff;
 mod empty;
+mod integrity;
+mod iter;
+mod multi;
+mod prune;
+pub(crate) mod range;
 mod regular;
+mod view;
 
 use std::borrow::Borrow;
+use std::cmp::Ordering;
 use std::sync::Arc;
 
+pub use async_compact::AsyncCompactMSSMT;
+pub use async_regular::AsyncMSSMT;
 pub use compact::CompactMSSMT;
+pub use diff::{DiffEntry, TreeDiff};
 pub use empty::EmptyTree;
+pub use integrity::{IntegrityIssue, IntegrityReport};
+pub use iter::LeafIter;
+pub use prune::PruneStats;
 pub use regular::bit_index;
 pub use regular::MSSMT;
+pub use view::TreeView;
 
+use crate::db::{Db, WriteBatch};
 use crate::Branch;
 use crate::Hasher;
 use crate::Leaf;
@@ -25,6 +43,8 @@ use crate::TreeError;
 ///     * `current: &Node<HASH_SIZE, H>` - current node on the way to the asked node
 ///     * `sibling: &Node<HASH_SIZE, H>` - sibling node of the current node on the way to the asked node
 ///     * `parent: &Node<HASH_SIZE, H>` - parent node of the current node on the way to the asked node
+///
+/// Returns [`TreeError::SumOverflow`] if the sum of a branch on the path overflows.
 pub fn walk_up<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
     key: &[u8; HASH_SIZE],
     start: Leaf<HASH_SIZE, H>,
@@ -34,6 +54,9 @@ pub fn walk_up<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
     let mut current = Arc::new(Node::Leaf(start));
     for i in (0..MSSMT::<HASH_SIZE, H, DbError>::max_levels()).rev() {
         let sibling = siblings[MSSMT::<HASH_SIZE, H, DbError>::max_levels() - 1 - i].clone();
+        if current.sum().checked_add(sibling.sum()).is_none() {
+            return Err(TreeError::SumOverflow);
+        }
         // order the children based on the path
         let parent = if bit_index(i, key) == 0 {
             Node::Branch(Branch::new_with_arc_children(
@@ -52,6 +75,225 @@ pub fn walk_up<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
     if let Node::Branch(current) = current.borrow() {
         Ok(current.clone())
     } else {
-        Err(TreeError::ExpectedBranch)
+        Err(TreeError::expected_branch(0, &current.hash()))
+    }
+}
+
+/// Builds the [`WriteBatch`] storing `leaf` at `key` in a regular tree, from the `siblings`
+/// and the hashes of the `prev_parents` on the path of `key`, both from the leaf to the root.
+///
+/// The branches of the new path are inserted and the ones they replace deleted, unchanged and
+/// empty branches being left as they are.
+pub(crate) fn path_update<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
+    empty_tree: &[Node<HASH_SIZE, H>],
+    key: &[u8; HASH_SIZE],
+    leaf: Leaf<HASH_SIZE, H>,
+    siblings: &[Arc<Node<HASH_SIZE, H>>],
+    prev_parents: &[[u8; HASH_SIZE]],
+) -> Result<WriteBatch<HASH_SIZE, H>, TreeError<DbError>> {
+    let mut batch = WriteBatch::new();
+    let mut branches_delete = Vec::new();
+    let root = walk_up(
+        key,
+        leaf.clone(),
+        siblings,
+        |height, _current, _sibling, parent| {
+            let prev_parent = prev_parents[HASH_SIZE * 8 - height - 1];
+            // An unchanged branch is neither deleted nor inserted again.
+            if prev_parent == parent.hash() {
+                return;
+            }
+            if prev_parent != empty_tree[height].hash() {
+                branches_delete.push(prev_parent);
+            }
+            if parent.hash() != empty_tree[height].hash() {
+                if let Node::Branch(parent) = parent {
+                    batch.insert_branch(parent.clone());
+                }
+            }
+        },
+    )?;
+    for key in branches_delete {
+        batch.delete_branch(&key);
+    }
+
+    batch.insert_leaf(leaf);
+    batch.update_root(root);
+    Ok(batch)
+}
+
+/// Compares two keys in the order their paths are laid out in the tree.
+///
+/// The path of a key starts with the least significant bit of its first byte (see
+/// [`bit_index`]), so this is the lexicographic order of the bit-reversed bytes.
+pub(crate) fn path_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    a.iter()
+        .map(|byte| byte.reverse_bits())
+        .cmp(b.iter().map(|byte| byte.reverse_bits()))
+}
+
+/// Sorts the leaves of a batch in path order and removes duplicated keys.
+/// When a key appears several times, the last leaf wins like with sequential inserts.
+#[allow(clippy::type_complexity)]
+pub(crate) fn sort_batch<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone>(
+    leaves: &[([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)],
+) -> Vec<([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)> {
+    let mut entries = leaves.to_vec();
+    // The sort is stable so duplicated keys stay in insertion order.
+    entries.sort_by(|(a, _), (b, _)| path_cmp(a, b));
+    let mut sorted: Vec<([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)> = Vec::with_capacity(entries.len());
+    for (key, leaf) in entries {
+        match sorted.last_mut() {
+            Some((last, last_leaf)) if *last == key => *last_leaf = leaf,
+            _ => sorted.push((key, leaf)),
+        }
+    }
+    sorted
+}
+
+/// Splits path-sorted batch entries sharing the same path up to `height` between the left
+/// and right subtrees.
+#[allow(clippy::type_complexity)]
+pub(crate) fn split_batch<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone>(
+    height: usize,
+    entries: &[([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)],
+) -> (
+    &[([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)],
+    &[([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)],
+) {
+    entries.split_at(entries.partition_point(|(key, _)| bit_index(height, key) == 0))
+}
+
+/// Creates a new [`Branch`], returning [`TreeError::SumOverflow`] if the sum of the
+/// children overflows.
+pub(crate) fn checked_branch<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
+    left: Node<HASH_SIZE, H>,
+    right: Node<HASH_SIZE, H>,
+) -> Result<Branch<HASH_SIZE, H>, TreeError<DbError>> {
+    if left.sum().checked_add(right.sum()).is_none() {
+        return Err(TreeError::SumOverflow);
+    }
+    Ok(Branch::new(left, right))
+}
+
+/// Returns the children of the subtree at `height` rooted at `node`, and whether they are
+/// detached from the database.
+///
+/// Empty subtrees and compacted leaves are split in memory, so their children are detached and
+/// hold their own children: a `detached` branch is split without reading the database.
+#[allow(clippy::type_complexity)]
+pub(crate) fn children<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
+    db: &dyn Db<HASH_SIZE, H, DbError = DbError>,
+    height: usize,
+    node: Node<HASH_SIZE, H>,
+    detached: bool,
+) -> Result<(Node<HASH_SIZE, H>, Node<HASH_SIZE, H>, bool), TreeError<DbError>> {
+    let empty_tree = db.empty_tree();
+    match node {
+        node if node.hash() == empty_tree[height].hash() => Ok((
+            empty_tree[height + 1].clone(),
+            empty_tree[height + 1].clone(),
+            true,
+        )),
+        // Rebuild the path of the compacted leaf to split it like a regular subtree.
+        Node::Compact(compact) => {
+            let Node::Branch(branch) = compact.extract(height - 1) else {
+                return Err(TreeError::expected_branch(height, &compact.hash()));
+            };
+            Ok((branch.left().clone(), branch.right().clone(), true))
+        }
+        Node::Branch(branch) if detached => {
+            Ok((branch.left().clone(), branch.right().clone(), true))
+        }
+        node => {
+            let (left, right) = db.get_children(height, node.hash())?;
+            Ok((left, right, false))
+        }
+    }
+}
+
+/// Subtrees above this height are built concurrently by batch operations.
+#[cfg(feature = "multi-thread")]
+const PARALLEL_MAX_HEIGHT: usize = 4;
+/// Minimum number of leaves on each side of a split to build both subtrees concurrently.
+#[cfg(feature = "multi-thread")]
+const PARALLEL_MIN_LEAVES: usize = 32;
+
+/// Returns `true` if the subtrees of a batch split at `height` are worth building
+/// concurrently. Always `false` without the `multi-thread` feature.
+#[cfg(feature = "multi-thread")]
+pub(crate) fn parallel_split(height: usize, left: usize, right: usize) -> bool {
+    height < PARALLEL_MAX_HEIGHT && left.min(right) >= PARALLEL_MIN_LEAVES
+}
+
+/// Returns `true` if the subtrees of a batch split at `height` are worth building
+/// concurrently. Always `false` without the `multi-thread` feature.
+#[cfg(not(feature = "multi-thread"))]
+pub(crate) fn parallel_split(_height: usize, _left: usize, _right: usize) -> bool {
+    false
+}
+
+/// Runs `left` on a scoped thread while `right` runs on the current one.
+#[cfg(feature = "multi-thread")]
+pub(crate) fn join<A: Send, B>(
+    left: impl FnOnce() -> A + Send,
+    right: impl FnOnce() -> B,
+) -> (A, B) {
+    std::thread::scope(|scope| {
+        let handle = scope.spawn(left);
+        let right = right();
+        let left = handle
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        (left, right)
+    })
+}
+
+/// Runs `left` then `right` on the current thread.
+#[cfg(not(feature = "multi-thread"))]
+pub(crate) fn join<A, B>(left: impl FnOnce() -> A, right: impl FnOnce() -> B) -> (A, B) {
+    (left(), right())
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp::Ordering;
+
+    use sha2::Sha256;
+
+    use super::{bit_index, path_cmp, sort_batch, split_batch};
+    use crate::Leaf;
+
+    #[test]
+    fn test_path_cmp() {
+        // The first bit of the path is the least significant bit of the first byte.
+        assert_eq!(path_cmp(&[2, 0], &[1, 0]), Ordering::Less);
+        assert_eq!(path_cmp(&[0, 1], &[1, 0]), Ordering::Less);
+        assert_eq!(path_cmp(&[3, 7], &[3, 7]), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_join() {
+        let (left, right) = super::join(|| 1 + 1, || 2 + 2);
+        assert_eq!((left, right), (2, 4));
+    }
+
+    #[test]
+    fn test_sort_batch() {
+        let leaves = vec![
+            ([1; 32], Leaf::<32, Sha256>::new(vec![1], 1)),
+            ([2; 32], Leaf::new(vec![2], 2)),
+            ([1; 32], Leaf::new(vec![3], 3)),
+        ];
+        let sorted = sort_batch(&leaves);
+        assert_eq!(sorted.len(), 2);
+        assert_eq!(sorted[0].0, [2; 32]);
+        assert_eq!(sorted[1].0, [1; 32]);
+        assert_eq!(sorted[1].1.sum(), 3);
+
+        let (left, right) = split_batch(0, &sorted);
+        assert!(left.iter().all(|(key, _)| bit_index(0, key) == 0));
+        assert!(right.iter().all(|(key, _)| bit_index(0, key) == 1));
+        assert_eq!(left.len() + right.len(), 2);
     }
 }
@@ -0,0 +1,56 @@
+//! Generation of [`MultiProof`]s.
+
+use bitvec::{order::Lsb0, vec::BitVec};
+
+use crate::{
+    db::Db,
+    node::{ComputedNode, Hasher, Node},
+    MultiProof, TreeError,
+};
+
+use super::{bit_index, children, path_cmp};
+
+/// Generates the [`MultiProof`] of `keys` for the tree rooted at `root`.
+pub(crate) fn multi_proof<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
+    db: &dyn Db<HASH_SIZE, H, DbError = DbError>,
+    root: Node<HASH_SIZE, H>,
+    keys: &[[u8; HASH_SIZE]],
+) -> Result<MultiProof<HASH_SIZE, H>, TreeError<DbError>> {
+    let mut keys = keys.to_vec();
+    keys.sort_by(|a, b| path_cmp(a, b));
+    keys.dedup();
+    let mut nodes = Vec::new();
+    let mut bits = BitVec::new();
+    prove(db, 0, root, false, &keys, &mut nodes, &mut bits)?;
+    Ok(MultiProof::new(nodes, bits))
+}
+
+/// Descends the subtree at `height` along the paths of the path-sorted `keys`, pushing the
+/// subtrees without any key as siblings. Empty siblings are only pushed to `bits`.
+fn prove<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
+    db: &dyn Db<HASH_SIZE, H, DbError = DbError>,
+    height: usize,
+    node: Node<HASH_SIZE, H>,
+    detached: bool,
+    keys: &[[u8; HASH_SIZE]],
+    nodes: &mut Vec<Node<HASH_SIZE, H>>,
+    bits: &mut BitVec<u8, Lsb0>,
+) -> Result<(), TreeError<DbError>> {
+    if keys.is_empty() {
+        let is_empty = node.hash() == db.empty_tree()[height].hash();
+        bits.push(is_empty);
+        if !is_empty {
+            nodes.push(Node::Computed(ComputedNode::new(node.hash(), node.sum())));
+        }
+        return Ok(());
+    }
+    if height == HASH_SIZE * 8 {
+        // The leaf is given by the verifier.
+        return Ok(());
+    }
+    let (left, right, detached) = children(db, height, node, detached)?;
+    let (left_keys, right_keys) =
+        keys.split_at(keys.partition_point(|key| bit_index(height, key) == 0));
+    prove(db, height + 1, left, detached, left_keys, nodes, bits)?;
+    prove(db, height + 1, right, detached, right_keys, nodes, bits)
+}
@@ -0,0 +1,184 @@
+//! Mark-and-sweep pruning of the nodes unreachable from a set of roots.
+
+use std::collections::HashSet;
+
+use crate::{
+    db::{Db, NodeKind, WriteBatch},
+    node::{Hasher, Node},
+    TreeError,
+};
+
+/// Nodes deleted by a prune.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneStats {
+    /// Number of branches, leaves and compact leaves deleted
+    pub nodes: usize,
+    /// Bytes given back to the storage by the deletions, as reported by [`Db::stored_nodes`].
+    /// Always `0` for an append-only [`FileDb`](crate::FileDb).
+    pub bytes: usize,
+}
+
+/// Deletes every node of `db` that isn't reachable from one of the `keep_roots`.
+///
+/// The retained trees are walked with [`Db::get_children`] to mark their nodes, then the
+/// unmarked stored nodes are deleted in a single [`WriteBatch`]. Returns
+/// [`TreeError::Unsupported`] if `db` can't list its nodes.
+pub(crate) fn prune<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
+    db: &mut dyn Db<HASH_SIZE, H, DbError = DbError>,
+    keep_roots: &[[u8; HASH_SIZE]],
+) -> Result<PruneStats, TreeError<DbError>> {
+    let empty_tree = db.empty_tree();
+    let mut marked = HashSet::new();
+    let mut stack = keep_roots.iter().map(|root| (0, *root)).collect::<Vec<_>>();
+    while let Some((height, hash)) = stack.pop() {
+        if hash == empty_tree[height].hash() || !marked.insert(hash) {
+            continue;
+        }
+        let (left, right) = db.get_children(height, hash)?;
+        for child in [left, right] {
+            match child {
+                Node::Branch(_) | Node::Computed(_) => stack.push((height + 1, child.hash())),
+                Node::Leaf(leaf) => {
+                    marked.insert(leaf.hash());
+                }
+                Node::Compact(compact) => {
+                    // The leaf of a compacted leaf is stored on its own too.
+                    marked.insert(compact.hash());
+                    marked.insert(compact.leaf().hash());
+                }
+            }
+        }
+    }
+
+    let mut stats = PruneStats::default();
+    let mut batch = WriteBatch::new();
+    for node in db.stored_nodes()? {
+        if marked.contains(&node.hash) {
+            continue;
+        }
+        match node.kind {
+            NodeKind::Branch => batch.delete_branch(&node.hash),
+            NodeKind::Leaf => batch.delete_leaf(&node.hash),
+            NodeKind::CompactLeaf => batch.delete_compact_leaf(&node.hash),
+        }
+        stats.nodes += 1;
+        stats.bytes += node.size;
+    }
+    if !batch.is_empty() {
+        db.write_batch(batch)?;
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::Sha256;
+
+    use super::PruneStats;
+    use crate::{CompactMSSMT, FileDb, Leaf, MemoryDb, TreeError, MSSMT};
+
+    fn leaves() -> Vec<([u8; 32], Leaf<32, Sha256>)> {
+        (0..10u8)
+            .map(|i| ([i.wrapping_mul(37); 32], Leaf::new(vec![i + 1], i as u64)))
+            .collect()
+    }
+
+    #[test]
+    fn test_prune() {
+        let leaves = leaves();
+        let mut tree = MSSMT::<32, Sha256, ()>::new_versioned(Box::new(MemoryDb::default()));
+        let mut compact_tree =
+            CompactMSSMT::<32, Sha256, ()>::new_versioned(Box::new(MemoryDb::default()));
+        for (key, leaf) in &leaves {
+            tree.insert(key, leaf.clone()).unwrap();
+            compact_tree.insert(key, leaf.clone()).unwrap();
+        }
+        let kept = tree.at_version(3).unwrap().root().hash();
+        let stats = tree.prune(&[kept]).unwrap();
+        let compact_stats = compact_tree.prune(&[kept]).unwrap();
+        assert!(stats.nodes > 0 && stats.bytes > 0);
+        assert!(compact_stats.nodes > 0 && compact_stats.bytes > 0);
+        // Nothing is left to prune.
+        assert_eq!(tree.prune(&[kept]).unwrap(), PruneStats::default());
+        assert_eq!(compact_tree.prune(&[kept]).unwrap(), PruneStats::default());
+
+        let root = tree.root().unwrap();
+        assert_eq!(compact_tree.root().unwrap().hash(), root.hash());
+        for version in [3, 10] {
+            let view = tree.at_version(version).unwrap();
+            let compact_view = compact_tree.at_version(version).unwrap();
+            assert_eq!(view.iter().count(), version as usize);
+            assert_eq!(compact_view.iter().count(), version as usize);
+            for (key, leaf) in &leaves[..version as usize] {
+                assert_eq!(view.get(key).unwrap().hash(), leaf.hash());
+                assert_eq!(compact_view.get(key).unwrap().hash(), leaf.hash());
+            }
+        }
+        for version in [0, 2, 9] {
+            assert_eq!(tree.at_version(version).err(), Some(TreeError::UnknownRoot));
+            assert_eq!(
+                compact_tree.at_version(version).err(),
+                Some(TreeError::UnknownRoot)
+            );
+        }
+        assert_eq!(
+            tree.at_root(kept).unwrap().root().hash(),
+            compact_tree.at_root(kept).unwrap().root().hash()
+        );
+
+        // Without the kept version, only the nodes of the current root are left.
+        tree.prune(&[]).unwrap();
+        let mut fresh = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        fresh.insert_batch(&leaves).unwrap();
+        assert_eq!(
+            tree.db().stored_nodes().unwrap().len(),
+            fresh.db().stored_nodes().unwrap().len()
+        );
+        assert_eq!(tree.at_root(kept).err(), Some(TreeError::UnknownRoot));
+    }
+
+    #[test]
+    fn test_prune_unknown_root() {
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        tree.insert(&[1; 32], Leaf::new(vec![1], 1)).unwrap();
+        assert_eq!(
+            tree.prune(&[[1; 32]]).unwrap_err(),
+            TreeError::node_not_found(0, &[1; 32])
+        );
+        assert_eq!(tree.get(&[1; 32]).unwrap().sum(), 1);
+    }
+
+    #[test]
+    fn test_prune_file_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let mut tree = CompactMSSMT::<32, Sha256, _>::new_versioned(Box::new(
+            FileDb::<32, Sha256>::open(&path).unwrap(),
+        ));
+        for (key, leaf) in leaves() {
+            tree.insert(&key, leaf).unwrap();
+        }
+        let stored = tree.db().stored_nodes().unwrap().len();
+        let stats = tree.prune(&[]).unwrap();
+        assert_eq!(
+            stored - stats.nodes,
+            tree.db().stored_nodes().unwrap().len()
+        );
+        // The log is append-only, no disk space is reclaimed.
+        assert!(stats.nodes > 0);
+        assert_eq!(stats.bytes, 0);
+        let root = tree.root().unwrap();
+        drop(tree);
+
+        // The deletions are durable.
+        let tree = CompactMSSMT::<32, Sha256, _>::new(Box::new(
+            FileDb::<32, Sha256>::open(&path).unwrap(),
+        ));
+        assert_eq!(tree.root().unwrap().hash(), root.hash());
+        assert_eq!(
+            stored - stats.nodes,
+            tree.db().stored_nodes().unwrap().len()
+        );
+        assert_eq!(tree.iter().count(), 10);
+    }
+}
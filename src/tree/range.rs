@@ -0,0 +1,314 @@
+//! Key range queries.
+//!
+//! Ranges are half-open `[start, end)` intervals of keys compared in lexicographic order.
+//!
+//! The path of a key starts with the least significant bit of its first byte, so a subtree
+//! doesn't cover a contiguous interval of keys. It still has a smallest and a biggest key, and
+//! it's only walked when the range holds some of its keys but not all of them. Within each
+//! byte of the paths of the two bounds, at most the 256 subtrees of that byte are split.
+
+use crate::{
+    db::Db,
+    node::{ComputedNode, Hasher, Leaf, Node, Sum},
+    Proof, RangeSumProof, TreeError,
+};
+
+use super::children;
+
+/// How a subtree overlaps a key range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Overlap {
+    /// No key of the subtree is in the range.
+    Outside,
+    /// All the keys of the subtree are in the range.
+    Inside,
+    /// Some keys of the subtree are in the range.
+    Partial,
+}
+
+/// Returns how the subtree at `height` whose path is `prefix` overlaps `[start, end)`.
+/// Only the first `height` bits of `prefix` are read.
+pub(crate) fn overlap<const HASH_SIZE: usize>(
+    height: usize,
+    prefix: &[u8; HASH_SIZE],
+    start: &[u8; HASH_SIZE],
+    end: &[u8; HASH_SIZE],
+) -> Overlap {
+    // Smallest and biggest keys of the subtree.
+    let mut min = *prefix;
+    let mut max = *prefix;
+    if height < HASH_SIZE * 8 {
+        let byte = height / 8;
+        let mask = !((1u8 << (height % 8)) - 1);
+        min[byte] &= !mask;
+        max[byte] |= mask;
+        min[byte + 1..].fill(0);
+        max[byte + 1..].fill(u8::MAX);
+    }
+    if max < *start || min >= *end {
+        Overlap::Outside
+    } else if min >= *start && max < *end {
+        Overlap::Inside
+    } else {
+        Overlap::Partial
+    }
+}
+
+/// Returns `true` if `key` is in `[start, end)`.
+pub(crate) fn contains<const HASH_SIZE: usize>(
+    key: &[u8; HASH_SIZE],
+    start: &[u8; HASH_SIZE],
+    end: &[u8; HASH_SIZE],
+) -> bool {
+    key >= start && key < end
+}
+
+/// Sums the leaves of the subtree rooted at `root` whose keys are in `[start, end)`.
+///
+/// Subtrees fully inside the range contribute their sum without being read. Within a byte the
+/// subtrees sharing its low bits span most of its values, so up to 255 branches per byte of the
+/// prefixes of each bound are split, against 8 if the path followed the key order.
+pub(crate) fn range_sum<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
+    db: &dyn Db<HASH_SIZE, H, DbError = DbError>,
+    root: Node<HASH_SIZE, H>,
+    start: &[u8; HASH_SIZE],
+    end: &[u8; HASH_SIZE],
+) -> Result<Sum, TreeError<DbError>> {
+    let empty_tree = db.empty_tree();
+    let mut sum: Sum = 0;
+    let mut stack = vec![(0, [0; HASH_SIZE], root)];
+    while let Some((height, key, node)) = stack.pop() {
+        if node.hash() == empty_tree[height].hash() {
+            continue;
+        }
+        if let Node::Compact(compact) = &node {
+            if contains(compact.key(), start, end) {
+                sum += compact.sum();
+            }
+            continue;
+        }
+        match overlap(height, &key, start, end) {
+            Overlap::Outside => {}
+            Overlap::Inside => sum += node.sum(),
+            Overlap::Partial => {
+                let (left, right) = db.get_children(height, node.hash())?;
+                let mut right_key = key;
+                right_key[height / 8] |= 1 << (height % 8);
+                stack.push((height + 1, key, left));
+                stack.push((height + 1, right_key, right));
+            }
+        }
+    }
+    Ok(sum)
+}
+
+/// Finds the leaf of the subtree rooted at `root` whose cumulative sum interval contains
+/// `target`, with its key and merkle proof.
+///
+/// Leaves are laid out in tree path order, which differs from the key order, each one covering
+/// `[before, before + sum)` where `before` is the sum of the leaves before it in that order. The
+/// descent goes left when `target` is below the sum of the left child and right otherwise, so
+/// leaves with a zero sum are never selected. The siblings on the path are collected during
+/// the descent to build the proof. Returns `None` if `target` isn't below the sum of the tree.
+#[allow(clippy::type_complexity)]
+pub(crate) fn find_by_cumulative_sum<
+    const HASH_SIZE: usize,
+    H: Hasher<HASH_SIZE> + Clone,
+    DbError,
+>(
+    db: &dyn Db<HASH_SIZE, H, DbError = DbError>,
+    root: Node<HASH_SIZE, H>,
+    mut target: Sum,
+) -> Result<Option<([u8; HASH_SIZE], Leaf<HASH_SIZE, H>, Proof<HASH_SIZE, H>)>, TreeError<DbError>>
+{
+    if target >= root.sum() {
+        return Ok(None);
+    }
+    let max_levels = HASH_SIZE * 8;
+    let empty_tree = db.empty_tree();
+    let mut key = [0; HASH_SIZE];
+    let mut siblings = Vec::with_capacity(max_levels);
+    let mut node = root;
+    for height in 0..max_levels {
+        if let Node::Compact(compact) = node {
+            // The siblings below a compacted leaf are empty subtrees.
+            siblings.extend((height..max_levels).map(|j| empty_tree[j + 1].clone()));
+            siblings.reverse();
+            return Ok(Some((
+                *compact.key(),
+                compact.leaf().clone(),
+                Proof::new(siblings),
+            )));
+        }
+        let (left, right) = db.get_children(height, node.hash())?;
+        node = if target < left.sum() {
+            siblings.push(right);
+            left
+        } else {
+            target -= left.sum();
+            key[height / 8] |= 1 << (height % 8);
+            siblings.push(left);
+            right
+        };
+    }
+    siblings.reverse();
+    match node {
+        Node::Leaf(leaf) => Ok(Some((key, leaf, Proof::new(siblings)))),
+        Node::Compact(compact) => Ok(Some((
+            *compact.key(),
+            compact.leaf().clone(),
+            Proof::new(siblings),
+        ))),
+        _ => Err(TreeError::ExpectedLeaf),
+    }
+}
+
+/// Generates the [`RangeSumProof`] of `[start, end)` for the tree rooted at `root`.
+pub(crate) fn range_sum_proof<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
+    db: &dyn Db<HASH_SIZE, H, DbError = DbError>,
+    root: Node<HASH_SIZE, H>,
+    start: &[u8; HASH_SIZE],
+    end: &[u8; HASH_SIZE],
+) -> Result<RangeSumProof<HASH_SIZE, H>, TreeError<DbError>> {
+    let mut nodes = Vec::new();
+    prove(db, 0, [0; HASH_SIZE], root, false, start, end, &mut nodes)?;
+    Ok(RangeSumProof::new(nodes))
+}
+
+/// Descends the subtree at `height` whose path is `key`, pushing the boundary nodes in
+/// `nodes`. A `detached` branch was extracted from a compact leaf and holds its children.
+/// Empty subtrees are pushed whole even when they overlap a bound.
+#[allow(clippy::too_many_arguments)]
+fn prove<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
+    db: &dyn Db<HASH_SIZE, H, DbError = DbError>,
+    height: usize,
+    key: [u8; HASH_SIZE],
+    node: Node<HASH_SIZE, H>,
+    detached: bool,
+    start: &[u8; HASH_SIZE],
+    end: &[u8; HASH_SIZE],
+    nodes: &mut Vec<Node<HASH_SIZE, H>>,
+) -> Result<(), TreeError<DbError>> {
+    if overlap(height, &key, start, end) != Overlap::Partial
+        || node.hash() == db.empty_tree()[height].hash()
+    {
+        nodes.push(Node::Computed(ComputedNode::new(node.hash(), node.sum())));
+        return Ok(());
+    }
+    let (left, right, detached) = children(db, height, node, detached)?;
+    let mut right_key = key;
+    right_key[height / 8] |= 1 << (height % 8);
+    prove(db, height + 1, key, left, detached, start, end, nodes)?;
+    prove(
+        db,
+        height + 1,
+        right_key,
+        right,
+        detached,
+        start,
+        end,
+        nodes,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::Sha256;
+
+    use super::{contains, overlap, Overlap};
+    use crate::{tree::path_cmp, CompactMSSMT, Leaf, MemoryDb, MSSMT};
+
+    #[test]
+    fn test_overlap() {
+        // Subtree at height 1 holding the keys whose first path bit is 1: [1, 0] to [ff, ff].
+        let prefix = [1, 0];
+        assert_eq!(overlap(1, &prefix, &[0, 0], &[0, 0]), Overlap::Outside);
+        assert_eq!(overlap(1, &prefix, &[0, 0], &[0, 0x80]), Overlap::Outside);
+        assert_eq!(
+            overlap(1, &prefix, &[0, 0], &[0xff, 0xff]),
+            Overlap::Partial
+        );
+        assert_eq!(
+            overlap(1, &prefix, &[3, 0], &[0xff, 0xff]),
+            Overlap::Partial
+        );
+        // Subtree at height 2 whose path starts with 1 then 0: [1, 0] to [fd, ff], holding
+        // [5, 0] but not [3, 0].
+        assert_eq!(overlap(2, &prefix, &[0, 0], &[0xfe, 0]), Overlap::Inside);
+        assert_eq!(overlap(2, &prefix, &[0, 0], &[3, 0]), Overlap::Partial);
+        assert_eq!(
+            overlap(2, &prefix, &[1, 0], &[0xfd, 0xff]),
+            Overlap::Partial
+        );
+        assert_eq!(overlap(2, &prefix, &[0, 0], &[1, 0]), Overlap::Outside);
+        assert_eq!(
+            overlap(2, &prefix, &[0xfe, 0], &[0xff, 0xff]),
+            Overlap::Outside
+        );
+        // Leaf level.
+        assert_eq!(overlap(16, &[3, 4], &[3, 4], &[7, 4]), Overlap::Inside);
+        assert_eq!(overlap(16, &[7, 4], &[3, 4], &[7, 4]), Overlap::Outside);
+    }
+
+    #[test]
+    fn test_contains() {
+        assert!(contains(&[1], &[1], &[2]));
+        assert!(!contains(&[2], &[1], &[2]));
+        assert!(!contains(&[0], &[1], &[2]));
+        assert!(!contains(&[1], &[2], &[1]));
+    }
+
+    #[test]
+    fn test_find_by_cumulative_sum() {
+        // Leaf 0 has a zero sum and can never be selected.
+        let mut leaves = (0..20u8)
+            .map(|i| ([i.wrapping_mul(37); 32], Leaf::new(vec![i + 1], i as u64)))
+            .collect::<Vec<_>>();
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut compact_tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for (key, leaf) in &leaves {
+            tree.insert(key, leaf.clone()).unwrap();
+            compact_tree.insert(key, leaf.clone()).unwrap();
+        }
+        leaves.sort_by(|(a, _), (b, _)| path_cmp(a, b));
+        let root = tree.root().unwrap();
+
+        let mut before = 0;
+        for (key, leaf) in &leaves {
+            for target in [before, before + leaf.sum() / 2, before + leaf.sum()] {
+                if target == before + leaf.sum() {
+                    // The end of the interval belongs to the next leaf.
+                    continue;
+                }
+                for (found_key, found_leaf, proof) in [
+                    tree.find_by_cumulative_sum(target).unwrap().unwrap(),
+                    compact_tree
+                        .find_by_cumulative_sum(target)
+                        .unwrap()
+                        .unwrap(),
+                ] {
+                    assert_eq!(found_key, *key);
+                    assert_eq!(found_leaf.hash(), leaf.hash());
+                    assert!(proof
+                        .verify_merkle_proof::<()>(key, found_leaf, root.hash())
+                        .is_ok());
+                }
+            }
+            before += leaf.sum();
+        }
+        assert_eq!(before, root.sum());
+        assert!(tree.find_by_cumulative_sum(before).unwrap().is_none());
+        assert!(compact_tree
+            .find_by_cumulative_sum(before)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_find_by_cumulative_sum_empty() {
+        let tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        assert!(tree.find_by_cumulative_sum(0).unwrap().is_none());
+        let tree = CompactMSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        assert!(tree.find_by_cumulative_sum(0).unwrap().is_none());
+    }
+}
@@ -3,12 +3,25 @@
 use std::{marker::PhantomData, sync::Arc};
 
 use crate::{
-    db::Db,
+    db::{Db, WriteBatch},
     node::{Branch, Hasher, Leaf, Node},
-    EmptyLeaf, Proof, TreeError,
+    EmptyLeaf, MultiProof, Proof, RangeSumProof, ThreadSafe, TransitionProof, TransitionStep,
+    TreeError,
 };
 
-use super::walk_up;
+use super::{
+    checked_branch,
+    diff::TreeDiff,
+    integrity::{verify_integrity, IntegrityReport},
+    iter::LeafIter,
+    join,
+    multi::multi_proof,
+    parallel_split, path_update,
+    prune::{prune, PruneStats},
+    range::{find_by_cumulative_sum, range_sum, range_sum_proof},
+    sort_batch, split_batch,
+    view::TreeView,
+};
 
 /// Merkle sum sparse merkle tree.
 /// * `KVStore` - Key value store for nodes.
@@ -16,6 +29,9 @@ use super::walk_up;
 /// * `H` - Hasher that will be used to hash nodes.
 pub struct MSSMT<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError> {
     db: Box<dyn Db<HASH_SIZE, H, DbError = DbError>>,
+    /// Committed roots indexed by their version, `None` if the tree isn't versioned and
+    /// for the pruned versions.
+    versions: Option<Vec<Option<Branch<HASH_SIZE, H>>>>,
     _phantom: PhantomData<H>,
 }
 
@@ -32,9 +48,24 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError> MSSMT<HASH_S
     pub fn new(db: Box<dyn Db<HASH_SIZE, H, DbError = DbError>>) -> Self {
         Self {
             db,
+            versions: None,
             _phantom: PhantomData,
         }
     }
+
+    /// Creates a new versioned mssmt. Each committed root gets a version number, starting
+    /// with `0` for the current root of `db`, and the replaced nodes are kept in the database
+    /// so that every version stays readable with [`at_version`](Self::at_version).
+    ///
+    /// The version numbers are only kept in memory and don't survive a restart: reopening `db`
+    /// numbers its current root as version `0` again. The nodes of the older roots stay in the
+    /// database but can't be read through the tree anymore.
+    pub fn new_versioned(db: Box<dyn Db<HASH_SIZE, H, DbError = DbError>>) -> Self {
+        let mut tree = Self::new(db);
+        tree.versions = Some(vec![Some(tree.root_node())]);
+        tree
+    }
+
     pub fn db(&self) -> &dyn Db<HASH_SIZE, H, DbError = DbError> {
         self.db.as_ref()
     }
@@ -46,15 +77,115 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError> MSSMT<HASH_S
 
     /// Root node of the tree.
     pub fn root(&self) -> Result<Branch<HASH_SIZE, H>, TreeError<DbError>> {
+        Ok(self.root_node())
+    }
+
+    /// Root node stored in the database, the empty tree root if there is none.
+    fn root_node(&self) -> Branch<HASH_SIZE, H> {
         match self.db.get_root_node() {
-            Some(branch) => Ok(branch),
+            Some(branch) => branch,
             None => {
                 let Node::Branch(branch) = self.db.empty_tree().as_ref()[0].clone() else {
                     unreachable!("Invalid empty tree. The root node should always be a branch.");
                 };
-                Ok(branch)
+                branch
+            }
+        }
+    }
+
+    /// Version of the current root, `None` if the tree isn't versioned.
+    pub fn version(&self) -> Option<u64> {
+        self.versions
+            .as_ref()
+            .map(|versions| versions.len() as u64 - 1)
+    }
+
+    /// Read-only view of the tree at the root committed as `version`.
+    ///
+    /// Returns [`TreeError::UnknownRoot`] if the tree isn't versioned, or if `version` wasn't
+    /// committed or was pruned.
+    pub fn at_version(
+        &self,
+        version: u64,
+    ) -> Result<TreeView<'_, HASH_SIZE, H, DbError>, TreeError<DbError>> {
+        let root = usize::try_from(version)
+            .ok()
+            .and_then(|version| self.versions.as_ref()?.get(version)?.as_ref())
+            .ok_or(TreeError::UnknownRoot)?;
+        Ok(TreeView::new(self.db.as_ref(), root.clone()))
+    }
+
+    /// Read-only view of the tree at the root whose hash is `root_hash`, either the current
+    /// root or a committed version.
+    ///
+    /// Returns [`TreeError::UnknownRoot`] if there is no such root.
+    pub fn at_root(
+        &self,
+        root_hash: [u8; HASH_SIZE],
+    ) -> Result<TreeView<'_, HASH_SIZE, H, DbError>, TreeError<DbError>> {
+        let current = self.root_node();
+        let root = if current.hash() == root_hash {
+            current
+        } else {
+            self.versions
+                .iter()
+                .flatten()
+                .flatten()
+                .find(|root| root.hash() == root_hash)
+                .ok_or(TreeError::UnknownRoot)?
+                .clone()
+        };
+        Ok(TreeView::new(self.db.as_ref(), root))
+    }
+
+    /// Deletes every node of the database that isn't reachable from the current root or one
+    /// of `keep_roots` and returns how many nodes were deleted and how many bytes the database
+    /// reclaimed.
+    ///
+    /// The versions of a versioned tree whose roots aren't kept can't be read anymore. Returns
+    /// [`TreeError::Unsupported`] if the database can't list its nodes.
+    pub fn prune(
+        &mut self,
+        keep_roots: &[[u8; HASH_SIZE]],
+    ) -> Result<PruneStats, TreeError<DbError>> {
+        let mut roots = keep_roots.to_vec();
+        roots.push(self.root_node().hash());
+        let stats = prune(self.db.as_mut(), &roots)?;
+        for version in self.versions.iter_mut().flatten() {
+            if version
+                .as_ref()
+                .is_some_and(|root| !roots.contains(&root.hash()))
+            {
+                *version = None;
             }
         }
+        Ok(stats)
+    }
+
+    /// Checks every node reachable from the current root and the committed versions, and
+    /// lists the stored nodes that none of them reaches.
+    ///
+    /// Branch hashes and sums are recomputed from their children, leaf hashes from their
+    /// value and sum, and compact leaf hashes from their key and height. Returns
+    /// [`TreeError::Unsupported`] if the database can't list its nodes.
+    pub fn verify_integrity(&self) -> Result<IntegrityReport<HASH_SIZE>, TreeError<DbError>> {
+        let mut roots = vec![self.root_node()];
+        roots.extend(self.versions.iter().flatten().flatten().cloned());
+        verify_integrity(self.db.as_ref(), &roots)
+    }
+
+    /// Applies `batch` to the database. A versioned tree keeps the replaced nodes and records
+    /// the new root as the next version.
+    fn commit(&mut self, mut batch: WriteBatch<HASH_SIZE, H>) -> Result<(), TreeError<DbError>> {
+        if self.versions.is_some() {
+            batch.retain_insertions();
+        }
+        self.db.write_batch(batch)?;
+        let root = self.root_node();
+        if let Some(versions) = &mut self.versions {
+            versions.push(Some(root));
+        }
+        Ok(())
     }
 
     /// Walk down the tree from the root node to the node.
@@ -82,6 +213,8 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError> MSSMT<HASH_S
     }
 
     /// Insert a leaf in the tree.
+    /// All the database writes are applied in a single [`WriteBatch`], so the update either
+    /// fully lands or leaves the previous root intact.
     pub fn insert(
         &mut self,
         key: &[u8; HASH_SIZE],
@@ -100,34 +233,94 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError> MSSMT<HASH_S
         prev_parents.reverse();
         siblings.reverse();
 
-        let mut branches_delete = Vec::new();
-        let mut branches_insertion = Vec::new();
-        let root = walk_up(
-            key,
-            leaf.clone(),
-            &siblings,
-            |height, _current, _sibling, parent| {
-                let prev_parent = prev_parents[Self::max_levels() - height - 1];
-                if prev_parent != self.db.empty_tree()[height].hash() {
-                    branches_delete.push(prev_parent);
-                }
-                if parent.hash() != self.db.empty_tree()[height].hash() {
-                    if let Node::Branch(parent) = parent {
-                        branches_insertion.push(parent.clone());
-                    }
-                }
-            },
-        )?;
+        let batch = path_update(&self.db.empty_tree(), key, leaf, &siblings, &prev_parents)?;
+        self.commit(batch)
+    }
 
-        for branch in branches_insertion {
-            self.db.insert_branch(branch)?;
-        }
-        for key in branches_delete {
-            self.db.delete_branch(&key)?;
+    /// Insert several leaves in the tree at once.
+    ///
+    /// The keys are sorted so that leaves sharing a path prefix share the walk down, and each
+    /// touched branch is hashed only once. The resulting root is the same as inserting the
+    /// leaves one by one. If a key appears several times, the last leaf wins.
+    /// All the database writes are applied in a single [`WriteBatch`].
+    ///
+    /// With the `multi-thread` feature, the subtrees of large batches are built concurrently
+    /// on scoped threads.
+    #[allow(clippy::type_complexity)]
+    pub fn insert_batch(
+        &mut self,
+        leaves: &[([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)],
+    ) -> Result<(), TreeError<DbError>>
+    where
+        H: ThreadSafe,
+        DbError: ThreadSafe,
+    {
+        let entries = sort_batch(leaves);
+        if entries.is_empty() {
+            return Ok(());
         }
+        let mut batch = WriteBatch::new();
+        let root =
+            match self.insert_batch_at(&mut batch, 0, Node::Branch(self.root()?), &entries)? {
+                Node::Branch(root) => root,
+                node => return Err(TreeError::expected_branch(0, &node.hash())),
+            };
+        batch.update_root(root);
+        self.commit(batch)
+    }
 
-        self.db.insert_leaf(leaf)?;
-        self.db.update_root(root)
+    /// Inserts the path-sorted `entries` in the subtree rooted at `node` and returns the new
+    /// subtree root. All the entries share the path of `node` up to `height`.
+    #[allow(clippy::type_complexity)]
+    fn insert_batch_at(
+        &self,
+        batch: &mut WriteBatch<HASH_SIZE, H>,
+        height: usize,
+        node: Node<HASH_SIZE, H>,
+        entries: &[([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)],
+    ) -> Result<Node<HASH_SIZE, H>, TreeError<DbError>>
+    where
+        H: ThreadSafe,
+        DbError: ThreadSafe,
+    {
+        if entries.is_empty() {
+            return Ok(node);
+        }
+        if height == Self::max_levels() {
+            // Keys are deduplicated so only one entry can reach a leaf.
+            let (_, leaf) = entries[0].clone();
+            batch.insert_leaf(leaf.clone());
+            return Ok(Node::Leaf(leaf));
+        }
+        let (left, right) = self.db.get_children(height, node.hash())?;
+        let (left_entries, right_entries) = split_batch(height, entries);
+        let (left, right) = if parallel_split(height, left_entries.len(), right_entries.len()) {
+            // The right subtree writes to its own batch which is appended afterwards to
+            // keep the same order as a sequential build.
+            let mut right_batch = WriteBatch::new();
+            let (left, right) = join(
+                || self.insert_batch_at(batch, height + 1, left, left_entries),
+                || self.insert_batch_at(&mut right_batch, height + 1, right, right_entries),
+            );
+            batch.extend(right_batch);
+            (left?, right?)
+        } else {
+            (
+                self.insert_batch_at(batch, height + 1, left, left_entries)?,
+                self.insert_batch_at(batch, height + 1, right, right_entries)?,
+            )
+        };
+        let branch = checked_branch(left, right)?;
+        let empty_hash = self.db.empty_tree()[height].hash();
+        if branch.hash() != node.hash() {
+            if node.hash() != empty_hash {
+                batch.delete_branch(&node.hash());
+            }
+            if branch.hash() != empty_hash {
+                batch.insert_branch(branch.clone());
+            }
+        }
+        Ok(Node::Branch(branch))
     }
 
     pub fn merkle_proof(
@@ -142,18 +335,133 @@ impl<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError> MSSMT<HASH_S
         Ok(Proof::new(proof))
     }
 
+    /// Returns the merkle proof that no leaf is stored at `key`, to be verified with
+    /// [`Proof::verify_exclusion`].
+    ///
+    /// Returns [`TreeError::ExpectedEmptyLeaf`] if a leaf is stored at `key`.
+    pub fn exclusion_proof(
+        &self,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<Proof<HASH_SIZE, H>, TreeError<DbError>> {
+        let mut proof = Vec::with_capacity(Self::max_levels());
+        let leaf = self.walk_down(key, |_, _next, sibling, _| {
+            proof.push(sibling);
+        })?;
+        if let Leaf::NonEmpty(_) = leaf {
+            return Err(TreeError::ExpectedEmptyLeaf);
+        }
+        proof.reverse();
+        Ok(Proof::new(proof))
+    }
+
+    /// Merkle proof of all the `keys` at once, each sibling being stored once.
+    /// Verify it with the leaves of the keys, empty leaves for the absent ones.
+    pub fn multi_proof(
+        &self,
+        keys: &[[u8; HASH_SIZE]],
+    ) -> Result<MultiProof<HASH_SIZE, H>, TreeError<DbError>> {
+        multi_proof(self.db.as_ref(), Node::Branch(self.root()?), keys)
+    }
+
+    /// Applies the `updates` in order and returns the [`TransitionProof`] from the previous
+    /// root to the new one. Empty leaves delete their key.
+    ///
+    /// Stops at the first update that fails, the previous ones staying applied.
+    #[allow(clippy::type_complexity)]
+    pub fn apply_transition(
+        &mut self,
+        updates: &[([u8; HASH_SIZE], Leaf<HASH_SIZE, H>)],
+    ) -> Result<TransitionProof<HASH_SIZE, H>, TreeError<DbError>> {
+        let mut transition = TransitionProof::new(Vec::with_capacity(updates.len()));
+        for (key, leaf) in updates {
+            let old_leaf = self.get(key)?;
+            let proof = self.merkle_proof(key)?;
+            self.insert(key, leaf.clone())?;
+            transition.push(TransitionStep::new(*key, old_leaf, leaf.clone(), proof));
+        }
+        Ok(transition)
+    }
+
     pub fn delete(&mut self, key: &[u8; HASH_SIZE]) -> Result<(), TreeError<DbError>> {
         self.insert(key, Leaf::Empty(EmptyLeaf::new()))
     }
     pub fn get(&self, key: &[u8; HASH_SIZE]) -> Result<Leaf<HASH_SIZE, H>, TreeError<DbError>> {
         self.walk_down(key, |_, _, _, _| {})
     }
+
+    /// Iterate over all the non-empty leaves of the tree in ascending key order.
+    pub fn iter(&self) -> LeafIter<'_, HASH_SIZE, H, DbError> {
+        LeafIter::new(self.db.as_ref())
+    }
+
+    /// Iterate over the leaves that differ from the tree whose root hash is `root_a` to the one
+    /// whose root hash is `root_b`, both stored in the database of this tree.
+    /// See [`TreeDiff`] for how the trees are compared.
+    pub fn diff(
+        &self,
+        root_a: [u8; HASH_SIZE],
+        root_b: [u8; HASH_SIZE],
+    ) -> TreeDiff<'_, HASH_SIZE, H, DbError> {
+        TreeDiff::new(self.db.as_ref(), root_a, root_b)
+    }
+
+    /// Iterate over the non-empty leaves whose keys are in `[start, end)`, keys being compared
+    /// as bytes. The leaves are yielded in ascending key order.
+    pub fn range(
+        &self,
+        start: &[u8; HASH_SIZE],
+        end: &[u8; HASH_SIZE],
+    ) -> LeafIter<'_, HASH_SIZE, H, DbError> {
+        LeafIter::new(self.db.as_ref()).with_range(*start, *end)
+    }
+
+    /// Sum of the leaves whose keys are in `[start, end)`.
+    /// Subtrees fully inside the range aren't walked, their sum is read from their root. The
+    /// path of a key starts with the low bits of its bytes, which don't order the keys, so on
+    /// the prefixes shared with `start` and `end` up to 255 branches per byte are fetched from
+    /// the database, instead of the 8 of a single path.
+    pub fn range_sum(
+        &self,
+        start: &[u8; HASH_SIZE],
+        end: &[u8; HASH_SIZE],
+    ) -> Result<u64, TreeError<DbError>> {
+        range_sum(self.db.as_ref(), Node::Branch(self.root()?), start, end)
+    }
+
+    /// Proof that the leaves whose keys are in `[start, end)` sum to
+    /// [`range_sum`](Self::range_sum), without revealing them.
+    pub fn range_sum_proof(
+        &self,
+        start: &[u8; HASH_SIZE],
+        end: &[u8; HASH_SIZE],
+    ) -> Result<RangeSumProof<HASH_SIZE, H>, TreeError<DbError>> {
+        range_sum_proof(self.db.as_ref(), Node::Branch(self.root()?), start, end)
+    }
+
+    /// Finds the leaf whose cumulative sum interval contains `target`, with its key and merkle
+    /// proof.
+    ///
+    /// Leaves are taken in tree path order, not in the key order of [`iter`](Self::iter), each
+    /// one covering `[before, before + sum)` where `before` is the sum of the leaves before it. The tree is
+    /// descended using the sums of the branches, so sampling a leaf with a probability
+    /// proportional to its sum only reads one path. Returns `None` if `target` isn't below the
+    /// sum of the tree.
+    #[allow(clippy::type_complexity)]
+    pub fn find_by_cumulative_sum(
+        &self,
+        target: u64,
+    ) -> Result<
+        Option<([u8; HASH_SIZE], Leaf<HASH_SIZE, H>, Proof<HASH_SIZE, H>)>,
+        TreeError<DbError>,
+    > {
+        find_by_cumulative_sum(self.db.as_ref(), Node::Branch(self.root()?), target)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::MSSMT;
-    use crate::{EmptyTree, Leaf, MemoryDb, TreeError};
+    use crate::{EmptyLeaf, EmptyTree, Leaf, MemoryDb, TreeError};
     use sha2::Sha256;
 
     #[test]
@@ -210,6 +518,22 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_tree_unchanged_insertion() {
+        let db = MemoryDb::<32, Sha256>::default();
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(db));
+        let leaf = Leaf::new([1; 32].to_vec(), 1);
+        tree.insert(&[1; 32], leaf.clone()).unwrap();
+        tree.insert(&[7; 32], leaf.clone()).unwrap();
+        let root = tree.root().unwrap();
+        // Neither deleting an absent key nor inserting the same leaf again changes the tree.
+        tree.delete(&[3; 32]).unwrap();
+        tree.insert(&[7; 32], leaf.clone()).unwrap();
+        assert_eq!(tree.root().unwrap().hash(), root.hash());
+        assert_eq!(tree.get(&[7; 32]).unwrap().hash(), leaf.hash());
+        assert_eq!(tree.get(&[1; 32]).unwrap().hash(), leaf.hash());
+    }
+
     #[test]
     fn test_get_leaf() {
         let db = MemoryDb::<32, Sha256>::default();
@@ -220,6 +544,96 @@ mod test {
         assert_eq!(got_leaf.hash(), leaf.hash());
     }
 
+    #[test]
+    fn test_strict_db() {
+        // A strict database errors on any missing child, valid trees never read one.
+        let leaves = (0..20u8)
+            .map(|i| ([i.wrapping_mul(37); 32], Leaf::new(vec![i + 1], i as u64)))
+            .collect::<Vec<_>>();
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::new_strict()));
+        let mut expected = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for (key, leaf) in &leaves[..10] {
+            tree.insert(key, leaf.clone()).unwrap();
+            expected.insert(key, leaf.clone()).unwrap();
+        }
+        tree.insert_batch(&leaves[10..]).unwrap();
+        expected.insert_batch(&leaves[10..]).unwrap();
+        for (key, _) in leaves.iter().step_by(3) {
+            tree.delete(key).unwrap();
+            expected.delete(key).unwrap();
+        }
+        let root = tree.root().unwrap();
+        assert_eq!(root.hash(), expected.root().unwrap().hash());
+        for (i, (key, leaf)) in leaves.iter().enumerate() {
+            let leaf = if i % 3 == 0 {
+                Leaf::Empty(EmptyLeaf::new())
+            } else {
+                leaf.clone()
+            };
+            assert_eq!(tree.get(key).unwrap().hash(), leaf.hash());
+            tree.merkle_proof(key)
+                .unwrap()
+                .verify_merkle_proof::<()>(key, leaf, root.hash())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_insert_batch() {
+        let leaves = (0..20u8)
+            .map(|i| ([i.wrapping_mul(37); 32], Leaf::new(vec![i + 1], i as u64)))
+            .collect::<Vec<_>>();
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let mut batch_tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        for (key, leaf) in &leaves {
+            tree.insert(key, leaf.clone()).unwrap();
+        }
+        batch_tree.insert_batch(&leaves[..10]).unwrap();
+        batch_tree.insert_batch(&leaves[10..]).unwrap();
+        assert_eq!(
+            tree.root().unwrap().hash(),
+            batch_tree.root().unwrap().hash()
+        );
+        for (key, leaf) in &leaves {
+            assert_eq!(batch_tree.get(key).unwrap().hash(), leaf.hash());
+        }
+
+        // Deleting through a batch.
+        let deletions = leaves
+            .iter()
+            .map(|(key, _)| (*key, Leaf::Empty(EmptyLeaf::new())))
+            .collect::<Vec<_>>();
+        batch_tree.insert_batch(&deletions).unwrap();
+        assert_eq!(
+            batch_tree.root().unwrap().hash(),
+            EmptyTree::<32, Sha256>::empty_tree()[0].hash()
+        );
+    }
+
+    #[test]
+    fn test_insert_batch_duplicated_keys() {
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        let leaf = Leaf::new(vec![2], 2);
+        tree.insert_batch(&[([1; 32], Leaf::new(vec![1], 1)), ([1; 32], leaf.clone())])
+            .unwrap();
+        assert_eq!(tree.get(&[1; 32]).unwrap().hash(), leaf.hash());
+        assert_eq!(tree.root().unwrap().sum(), 2);
+    }
+
+    #[test]
+    fn test_insert_batch_overflow() {
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        tree.insert(&[0; 32], Leaf::new([1; 32].to_vec(), u64::MAX))
+            .unwrap();
+        let root = tree.root().unwrap();
+        assert_eq!(
+            tree.insert_batch(&[([1; 32], Leaf::new([1; 32].to_vec(), 1))])
+                .unwrap_err(),
+            TreeError::SumOverflow
+        );
+        assert_eq!(tree.root().unwrap().hash(), root.hash());
+    }
+
     #[test]
     fn test_tree_overflow() {
         let db = MemoryDb::<32, Sha256>::default();
@@ -0,0 +1,256 @@
+//! Read-only views of a tree at one of its roots.
+
+use std::{ops::Range, sync::Arc};
+
+use crate::{
+    db::Db,
+    node::{Branch, CompactLeaf, Hasher, Leaf, Node},
+    EmptyLeaf, Proof, TreeError,
+};
+
+use super::{bit_index, LeafIter};
+
+/// A read-only view of a tree at one of its roots, created by
+/// [`MSSMT::at_version`](crate::MSSMT::at_version), [`MSSMT::at_root`](crate::MSSMT::at_root)
+/// and their [`CompactMSSMT`](crate::CompactMSSMT) counterparts.
+///
+/// The view reads the nodes of its root from the database of the tree, it works with both the
+/// regular and the compact layouts.
+pub struct TreeView<'a, const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError> {
+    db: &'a dyn Db<HASH_SIZE, H, DbError = DbError>,
+    root: Branch<HASH_SIZE, H>,
+}
+
+impl<'a, const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>
+    TreeView<'a, HASH_SIZE, H, DbError>
+{
+    /// Creates a view of the tree rooted at `root` stored in `db`.
+    pub(crate) fn new(
+        db: &'a dyn Db<HASH_SIZE, H, DbError = DbError>,
+        root: Branch<HASH_SIZE, H>,
+    ) -> Self {
+        Self { db, root }
+    }
+
+    /// Root node of the view.
+    pub fn root(&self) -> &Branch<HASH_SIZE, H> {
+        &self.root
+    }
+
+    /// Returns the leaf stored at `key`, an empty leaf if there is none.
+    pub fn get(&self, key: &[u8; HASH_SIZE]) -> Result<Leaf<HASH_SIZE, H>, TreeError<DbError>> {
+        lookup(self.db, self.root.clone(), key, None)
+    }
+
+    /// Returns the merkle proof of `key` in the view.
+    pub fn merkle_proof(
+        &self,
+        key: &[u8; HASH_SIZE],
+    ) -> Result<Proof<HASH_SIZE, H>, TreeError<DbError>> {
+        let mut proof = Vec::with_capacity(HASH_SIZE * 8);
+        lookup(self.db, self.root.clone(), key, Some(&mut proof))?;
+        proof.reverse();
+        Ok(Proof::new(proof))
+    }
+
+    /// Iterate over all the non-empty leaves of the view in ascending key order.
+    pub fn iter(&self) -> LeafIter<'a, HASH_SIZE, H, DbError> {
+        LeafIter::from_node(self.db, 0, [0; HASH_SIZE], Node::Branch(self.root.clone()))
+    }
+}
+
+/// Walks down the path of `key` from `root` until its leaf, a compacted leaf or an empty
+/// subtree. The siblings on the path are pushed from the top to `proof` if it's provided.
+///
+/// Below a compacted leaf or an empty subtree the siblings are empty subtrees, except for the
+/// subtree of a compacted leaf whose key diverges from `key`, which is hashed without
+/// rebuilding its branches.
+pub(crate) fn lookup<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
+    db: &dyn Db<HASH_SIZE, H, DbError = DbError>,
+    root: Branch<HASH_SIZE, H>,
+    key: &[u8; HASH_SIZE],
+    mut proof: Option<&mut Vec<Node<HASH_SIZE, H>>>,
+) -> Result<Leaf<HASH_SIZE, H>, TreeError<DbError>> {
+    let (height, node) = descend(db, root, key, proof.as_deref_mut())?;
+    resolve(&db.empty_tree(), height, node, key, proof)
+}
+
+/// Returns the leaf stored at `key` from the `node` at `height` a walk down the path of `key`
+/// stopped at, and pushes the siblings below it to `proof` if it's provided. See [`lookup`].
+pub(crate) fn resolve<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
+    empty_tree: &Arc<Vec<Node<HASH_SIZE, H>>>,
+    height: usize,
+    node: Node<HASH_SIZE, H>,
+    key: &[u8; HASH_SIZE],
+    mut proof: Option<&mut Vec<Node<HASH_SIZE, H>>>,
+) -> Result<Leaf<HASH_SIZE, H>, TreeError<DbError>> {
+    let max_levels = HASH_SIZE * 8;
+    // Pushes the empty siblings at `heights` on the path.
+    let push_empty = |proof: &mut Option<&mut Vec<Node<HASH_SIZE, H>>>, heights: Range<usize>| {
+        if let Some(proof) = proof {
+            for j in heights {
+                proof.push(empty_tree[j + 1].clone());
+            }
+        }
+    };
+
+    match node {
+        Node::Compact(compact) if compact.key() == key => {
+            push_empty(&mut proof, height..max_levels);
+            Ok(compact.leaf().clone())
+        }
+        Node::Compact(compact) => {
+            if proof.is_some() {
+                // The paths share the bits above `height`, the first one that differs is
+                // where the subtree of the compacted leaf is the sibling.
+                let diverge = (height..max_levels)
+                    .find(|&j| bit_index(j, key) != bit_index(j, compact.key()))
+                    .ok_or(TreeError::ExpectedCompactLeaf)?;
+                push_empty(&mut proof, height..diverge);
+                if let Some(proof) = &mut proof {
+                    proof.push(Node::Compact(CompactLeaf::new(
+                        diverge + 1,
+                        *compact.key(),
+                        compact.leaf().clone(),
+                        empty_tree.clone(),
+                    )));
+                }
+                push_empty(&mut proof, diverge + 1..max_levels);
+            }
+            Ok(Leaf::Empty(EmptyLeaf::new()))
+        }
+        Node::Leaf(leaf) => Ok(leaf),
+        _ => {
+            push_empty(&mut proof, height..max_levels);
+            Ok(Leaf::Empty(EmptyLeaf::new()))
+        }
+    }
+}
+
+/// Walks down the path of `key` from `root` until a compacted leaf, an empty subtree or the
+/// leaf level, and returns the height of the node it stopped at with the node. The siblings on
+/// the path are pushed from the top to `siblings` if it's provided.
+pub(crate) fn descend<const HASH_SIZE: usize, H: Hasher<HASH_SIZE> + Clone, DbError>(
+    db: &dyn Db<HASH_SIZE, H, DbError = DbError>,
+    root: Branch<HASH_SIZE, H>,
+    key: &[u8; HASH_SIZE],
+    mut siblings: Option<&mut Vec<Node<HASH_SIZE, H>>>,
+) -> Result<(usize, Node<HASH_SIZE, H>), TreeError<DbError>> {
+    let max_levels = HASH_SIZE * 8;
+    let empty_tree = db.empty_tree();
+    let mut current = Node::Branch(root);
+    for i in 0..max_levels {
+        let (left, right) = db.get_children(i, current.hash())?;
+        let (next, sibling) = if bit_index(i, key) == 0 {
+            (left, right)
+        } else {
+            (right, left)
+        };
+        if let Some(siblings) = &mut siblings {
+            siblings.push(sibling);
+        }
+        if matches!(next, Node::Compact(_)) || next.hash() == empty_tree[i + 1].hash() {
+            return Ok((i + 1, next));
+        }
+        current = next;
+    }
+    match current {
+        Node::Leaf(_) => Ok((max_levels, current)),
+        _ => Err(TreeError::ExpectedLeaf),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::Sha256;
+
+    use crate::{CompactMSSMT, EmptyLeaf, Leaf, MemoryDb, TreeError, MSSMT};
+
+    fn leaves() -> Vec<([u8; 32], Leaf<32, Sha256>)> {
+        (0..10u8)
+            .map(|i| ([i.wrapping_mul(37); 32], Leaf::new(vec![i + 1], i as u64)))
+            .collect()
+    }
+
+    #[test]
+    fn test_versions() {
+        let mut tree = MSSMT::<32, Sha256, ()>::new_versioned(Box::new(MemoryDb::default()));
+        let mut compact_tree =
+            CompactMSSMT::<32, Sha256, ()>::new_versioned(Box::new(MemoryDb::default()));
+        assert_eq!(tree.version(), Some(0));
+        assert_eq!(compact_tree.version(), Some(0));
+        let leaves = leaves();
+        let mut roots = vec![tree.root().unwrap()];
+        for (key, leaf) in &leaves {
+            tree.insert(key, leaf.clone()).unwrap();
+            compact_tree.insert(key, leaf.clone()).unwrap();
+            roots.push(tree.root().unwrap());
+        }
+        tree.delete(&leaves[0].0).unwrap();
+        compact_tree.delete(&leaves[0].0).unwrap();
+        roots.push(tree.root().unwrap());
+        tree.insert_batch(&leaves[..1]).unwrap();
+        compact_tree.insert_batch(&leaves[..1]).unwrap();
+        roots.push(tree.root().unwrap());
+        assert_eq!(tree.version(), Some(roots.len() as u64 - 1));
+        assert_eq!(compact_tree.version(), Some(roots.len() as u64 - 1));
+
+        for (version, root) in roots.iter().enumerate() {
+            // The leaves inserted up to this version, the first one being deleted and
+            // inserted back by the last two versions.
+            let first = if version == roots.len() - 2 { 1 } else { 0 };
+            let stored = &leaves[first..version.min(leaves.len())];
+            for view in [
+                tree.at_version(version as u64).unwrap(),
+                tree.at_root(root.hash()).unwrap(),
+            ]
+            .into_iter()
+            .chain([
+                compact_tree.at_version(version as u64).unwrap(),
+                compact_tree.at_root(root.hash()).unwrap(),
+            ]) {
+                assert_eq!(view.root().hash(), root.hash());
+                assert_eq!(view.iter().count(), stored.len());
+                for (key, leaf) in stored {
+                    assert_eq!(view.get(key).unwrap().hash(), leaf.hash());
+                    view.merkle_proof(key)
+                        .unwrap()
+                        .verify_merkle_proof::<()>(key, leaf.clone(), root.hash())
+                        .unwrap();
+                }
+                for (key, _) in &leaves[version.min(leaves.len())..] {
+                    assert!(matches!(view.get(key).unwrap(), Leaf::Empty(_)));
+                    view.merkle_proof(key)
+                        .unwrap()
+                        .verify_exclusion::<()>(key, root.hash())
+                        .unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_version() {
+        let mut tree = MSSMT::<32, Sha256, ()>::new(Box::new(MemoryDb::default()));
+        tree.insert(&[1; 32], Leaf::new(vec![1], 1)).unwrap();
+        assert_eq!(tree.version(), None);
+        assert_eq!(tree.at_version(0).err(), Some(TreeError::UnknownRoot));
+        // The current root is always readable.
+        let root = tree.root().unwrap();
+        assert_eq!(tree.at_root(root.hash()).unwrap().iter().count(), 1);
+
+        let mut compact_tree =
+            CompactMSSMT::<32, Sha256, ()>::new_versioned(Box::new(MemoryDb::default()));
+        compact_tree
+            .insert(&[1; 32], Leaf::Empty(EmptyLeaf::new()))
+            .unwrap();
+        assert_eq!(
+            compact_tree.at_version(2).err(),
+            Some(TreeError::UnknownRoot)
+        );
+        assert_eq!(
+            compact_tree.at_root([0; 32]).err(),
+            Some(TreeError::UnknownRoot)
+        );
+    }
+}